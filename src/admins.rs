@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Recent admin statuses of chat members, so a burst of config commands doesn't ask Telegram for
+// the permissions every time. Failed lookups are not kept.
+pub struct AdminCache {
+    ttl: Duration,
+    statuses: HashMap<(i64, i64), (bool, Instant)>,
+}
+
+impl AdminCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            statuses: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, chat_id: i64, user_id: i64, now: Instant) -> Option<bool> {
+        self.statuses
+            .get(&(chat_id, user_id))
+            .filter(|(_, checked_at)| now.saturating_duration_since(*checked_at) < self.ttl)
+            .map(|(is_admin, _)| *is_admin)
+    }
+
+    // Expired statuses are dropped on the way, so the cache only holds the active members.
+    pub fn insert(&mut self, chat_id: i64, user_id: i64, is_admin: bool, now: Instant) {
+        let ttl = self.ttl;
+        self.statuses
+            .retain(|_, (_, checked_at)| now.saturating_duration_since(*checked_at) < ttl);
+        self.statuses.insert((chat_id, user_id), (is_admin, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_expire_after_ttl() {
+        let mut cache = AdminCache::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(cache.get(1, 10, start), None);
+
+        cache.insert(1, 10, true, start);
+        cache.insert(1, 11, false, start);
+        assert_eq!(
+            cache.get(1, 10, start + Duration::from_secs(59)),
+            Some(true)
+        );
+        assert_eq!(cache.get(1, 11, start), Some(false));
+        // Statuses are per chat.
+        assert_eq!(cache.get(2, 10, start), None);
+
+        assert_eq!(cache.get(1, 10, start + Duration::from_secs(60)), None);
+        cache.insert(1, 12, true, start + Duration::from_secs(60));
+        assert_eq!(cache.statuses.len(), 1);
+    }
+}
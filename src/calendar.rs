@@ -0,0 +1,113 @@
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Calendar day requested with /summarize, in the chat's timezone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Day {
+    Today,
+    Yesterday,
+    // Year, month and day of an explicit `YYYY-MM-DD` date.
+    Date(i64, u32, u32),
+}
+
+impl Day {
+    pub fn parse(day: &str) -> Option<Self> {
+        match day {
+            "today" => return Some(Day::Today),
+            "yesterday" => return Some(Day::Yesterday),
+            _ => {}
+        }
+        let mut parts = day.splitn(3, '-');
+        let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+        if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return None;
+        }
+        let year = year.parse::<i64>().ok()?;
+        let month = month
+            .parse::<u32>()
+            .ok()
+            .filter(|month| (1..=12).contains(month))?;
+        let day = day
+            .parse::<u32>()
+            .ok()
+            .filter(|day| (1..=days_in_month(year, month)).contains(day))?;
+        Some(Day::Date(year, month, day))
+    }
+
+    // Unix times of the start of the day and of the next day. None for days before 1970.
+    pub fn range(self, now: u64, utc_offset_minutes: i32) -> Option<(u64, u64)> {
+        let offset = utc_offset_minutes as i64 * 60;
+        let today = (now as i64 + offset).div_euclid(SECONDS_PER_DAY);
+        let day = match self {
+            Day::Today => today,
+            Day::Yesterday => today - 1,
+            Day::Date(year, month, day) => days_from_civil(year, month, day),
+        };
+        let start = u64::try_from(day * SECONDS_PER_DAY - offset).ok()?;
+        Some((start, start + SECONDS_PER_DAY as u64))
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-03-10 01:30 UTC
+    const NOW: u64 = 1_710_034_200;
+    // 2024-03-10 00:00 UTC
+    const MARCH_10: u64 = 1_710_028_800;
+    const DAY: u64 = SECONDS_PER_DAY as u64;
+
+    #[test]
+    fn days_are_parsed_into_utc_ranges() {
+        assert_eq!(Day::parse("today"), Some(Day::Today));
+        assert_eq!(Day::parse("yesterday"), Some(Day::Yesterday));
+        assert_eq!(Day::parse("2024-02-29"), Some(Day::Date(2024, 2, 29)));
+        assert_eq!(Day::parse("2023-02-29"), None);
+        assert_eq!(Day::parse("2024-13-01"), None);
+        assert_eq!(Day::parse("2024-3-1"), None);
+        assert_eq!(Day::parse("100"), None);
+        assert_eq!(Day::parse("24h"), None);
+
+        assert_eq!(Day::Today.range(NOW, 0), Some((MARCH_10, MARCH_10 + DAY)));
+        assert_eq!(
+            Day::Yesterday.range(NOW, 0),
+            Some((MARCH_10 - DAY, MARCH_10))
+        );
+        // It's still March 9 in New York (UTC-5), the day started at 05:00 UTC.
+        assert_eq!(
+            Day::Today.range(NOW, -5 * 60),
+            Some((MARCH_10 - DAY + 5 * 3600, MARCH_10 + 5 * 3600))
+        );
+        // In Moscow (UTC+3) the day started at 21:00 UTC the day before.
+        assert_eq!(
+            Day::parse("2024-03-10").unwrap().range(NOW, 3 * 60),
+            Some((MARCH_10 - 3 * 3600, MARCH_10 + DAY - 3 * 3600))
+        );
+        assert_eq!(
+            Day::parse("1970-01-01").unwrap().range(NOW, 0),
+            Some((0, DAY))
+        );
+        assert_eq!(Day::parse("1969-12-31").unwrap().range(NOW, 0), None);
+    }
+}
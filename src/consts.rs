@@ -1,5 +1,89 @@
 pub const MESSAGE_TO_STORE: u32 = 1000;
 pub const TELEGRAM_MAX_MESSAGE_FETCH: usize = 200;
+// Pause between the batches of /backfill, so fetching a long history doesn't trigger FLOOD_WAIT.
+pub const BACKFILL_BATCH_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+// Most of a long history can be service messages or filtered out, so /backfill gives up after
+// reading this many batches.
+pub const BACKFILL_MAX_BATCHES: usize = 20;
 pub const DEFAULT_SUMMARY_LENGTH: u32 = 100;
-pub const SYMBOL_PER_OPENAI_MESSAGE: usize = 10_000;
 pub const MEDIA_DIR: &str = "./media";
+// Upper bound for the per-chat minimum message length, so short but meaningful replies are still stored.
+pub const MAX_MIN_MESSAGE_LENGTH: u32 = 10;
+// Upper bound for the per-chat interval between summaries, in minutes.
+pub const MAX_SUMMARY_INTERVAL_MINUTES: u32 = 24 * 60;
+// Unknown commands further from every known one are left unanswered, they are likely meant for another bot.
+pub const MAX_COMMAND_SUGGESTION_DISTANCE: usize = 2;
+// How long and how many of the latest summaries are kept as context for follow-up questions in DM.
+pub const DM_CONTEXT_TTL: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+pub const DM_CONTEXT_SIZE: usize = 5;
+// How long and how many of the latest /ask questions and answers of a user in a chat are kept
+// as context for their next question.
+pub const ASK_THREAD_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+pub const ASK_THREAD_SIZE: u32 = 3;
+pub const TELEGRAM_MAX_MESSAGE_LENGTH: usize = 4096;
+// Telegram rate limits message edits, so streamed responses are shown at most once per interval.
+pub const STREAM_EDIT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+pub const DEAD_LETTERS_TO_STORE: u32 = 1000;
+pub const DEAD_LETTERS_TO_SHOW: u32 = 10;
+// Size of the channel between the update loop and the command processor.
+// The last slots are reserved for internal commands, user requests are rejected once only the reserve is left.
+pub const COMMAND_CHANNEL_SIZE: usize = 1000;
+pub const COMMAND_CHANNEL_RESERVE: usize = 50;
+// Longer audio is treated as a voice chat recording and transcribed in chunks of this length.
+pub const RECORDING_CHUNK: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+// Whisper rejects uploads over 25MB, larger audio is split in shorter chunks to stay below this.
+pub const WHISPER_FILE_LIMIT: u64 = 24 * 1024 * 1024;
+// Chunks are cut this much longer than their length, so a word at the boundary isn't lost.
+// The repeated words are trimmed from the transcript of the next chunk.
+pub const CHUNK_OVERLAP: std::time::Duration = std::time::Duration::from_secs(3);
+// Media downloads that take longer are cancelled and retried, large videos can take a while.
+pub const MEDIA_DOWNLOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+pub const MEDIA_DOWNLOAD_RETRIES: u32 = 2;
+// Automatic retries of a user request across media downloads, completions and self checks.
+pub const COMMAND_RETRY_BUDGET: u32 = 3;
+// Media jobs running at once and waiting for their turn, transcribing large videos takes a lot of memory.
+pub const MAX_MEDIA_JOBS: usize = 2;
+pub const MEDIA_QUEUE_SIZE: usize = 20;
+// Transcripts of media are reused when the same media is summarized again within this time.
+pub const MEDIA_TRANSCRIPT_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+// Chats whose commands are processed at once, the commands of one chat are always processed in order.
+pub const MAX_CONCURRENT_COMMANDS: usize = 4;
+// How often idle command workers check the queue.
+pub const COMMAND_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+// Requests estimated to use more tokens than this are answered with their estimated cost first.
+pub const COST_NOTICE_TOKENS: usize = 20_000;
+// Messages kept of the windows summarized with sampling.
+pub const SUMMARY_SAMPLE_SIZE: usize = 300;
+// Pause after OpenAI rejects a request with 429 without saying how long to wait.
+pub const RATE_LIMIT_BACKOFF: std::time::Duration = std::time::Duration::from_secs(20);
+// Pause before sending an OpenAI request again after a rate limit or an outage, doubled after
+// every failed retry up to the max.
+pub const OPENAI_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+pub const OPENAI_MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+// Attempts of an OpenAI request failing with a rate limit or an outage, the first one included.
+pub const OPENAI_MAX_ATTEMPTS: u32 = 4;
+// Partial summaries of long summary chunks are reused by overlapping requests within this time.
+pub const PARTIAL_SUMMARIES_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+pub const PARTIAL_SUMMARIES_SIZE: usize = 500;
+// Most messages summarized before and after a replied message with /summarize ±N.
+pub const MAX_SUMMARY_RADIUS: u32 = 100;
+// Replies to a summary are answered over its messages for this long after it was sent.
+pub const SUMMARY_SOURCE_TTL: std::time::Duration =
+    std::time::Duration::from_secs(7 * 24 * 60 * 60);
+// The bot's own user is fetched this many times at startup, so a brief Telegram outage doesn't stop it.
+pub const STARTUP_ATTEMPTS: u32 = 5;
+pub const STARTUP_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+// Ratings of summaries older than this are dropped with the summaries.
+pub const RATED_SUMMARY_TTL: std::time::Duration =
+    std::time::Duration::from_secs(90 * 24 * 60 * 60);
+// Admin status of a chat member is checked again after this long, so demoted admins lose access soon.
+pub const ADMIN_STATUS_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+// Reactions on command messages while their request is processed and once it's done, with
+// COMMAND_REACTIONS set. Bots can only use the standard reactions, so there are no ⏳ and ✅.
+pub const PROCESSING_REACTION: &str = "👀";
+pub const DONE_REACTION: &str = "👍";
+pub const FAILED_REACTION: &str = "🤷";
+// Summaries of more messages than this are queued behind the other requests.
+pub const BULK_SUMMARY_MESSAGES: u32 = 300;
+// Queued commands are taken ahead of the higher priorities once this many later ones were.
+pub const MAX_COMMAND_OVERTAKES: usize = 20;
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Minimum time between the requests of a user, so a single user can't flood the command queue.
+pub struct Cooldown {
+    interval: Duration,
+    last_requests: HashMap<i64, Instant>,
+}
+
+impl Cooldown {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_requests: HashMap::new(),
+        }
+    }
+
+    // Records the request if the user's cooldown is over. Otherwise returns how long is left,
+    // rejected requests don't extend the cooldown.
+    pub fn check(&mut self, user_id: i64, now: Instant) -> Result<(), Duration> {
+        if let Some(last_request) = self.last_requests.get(&user_id) {
+            let elapsed = now.saturating_duration_since(*last_request);
+            if elapsed < self.interval {
+                return Err(self.interval - elapsed);
+            }
+        }
+        self.last_requests.insert(user_id, now);
+        Ok(())
+    }
+}
+
+// Reply to a rejected request. Rounded up, so the user never comes back too early.
+pub fn wait_message(remaining: Duration) -> String {
+    let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+    format!("Please wait {seconds}s")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_request_is_told_how_long_to_wait() {
+        let mut cooldown = Cooldown::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(cooldown.check(1, start).is_ok());
+        assert!(cooldown.check(2, start).is_ok());
+
+        let remaining = cooldown
+            .check(1, start + Duration::from_millis(17_500))
+            .unwrap_err();
+        assert_eq!(remaining, Duration::from_millis(42_500));
+        assert_eq!(wait_message(remaining), "Please wait 43s");
+        assert_eq!(wait_message(Duration::from_secs(42)), "Please wait 42s");
+
+        // The rejected request didn't restart the cooldown.
+        assert_eq!(
+            cooldown.check(1, start + Duration::from_secs(30)),
+            Err(Duration::from_secs(30))
+        );
+        assert!(cooldown.check(1, start + Duration::from_secs(60)).is_ok());
+        assert!(cooldown.check(1, start + Duration::from_secs(61)).is_err());
+    }
+}
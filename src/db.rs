@@ -1,22 +1,962 @@
-use rusqlite::Connection;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, OptionalExtension};
 
 use crate::consts;
+use crate::quiet_hours::QuietHours;
+
+// Run by the periodic maintenance, see `Db::maintain`.
+const MAINTENANCE_STATEMENTS: &[&str] = &["VACUUM", "ANALYZE"];
 
 pub struct Db {
     connection: Connection,
 }
 
+// Times are stored as unix seconds.
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatConfig {
+    // Messages shorter than this (in characters) are not stored. 0 disables the filter.
+    pub min_message_length: u32,
+    // Replaces the default system prompt for /ask.
+    pub ask_prompt: Option<String>,
+    // Replies in the chat are sent without a notification during these hours.
+    pub quiet_hours: Option<QuietHours>,
+    // Commands except /enable are ignored while the bot is disabled in the chat.
+    pub enabled: bool,
+    pub store_while_disabled: bool,
+    // Reacting to a message with this emoji posts its summary to the chat.
+    pub summary_reaction: Option<String>,
+    // Passed to Whisper with the chat's audio, e.g. names and jargon it should spell right.
+    pub whisper_prompt: Option<String>,
+    // Minimum time between two summaries in the chat, whoever requests them. 0 disables the limit.
+    pub summary_interval_minutes: u32,
+    // Summaries are written as bullet points unless requested with --prose.
+    pub bullet_summaries: bool,
+    // Recognized command messages are deleted after they're handled, if the bot has the right.
+    pub cleanup_commands: bool,
+    // Name of the length of /summarize without a length, like `large`. Medium if unset.
+    pub default_length: Option<String>,
+    // Language code, like `uk`, summaries are written in instead of the chat's dominant language.
+    pub forced_language: Option<String>,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            min_message_length: 0,
+            ask_prompt: None,
+            quiet_hours: None,
+            enabled: true,
+            store_while_disabled: true,
+            summary_reaction: None,
+            whisper_prompt: None,
+            summary_interval_minutes: 0,
+            bullet_summaries: false,
+            cleanup_commands: true,
+            default_length: None,
+            forced_language: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub created_at: String,
+    pub command: String,
+    pub chat_id: Option<i64>,
+    pub user_id: Option<i64>,
+    pub error: String,
+}
+
+// The command message of a stored request, it gets a reaction once the request is done.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingAck {
+    pub chat: Vec<u8>,
+    pub message_id: i32,
+}
+
+// Columns are added on startup if missing, so databases created by older versions keep working.
+const CHAT_CONFIG_COLUMNS: &[(&str, &str)] = &[
+    ("min_message_length", "INTEGER NOT NULL DEFAULT 0"),
+    ("ask_prompt", "TEXT"),
+    ("quiet_hours_start", "INTEGER"),
+    ("quiet_hours_end", "INTEGER"),
+    ("quiet_hours_utc_offset", "INTEGER NOT NULL DEFAULT 0"),
+    ("enabled", "INTEGER NOT NULL DEFAULT 1"),
+    ("store_while_disabled", "INTEGER NOT NULL DEFAULT 1"),
+    ("summary_reaction", "TEXT"),
+    ("whisper_prompt", "TEXT"),
+    ("summary_interval_minutes", "INTEGER NOT NULL DEFAULT 0"),
+    ("bullet_summaries", "INTEGER NOT NULL DEFAULT 0"),
+    ("cleanup_commands", "INTEGER NOT NULL DEFAULT 1"),
+    ("default_length", "TEXT"),
+    ("forced_language", "TEXT"),
+];
+
+const MESSAGES_COLUMNS: &[(&str, &str)] = &[
+    // Forum topic of the message, see `Db::set_message_topic`.
+    ("topic_id", "INTEGER"),
+    // Filled only when the bot stores message texts, see `Db::add_message_text`.
+    ("sender_id", "INTEGER"),
+    ("sender_username", "TEXT"),
+    ("text", "TEXT"),
+];
+
+// Condition on the `topic_id` parameter of the message queries, NULL matches the whole chat.
+const IN_TOPIC: &str = "(?2 IS NULL OR topic_id = ?2)";
+
+// A stored message whose text can be summarized without fetching it from Telegram.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub message_id: i32,
+    pub sender_id: Option<i64>,
+    pub sender_username: Option<String>,
+    pub text: String,
+}
+
 impl Db {
-    pub fn new_with_file(filename: &str) -> anyhow::Result<Self> {
+    pub fn new_with_file(filename: &str) -> rusqlite::Result<Self> {
         let connection = Connection::open(filename)?;
-        Ok(Self { connection })
+        let db = Self { connection };
+        db.create_tables()?;
+        Ok(db)
+    }
+
+    fn create_tables(&self) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS chat_config (
+                chat_id INTEGER PRIMARY KEY
+            )",
+            [],
+        )?;
+        for (column, definition) in CHAT_CONFIG_COLUMNS {
+            self.add_column_if_missing("chat_config", column, definition)?;
+        }
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS dead_letters (
+                id INTEGER PRIMARY KEY,
+                created_at TEXT NOT NULL,
+                command TEXT NOT NULL,
+                chat_id INTEGER,
+                user_id INTEGER,
+                error TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS known_chats (
+                chat_id INTEGER PRIMARY KEY,
+                packed BLOB NOT NULL
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS removed_chats (
+                chat_id INTEGER PRIMARY KEY,
+                removed_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS last_summaries (
+                chat_id INTEGER PRIMARY KEY,
+                summarized_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS opted_out_users (
+                chat_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, user_id)
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS ask_threads (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                asked_at INTEGER NOT NULL,
+                question TEXT NOT NULL,
+                answer TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS media_transcripts (
+                media_id INTEGER PRIMARY KEY,
+                created_at INTEGER NOT NULL,
+                text TEXT NOT NULL,
+                recording INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS rated_summaries (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                parameters TEXT NOT NULL,
+                prompt_version TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        self.add_column_if_missing("rated_summaries", "recipient_id", "INTEGER")?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS feedback (
+                summary_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                rating INTEGER NOT NULL,
+                PRIMARY KEY (summary_id, user_id)
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS summary_sources (
+                chat_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                source_chat_id INTEGER NOT NULL,
+                first_message_id INTEGER NOT NULL,
+                last_message_id INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, message_id)
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS welcomed_chats (
+                chat_id INTEGER PRIMARY KEY,
+                welcomed_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY,
+                chat_id INTEGER NOT NULL,
+                timestamp TEXT NOT NULL,
+                message_id INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        for (column, definition) in MESSAGES_COLUMNS {
+            self.add_column_if_missing("messages", column, definition)?;
+        }
+        self.connection.execute(
+            "CREATE INDEX IF NOT EXISTS messages_by_chat ON messages (chat_id, id)",
+            [],
+        )?;
+        self.migrate_chat_tables()?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS pending_commands (
+                id INTEGER PRIMARY KEY,
+                created_at TEXT NOT NULL,
+                chat_id INTEGER,
+                recipient_id INTEGER,
+                command TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.add_column_if_missing("pending_commands", "ack_chat", "BLOB")?;
+        self.add_column_if_missing("pending_commands", "ack_message_id", "INTEGER")?;
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS daily_costs (
+                day INTEGER PRIMARY KEY,
+                spent REAL NOT NULL
+            )",
+            [],
+        )?;
+        // Used to be written by `Db::probe`.
+        self.connection
+            .execute("DROP TABLE IF EXISTS self_test", [])?;
+        Ok(())
+    }
+
+    // Message ids used to be stored in a `g{chat_id}` table per chat, they are moved into `messages`.
+    fn migrate_chat_tables(&self) -> rusqlite::Result<()> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name GLOB 'g*'")?;
+        let tables = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for table in tables {
+            let Some(chat_id) = table
+                .strip_prefix('g')
+                .and_then(|id| id.parse::<i64>().ok())
+            else {
+                continue;
+            };
+            let transaction = self.connection.unchecked_transaction()?;
+            transaction.execute(
+                &format!(
+                    "INSERT INTO messages (chat_id, timestamp, message_id)
+                    SELECT ?, timestamp, message_id FROM \"{table}\" ORDER BY id ASC"
+                ),
+                [chat_id],
+            )?;
+            transaction.execute(&format!("DROP TABLE \"{table}\""), [])?;
+            transaction.commit()?;
+            log::info!("Migrated the stored messages of chat {chat_id}");
+        }
+        Ok(())
+    }
+
+    // Takes the write lock and lets it go, so /selftest can tell the database is writable.
+    pub fn probe(&self) -> rusqlite::Result<()> {
+        self.connection.execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+    }
+
+    fn add_column_if_missing(
+        &self,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> rusqlite::Result<()> {
+        let mut statement = self
+            .connection
+            .prepare(&format!("SELECT name FROM pragma_table_info('{table}')"))?;
+        let exists = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .iter()
+            .any(|name| name == column);
+
+        if !exists {
+            self.connection.execute(
+                &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn record_dead_letter(
+        &self,
+        command: &str,
+        chat_id: Option<i64>,
+        user_id: Option<i64>,
+        error: &str,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO dead_letters (created_at, command, chat_id, user_id, error)
+                VALUES (datetime('now'), ?, ?, ?, ?)",
+            rusqlite::params![command, chat_id, user_id, error],
+        )?;
+        self.connection.execute(
+            "DELETE FROM dead_letters WHERE id NOT IN (
+                SELECT id FROM dead_letters ORDER BY id DESC LIMIT ?
+            )",
+            [consts::DEAD_LETTERS_TO_STORE],
+        )?;
+        Ok(())
+    }
+
+    // Returns the latest dead letters, newest first.
+    pub fn get_dead_letters(&self, count: u32) -> rusqlite::Result<Vec<DeadLetter>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, created_at, command, chat_id, user_id, error
+                FROM dead_letters ORDER BY id DESC LIMIT ?",
+        )?;
+        let dead_letters = statement
+            .query_map([count], |row| {
+                Ok(DeadLetter {
+                    id: row.get(0)?,
+                    created_at: row.get(1)?,
+                    command: row.get(2)?,
+                    chat_id: row.get(3)?,
+                    user_id: row.get(4)?,
+                    error: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(dead_letters)
+    }
+
+    // Keeps a serialized request until it's done, returns its id.
+    pub fn add_pending_command(
+        &self,
+        chat_id: Option<i64>,
+        recipient_id: Option<i64>,
+        command: &str,
+        ack: Option<&PendingAck>,
+    ) -> rusqlite::Result<i64> {
+        self.connection.execute(
+            "INSERT INTO pending_commands
+                (created_at, chat_id, recipient_id, command, ack_chat, ack_message_id)
+                VALUES (datetime('now'), ?, ?, ?, ?, ?)",
+            rusqlite::params![
+                chat_id,
+                recipient_id,
+                command,
+                ack.map(|ack| &ack.chat),
+                ack.map(|ack| ack.message_id)
+            ],
+        )?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    // Returns the ids, the serialized requests and their command messages in the order they
+    // were received.
+    pub fn get_pending_commands(&self) -> rusqlite::Result<Vec<(i64, String, Option<PendingAck>)>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, command, ack_chat, ack_message_id FROM pending_commands ORDER BY id",
+        )?;
+        let commands = statement
+            .query_map([], |row| {
+                let ack_chat: Option<Vec<u8>> = row.get(2)?;
+                let ack_message_id: Option<i32> = row.get(3)?;
+                let ack = ack_chat
+                    .zip(ack_message_id)
+                    .map(|(chat, message_id)| PendingAck { chat, message_id });
+                Ok((row.get(0)?, row.get(1)?, ack))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(commands)
+    }
+
+    pub fn remove_pending_command(&self, id: i64) -> rusqlite::Result<()> {
+        self.connection
+            .execute("DELETE FROM pending_commands WHERE id = ?", [id])?;
+        Ok(())
+    }
+
+    // Drops the requests reading from or sent to the chat.
+    pub fn remove_chat_pending_commands(&self, chat_id: i64) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "DELETE FROM pending_commands WHERE chat_id = ?1 OR recipient_id = ?1",
+            [chat_id],
+        )?;
+        Ok(())
+    }
+
+    // Remembers how to access the chat, so it can be resolved by id later.
+    pub fn remember_chat(&self, chat_id: i64, packed: &[u8]) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO known_chats (chat_id, packed) VALUES (?, ?)",
+            rusqlite::params![chat_id, packed],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_known_chat(&self, chat_id: i64) -> rusqlite::Result<Option<Vec<u8>>> {
+        let packed = self
+            .connection
+            .query_row(
+                "SELECT packed FROM known_chats WHERE chat_id = ?",
+                [chat_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(packed)
+    }
+
+    pub fn get_known_chats(&self) -> rusqlite::Result<Vec<(i64, Vec<u8>)>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT chat_id, packed FROM known_chats")?;
+        let chats = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(chats)
+    }
+
+    // Unix time of the latest summary requested in the chat.
+    pub fn get_last_summary(&self, chat_id: i64) -> rusqlite::Result<Option<u64>> {
+        let summarized_at = self
+            .connection
+            .query_row(
+                "SELECT summarized_at FROM last_summaries WHERE chat_id = ?",
+                [chat_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(summarized_at)
+    }
+
+    // Estimated OpenAI spending of a UTC day, counted in days since the unix epoch.
+    pub fn get_daily_cost(&self, day: u64) -> rusqlite::Result<f64> {
+        let spent = self
+            .connection
+            .query_row(
+                "SELECT spent FROM daily_costs WHERE day = ?",
+                [day],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(spent.unwrap_or_default())
+    }
+
+    pub fn add_daily_cost(&self, day: u64, usd: f64) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO daily_costs (day, spent) VALUES (?, ?)
+            ON CONFLICT (day) DO UPDATE SET spent = spent + excluded.spent",
+            rusqlite::params![day, usd],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_last_summary(&self, chat_id: i64, summarized_at: u64) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO last_summaries (chat_id, summarized_at) VALUES (?, ?)",
+            rusqlite::params![chat_id, summarized_at],
+        )?;
+        Ok(())
+    }
+
+    // Members who opted out aren't stored and their messages are left out of the chat's summaries.
+    pub fn set_opted_out(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        opted_out: bool,
+    ) -> rusqlite::Result<()> {
+        let statement = if opted_out {
+            "INSERT OR IGNORE INTO opted_out_users (chat_id, user_id) VALUES (?, ?)"
+        } else {
+            "DELETE FROM opted_out_users WHERE chat_id = ? AND user_id = ?"
+        };
+        self.connection
+            .execute(statement, rusqlite::params![chat_id, user_id])?;
+        if opted_out {
+            // Texts stored before opting out are dropped as well.
+            self.connection.execute(
+                "UPDATE messages SET sender_username = NULL, text = NULL WHERE chat_id = ? AND sender_id = ?",
+                rusqlite::params![chat_id, user_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_opted_out_users(&self, chat_id: i64) -> rusqlite::Result<Vec<i64>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT user_id FROM opted_out_users WHERE chat_id = ?")?;
+        let user_ids = statement
+            .query_map([chat_id], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(user_ids)
+    }
+
+    // Only the latest consts::ASK_THREAD_SIZE exchanges of a thread are kept.
+    pub fn add_ask_exchange(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        asked_at: u64,
+        question: &str,
+        answer: &str,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO ask_threads (chat_id, user_id, asked_at, question, answer) VALUES (?, ?, ?, ?, ?)",
+            rusqlite::params![chat_id, user_id, asked_at, question, answer],
+        )?;
+        self.connection.execute(
+            "DELETE FROM ask_threads WHERE chat_id = ?1 AND user_id = ?2 AND id NOT IN (
+                SELECT id FROM ask_threads WHERE chat_id = ?1 AND user_id = ?2 ORDER BY id DESC LIMIT ?3
+            )",
+            rusqlite::params![chat_id, user_id, consts::ASK_THREAD_SIZE],
+        )?;
+        Ok(())
+    }
+
+    // (question, answer) pairs asked since the given unix time, the oldest first.
+    pub fn get_ask_thread(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        since: u64,
+    ) -> rusqlite::Result<Vec<(String, String)>> {
+        let mut statement = self.connection.prepare(
+            "SELECT question, answer FROM ask_threads
+            WHERE chat_id = ? AND user_id = ? AND asked_at >= ?
+            ORDER BY id ASC",
+        )?;
+        let thread = statement
+            .query_map(rusqlite::params![chat_id, user_id, since], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(thread)
+    }
+
+    pub fn clear_ask_thread(&self, chat_id: i64, user_id: i64) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "DELETE FROM ask_threads WHERE chat_id = ? AND user_id = ?",
+            rusqlite::params![chat_id, user_id],
+        )?;
+        Ok(())
+    }
+
+    // The transcript of the media and whether it was a recording, if it was added since the given unix time.
+    pub fn get_media_transcript(
+        &self,
+        media_id: i64,
+        since: u64,
+    ) -> rusqlite::Result<Option<(String, bool)>> {
+        self.connection
+            .query_row(
+                "SELECT text, recording FROM media_transcripts WHERE media_id = ? AND created_at >= ?",
+                rusqlite::params![media_id, since],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    // Transcripts added before `expired_before` are dropped.
+    pub fn add_media_transcript(
+        &self,
+        media_id: i64,
+        created_at: u64,
+        text: &str,
+        recording: bool,
+        expired_before: u64,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "DELETE FROM media_transcripts WHERE created_at < ?",
+            [expired_before],
+        )?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO media_transcripts (media_id, created_at, text, recording) VALUES (?, ?, ?, ?)",
+            rusqlite::params![media_id, created_at, text, recording],
+        )?;
+        Ok(())
+    }
+
+    // A summary of `chat_id` sent to `recipient_id` with the rating buttons, its id is in their
+    // callback data.
+    pub fn add_rated_summary(
+        &self,
+        chat_id: i64,
+        recipient_id: i64,
+        parameters: &str,
+        prompt_version: &str,
+        created_at: u64,
+    ) -> rusqlite::Result<i64> {
+        self.connection.execute(
+            "INSERT INTO rated_summaries (chat_id, recipient_id, parameters, prompt_version, created_at) VALUES (?, ?, ?, ?, ?)",
+            rusqlite::params![chat_id, recipient_id, parameters, prompt_version, created_at],
+        )?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    // Replaces the user's previous rating of the summary. False if the summary isn't known,
+    // e.g. its chat was purged.
+    pub fn set_feedback(
+        &self,
+        summary_id: i64,
+        user_id: i64,
+        rating: i32,
+    ) -> rusqlite::Result<bool> {
+        let inserted = self.connection.execute(
+            "INSERT OR REPLACE INTO feedback (summary_id, user_id, rating)
+                SELECT id, ?2, ?3 FROM rated_summaries WHERE id = ?1",
+            rusqlite::params![summary_id, user_id, rating],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    // (prompt version, parameters, 👍 count, 👎 count) of every rated setting.
+    pub fn get_feedback_totals(&self) -> rusqlite::Result<Vec<(String, String, u32, u32)>> {
+        let mut statement = self.connection.prepare(
+            "SELECT prompt_version, parameters,
+                SUM(CASE WHEN rating > 0 THEN 1 ELSE 0 END),
+                SUM(CASE WHEN rating < 0 THEN 1 ELSE 0 END)
+                FROM feedback JOIN rated_summaries ON rated_summaries.id = feedback.summary_id
+                GROUP BY prompt_version, parameters
+                ORDER BY prompt_version, parameters",
+        )?;
+        let totals = statement
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(totals)
+    }
+
+    // Links the summary message sent to `chat_id` to the messages of `source_chat_id` it was made from.
+    // Links added before `expired_before` are dropped.
+    pub fn add_summary_source(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        (source_chat_id, first_message_id, last_message_id): (i64, i32, i32),
+        created_at: u64,
+        expired_before: u64,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "DELETE FROM summary_sources WHERE created_at < ?",
+            [expired_before],
+        )?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO summary_sources
+                (chat_id, message_id, source_chat_id, first_message_id, last_message_id, created_at)
+                VALUES (?, ?, ?, ?, ?, ?)",
+            rusqlite::params![
+                chat_id,
+                message_id,
+                source_chat_id,
+                first_message_id,
+                last_message_id,
+                created_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    // (source chat id, first message id, last message id) of the summary, if it was sent since the given unix time.
+    pub fn get_summary_source(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        since: u64,
+    ) -> rusqlite::Result<Option<(i64, i32, i32)>> {
+        self.connection
+            .query_row(
+                "SELECT source_chat_id, first_message_id, last_message_id FROM summary_sources
+                    WHERE chat_id = ? AND message_id = ? AND created_at >= ?",
+                rusqlite::params![chat_id, message_id, since],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+    }
+
+    pub fn mark_chat_removed(&self, chat_id: i64) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO removed_chats (chat_id, removed_at) VALUES (?, datetime('now'))",
+            [chat_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn unmark_chat_removed(&self, chat_id: i64) -> rusqlite::Result<()> {
+        self.connection
+            .execute("DELETE FROM removed_chats WHERE chat_id = ?", [chat_id])?;
+        Ok(())
+    }
+
+    // Creates the chat's config with the defaults. Returns false if the chat was already welcomed.
+    pub fn mark_chat_welcomed(&self, chat_id: i64) -> rusqlite::Result<bool> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO chat_config (chat_id) VALUES (?)",
+            [chat_id],
+        )?;
+        let added = self.connection.execute(
+            "INSERT OR IGNORE INTO welcomed_chats (chat_id, welcomed_at) VALUES (?, datetime('now'))",
+            [chat_id],
+        )?;
+        Ok(added > 0)
+    }
+
+    // Run before the maintenance. Deletes the rows that are past their TTL and never read again.
+    pub fn purge_expired(&self, now: u64) -> rusqlite::Result<()> {
+        let expired_before = now.saturating_sub(consts::ASK_THREAD_TTL.as_secs());
+        self.connection.execute(
+            "DELETE FROM ask_threads WHERE asked_at < ?",
+            [expired_before],
+        )?;
+        let expired_before = now.saturating_sub(consts::RATED_SUMMARY_TTL.as_secs());
+        self.connection.execute(
+            "DELETE FROM feedback WHERE summary_id IN (
+                SELECT id FROM rated_summaries WHERE created_at < ?
+            )",
+            [expired_before],
+        )?;
+        self.connection.execute(
+            "DELETE FROM rated_summaries WHERE created_at < ?",
+            [expired_before],
+        )?;
+        Ok(())
+    }
+
+    // Compacts the file fragmented by the deleted messages and refreshes the statistics
+    // the query planner relies on.
+    pub fn maintain(&self) -> rusqlite::Result<()> {
+        for statement in MAINTENANCE_STATEMENTS {
+            self.connection.execute_batch(statement)?;
+        }
+        Ok(())
+    }
+
+    // Run on shutdown. Moves the pages written to the WAL into the database file and truncates
+    // the log, so the latest stored ids don't depend on the log surviving. Does nothing without WAL.
+    pub fn checkpoint(&self) -> rusqlite::Result<()> {
+        let busy: i64 =
+            self.connection
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+        if busy != 0 {
+            log::warn!("Database checkpoint didn't complete, the log is in use");
+        }
+        Ok(())
+    }
+
+    // Deletes all data of chats the bot was removed from more than `grace_period` ago.
+    pub fn purge_removed_chats(
+        &self,
+        grace_period: std::time::Duration,
+    ) -> rusqlite::Result<Vec<i64>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT chat_id FROM removed_chats WHERE removed_at <= datetime('now', ?)")?;
+        let modifier = format!("-{} seconds", grace_period.as_secs());
+        let chat_ids = statement
+            .query_map([modifier], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+
+        for chat_id in &chat_ids {
+            self.connection
+                .execute("DELETE FROM messages WHERE chat_id = ?", [chat_id])?;
+            self.connection
+                .execute("DELETE FROM chat_config WHERE chat_id = ?", [chat_id])?;
+            self.connection
+                .execute("DELETE FROM known_chats WHERE chat_id = ?", [chat_id])?;
+            self.connection
+                .execute("DELETE FROM last_summaries WHERE chat_id = ?", [chat_id])?;
+            self.connection
+                .execute("DELETE FROM opted_out_users WHERE chat_id = ?", [chat_id])?;
+            self.connection
+                .execute("DELETE FROM ask_threads WHERE chat_id = ?", [chat_id])?;
+            self.connection.execute(
+                "DELETE FROM feedback WHERE summary_id IN (
+                    SELECT id FROM rated_summaries WHERE chat_id = ?1 OR recipient_id = ?1
+                )",
+                [chat_id],
+            )?;
+            self.connection.execute(
+                "DELETE FROM rated_summaries WHERE chat_id = ?1 OR recipient_id = ?1",
+                [chat_id],
+            )?;
+            self.connection.execute(
+                "DELETE FROM summary_sources WHERE source_chat_id = ?",
+                [chat_id],
+            )?;
+            self.connection
+                .execute("DELETE FROM welcomed_chats WHERE chat_id = ?", [chat_id])?;
+            self.remove_chat_pending_commands(*chat_id)?;
+            self.connection
+                .execute("DELETE FROM removed_chats WHERE chat_id = ?", [chat_id])?;
+        }
+
+        Ok(chat_ids)
     }
 
-    pub fn get_messages_id(&self, chat_id: i64, count: u32) -> anyhow::Result<Vec<i32>> {
-        let statement = format!("SELECT message_id FROM g{chat_id} ORDER BY id DESC LIMIT ?",);
+    pub fn get_chat_config(&self, chat_id: i64) -> rusqlite::Result<ChatConfig> {
+        let config = self
+            .connection
+            .query_row(
+                "SELECT * FROM chat_config WHERE chat_id = ?",
+                [chat_id],
+                |row| {
+                    let quiet_hours_start: Option<u32> = row.get("quiet_hours_start")?;
+                    let quiet_hours_end: Option<u32> = row.get("quiet_hours_end")?;
+                    Ok(ChatConfig {
+                        min_message_length: row.get("min_message_length")?,
+                        ask_prompt: row.get("ask_prompt")?,
+                        quiet_hours: quiet_hours_start
+                            .zip(quiet_hours_end)
+                            .map(|(start, end)| -> rusqlite::Result<QuietHours> {
+                                Ok(QuietHours {
+                                    start,
+                                    end,
+                                    utc_offset_minutes: row.get("quiet_hours_utc_offset")?,
+                                })
+                            })
+                            .transpose()?,
+                        enabled: row.get("enabled")?,
+                        store_while_disabled: row.get("store_while_disabled")?,
+                        summary_reaction: row.get("summary_reaction")?,
+                        whisper_prompt: row.get("whisper_prompt")?,
+                        summary_interval_minutes: row.get("summary_interval_minutes")?,
+                        bullet_summaries: row.get("bullet_summaries")?,
+                        cleanup_commands: row.get("cleanup_commands")?,
+                        default_length: row.get("default_length")?,
+                        forced_language: row.get("forced_language")?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(config.unwrap_or_default())
+    }
 
-        let mut statement = self.connection.prepare(&statement)?;
-        let mut rows = statement.query([count])?;
+    pub fn set_chat_config(&self, chat_id: i64, config: &ChatConfig) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO chat_config (
+                chat_id,
+                min_message_length,
+                ask_prompt,
+                quiet_hours_start,
+                quiet_hours_end,
+                quiet_hours_utc_offset,
+                enabled,
+                store_while_disabled,
+                summary_reaction,
+                whisper_prompt,
+                summary_interval_minutes,
+                bullet_summaries,
+                cleanup_commands,
+                default_length,
+                forced_language
+            ) VALUES (
+                :chat_id,
+                :min_message_length,
+                :ask_prompt,
+                :quiet_hours_start,
+                :quiet_hours_end,
+                :quiet_hours_utc_offset,
+                :enabled,
+                :store_while_disabled,
+                :summary_reaction,
+                :whisper_prompt,
+                :summary_interval_minutes,
+                :bullet_summaries,
+                :cleanup_commands,
+                :default_length,
+                :forced_language
+            )",
+            rusqlite::named_params! {
+                ":chat_id": chat_id,
+                ":min_message_length": config.min_message_length,
+                ":ask_prompt": config.ask_prompt,
+                ":quiet_hours_start": config.quiet_hours.map(|quiet_hours| quiet_hours.start),
+                ":quiet_hours_end": config.quiet_hours.map(|quiet_hours| quiet_hours.end),
+                ":quiet_hours_utc_offset": config
+                    .quiet_hours
+                    .map_or(0, |quiet_hours| quiet_hours.utc_offset_minutes),
+                ":enabled": config.enabled,
+                ":store_while_disabled": config.store_while_disabled,
+                ":summary_reaction": config.summary_reaction,
+                ":whisper_prompt": config.whisper_prompt,
+                ":summary_interval_minutes": config.summary_interval_minutes,
+                ":bullet_summaries": config.bullet_summaries,
+                ":cleanup_commands": config.cleanup_commands,
+                ":default_length": config.default_length,
+                ":forced_language": config.forced_language,
+            },
+        )?;
+        Ok(())
+    }
+
+    // Messages of a forum topic if `topic_id` is set, otherwise of the whole chat.
+    pub fn get_messages_id(
+        &self,
+        chat_id: i64,
+        topic_id: Option<i32>,
+        count: u32,
+    ) -> rusqlite::Result<Vec<i32>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT message_id FROM messages WHERE chat_id = ? AND {IN_TOPIC} ORDER BY id DESC LIMIT ?"
+        ))?;
+        let mut rows = statement.query(rusqlite::params![chat_id, topic_id, count])?;
 
         let mut message_ids = Vec::new();
         while let Some(row) = rows.next()? {
@@ -26,34 +966,780 @@ impl Db {
         Ok(message_ids)
     }
 
-    pub fn add_message_id(&self, chat_id: i64, message_id: i32) -> anyhow::Result<()> {
-        // First we have to check if we have a table with the chat_id name. If not we have to create it.
-        // Then we have to insert the message_id into the table.
-        // Also, we need maintain the table size to be consts::MESSAGE_TO_STORE messages.
+    // Latest messages stored no longer than `window` ago.
+    pub fn get_messages_id_within(
+        &self,
+        chat_id: i64,
+        topic_id: Option<i32>,
+        count: u32,
+        window: std::time::Duration,
+    ) -> rusqlite::Result<Vec<i32>> {
+        let modifier = format!("-{} seconds", window.as_secs());
 
-        let table_statement = format!(
-            "CREATE TABLE IF NOT EXISTS g{chat_id} (
-                id INTEGER PRIMARY KEY,
-                timestamp TEXT NOT NULL,
-                message_id INTEGER NOT NULL
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT message_id FROM messages WHERE chat_id = ? AND {IN_TOPIC} AND timestamp >= datetime('now', ?) ORDER BY id DESC LIMIT ?"
+        ))?;
+        let message_ids = statement
+            .query_map(
+                rusqlite::params![chat_id, topic_id, modifier, count],
+                |row| row.get(0),
+            )?
+            .collect::<Result<Vec<i32>, _>>()?;
+        Ok(message_ids)
+    }
+
+    // Latest messages stored from `start` until `end`, in unix time.
+    pub fn get_messages_id_in_period(
+        &self,
+        chat_id: i64,
+        topic_id: Option<i32>,
+        count: u32,
+        start: u64,
+        end: u64,
+    ) -> rusqlite::Result<Vec<i32>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT message_id FROM messages WHERE chat_id = ? AND {IN_TOPIC} AND timestamp >= datetime(?, 'unixepoch') AND timestamp < datetime(?, 'unixepoch') ORDER BY id DESC LIMIT ?"
+        ))?;
+        let message_ids = statement
+            .query_map(
+                rusqlite::params![chat_id, topic_id, start, end, count],
+                |row| row.get(0),
+            )?
+            .collect::<Result<Vec<i32>, _>>()?;
+        Ok(message_ids)
+    }
+
+    // Stored ids from `first` to `last` inclusive, the newest first.
+    pub fn get_messages_id_between(
+        &self,
+        chat_id: i64,
+        topic_id: Option<i32>,
+        first: i32,
+        last: i32,
+    ) -> rusqlite::Result<Vec<i32>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT message_id FROM messages WHERE chat_id = ? AND {IN_TOPIC} AND message_id BETWEEN ? AND ? ORDER BY message_id DESC"
+        ))?;
+        let message_ids = statement
+            .query_map(rusqlite::params![chat_id, topic_id, first, last], |row| {
+                row.get(0)
+            })?
+            .collect::<Result<Vec<i32>, _>>()?;
+        Ok(message_ids)
+    }
+
+    // Up to `radius` stored messages before and after the message, newest first.
+    // The message itself is included even if it wasn't stored.
+    pub fn get_messages_id_around(
+        &self,
+        chat_id: i64,
+        topic_id: Option<i32>,
+        message_id: i32,
+        radius: u32,
+    ) -> rusqlite::Result<Vec<i32>> {
+        let query = |statement: &str| -> rusqlite::Result<Vec<i32>> {
+            let mut statement = self.connection.prepare(statement)?;
+            let message_ids = statement
+                .query_map(
+                    rusqlite::params![chat_id, topic_id, message_id, radius],
+                    |row| row.get(0),
+                )?
+                .collect::<Result<Vec<i32>, _>>()?;
+            Ok(message_ids)
+        };
+        let after = query(&format!(
+            "SELECT message_id FROM messages WHERE chat_id = ? AND {IN_TOPIC} AND message_id > ? ORDER BY message_id ASC LIMIT ?"
+        ))?;
+        let before = query(&format!(
+            "SELECT message_id FROM messages WHERE chat_id = ? AND {IN_TOPIC} AND message_id < ? ORDER BY message_id DESC LIMIT ?"
+        ))?;
+
+        let mut message_ids = after.into_iter().rev().collect::<Vec<_>>();
+        message_ids.push(message_id);
+        message_ids.extend(before);
+        Ok(message_ids)
+    }
+
+    pub fn add_message_id(&self, chat_id: i64, message_id: i32) -> rusqlite::Result<()> {
+        // We need maintain the number of stored messages of the chat to be consts::MESSAGE_TO_STORE.
+        let _inserted = self.connection.execute(
+            "INSERT INTO messages (chat_id, timestamp, message_id) VALUES (?, datetime('now'), ?)",
+            rusqlite::params![chat_id, message_id],
+        )?;
+
+        self.trim_messages(chat_id)
+    }
+
+    // Forum topic of a stored message, topics are summarized on their own.
+    pub fn set_message_topic(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        topic_id: i32,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "UPDATE messages SET topic_id = ? WHERE chat_id = ? AND message_id = ?",
+            rusqlite::params![topic_id, chat_id, message_id],
+        )?;
+        Ok(())
+    }
+
+    // Keeps the text of a stored message, so summaries read it from the database instead of fetching
+    // it again. The text goes away with the message id, the latest consts::MESSAGE_TO_STORE are kept.
+    pub fn add_message_text(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        sender_id: Option<i64>,
+        sender_username: Option<&str>,
+        text: &str,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "UPDATE messages SET sender_id = ?, sender_username = ?, text = ? WHERE chat_id = ? AND message_id = ?",
+            rusqlite::params![sender_id, sender_username, text, chat_id, message_id],
+        )?;
+        Ok(())
+    }
+
+    // The text is fetched with the message again, like when it was edited while texts aren't stored.
+    pub fn forget_message_text(&self, chat_id: i64, message_id: i32) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "UPDATE messages SET sender_username = NULL, text = NULL WHERE chat_id = ? AND message_id = ?",
+            rusqlite::params![chat_id, message_id],
+        )?;
+        Ok(())
+    }
+
+    // Deleted messages are left out of the summaries, their stored texts included.
+    pub fn remove_messages(&self, chat_id: i64, message_ids: &[i32]) -> rusqlite::Result<usize> {
+        let mut statement = self
+            .connection
+            .prepare("DELETE FROM messages WHERE chat_id = ? AND message_id = ?")?;
+        let mut removed = 0;
+        for message_id in message_ids {
+            removed += statement.execute(rusqlite::params![chat_id, message_id])?;
+        }
+        Ok(removed)
+    }
+
+    // The messages among `message_ids` that have their text stored, in no particular order.
+    pub fn get_messages_with_text(
+        &self,
+        chat_id: i64,
+        message_ids: &[i32],
+    ) -> rusqlite::Result<Vec<StoredMessage>> {
+        let mut statement = self.connection.prepare(
+            "SELECT message_id, sender_id, sender_username, text FROM messages WHERE chat_id = ? AND message_id = ? AND text IS NOT NULL",
+        )?;
+        let mut messages = Vec::with_capacity(message_ids.len());
+        for message_id in message_ids {
+            let message = statement
+                .query_row(rusqlite::params![chat_id, message_id], |row| {
+                    Ok(StoredMessage {
+                        message_id: row.get(0)?,
+                        sender_id: row.get(1)?,
+                        sender_username: row.get(2)?,
+                        text: row.get(3)?,
+                    })
+                })
+                .optional()?;
+            messages.extend(message);
+        }
+        Ok(messages)
+    }
+
+    // Stores up to `limit` messages older than the stored ones, given newest first with their unix
+    // times. They get ids below all stored rows. Older messages would be trimmed right away, so it
+    // stops once the chat is full and returns how many were kept.
+    pub fn add_older_messages(
+        &self,
+        chat_id: i64,
+        messages: &[(i32, u64)],
+        limit: usize,
+    ) -> rusqlite::Result<usize> {
+        let mut next_id = self.connection.query_row(
+            "SELECT COALESCE(MIN(id), 1) - 1 FROM messages",
+            [],
+            |row| row.get::<_, i64>(0),
+        )?;
+        let oldest_message_id = self
+            .connection
+            .query_row(
+                "SELECT message_id FROM messages WHERE chat_id = ? ORDER BY id ASC LIMIT 1",
+                [chat_id],
+                |row| row.get::<_, i32>(0),
+            )
+            .optional()?
+            .unwrap_or(i32::MAX);
+        let room =
+            (consts::MESSAGE_TO_STORE as usize).saturating_sub(self.count_messages(chat_id)?);
+
+        let mut inserted = 0;
+        for (message_id, date) in messages
+            .iter()
+            .filter(|(message_id, _)| *message_id < oldest_message_id)
+            .take(limit.min(room))
+        {
+            self.connection.execute(
+                "INSERT INTO messages (id, chat_id, timestamp, message_id) VALUES (?, ?, datetime(?, 'unixepoch'), ?)",
+                rusqlite::params![next_id, chat_id, date, message_id],
+            )?;
+            next_id -= 1;
+            inserted += 1;
+        }
+        Ok(inserted)
+    }
+
+    // Number of the stored messages of the chat, at most consts::MESSAGE_TO_STORE.
+    pub fn count_messages(&self, chat_id: i64) -> rusqlite::Result<usize> {
+        self.connection.query_row(
+            "SELECT COUNT(*) FROM messages WHERE chat_id = ?",
+            [chat_id],
+            |row| row.get(0),
+        )
+    }
+
+    fn trim_messages(&self, chat_id: i64) -> rusqlite::Result<()> {
+        let _removed = self.connection.execute(
+            "DELETE FROM messages WHERE chat_id = ?1 AND id NOT IN (
+                SELECT id FROM messages WHERE chat_id = ?1 ORDER BY id DESC LIMIT ?2
             )",
+            rusqlite::params![chat_id, consts::MESSAGE_TO_STORE],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_config_round_trip() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        assert_eq!(db.get_chat_config(1).unwrap(), ChatConfig::default());
+
+        let config = ChatConfig {
+            min_message_length: 3,
+            ask_prompt: Some("Answer like a pirate".to_string()),
+            quiet_hours: Some(QuietHours {
+                start: 23,
+                end: 7,
+                utc_offset_minutes: -180,
+            }),
+            enabled: false,
+            store_while_disabled: false,
+            summary_reaction: Some("👀".to_string()),
+            whisper_prompt: Some("ohsumbot, grammers, Kyiv".to_string()),
+            summary_interval_minutes: 15,
+            bullet_summaries: true,
+            cleanup_commands: false,
+            default_length: Some("large".to_string()),
+            forced_language: Some("uk".to_string()),
+        };
+        db.set_chat_config(1, &config).unwrap();
+        assert_eq!(db.get_chat_config(1).unwrap(), config);
+        assert_eq!(db.get_chat_config(2).unwrap(), ChatConfig::default());
+    }
+
+    #[test]
+    fn negative_chat_ids_round_trip() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let supergroup = -1001234567890;
+        for message_id in 1..=3 {
+            db.add_message_id(supergroup, message_id).unwrap();
+        }
+        db.add_message_id(-42, 7).unwrap();
+
+        assert_eq!(
+            db.get_messages_id(supergroup, None, 10).unwrap(),
+            vec![3, 2, 1]
+        );
+        assert_eq!(db.get_messages_id(-42, None, 10).unwrap(), vec![7]);
+        assert!(db.get_messages_id(1234567890, None, 10).unwrap().is_empty());
+        assert_eq!(
+            db.get_messages_id_between(supergroup, None, 2, 3).unwrap(),
+            vec![3, 2]
+        );
+        assert_eq!(
+            db.get_messages_id_around(supergroup, None, 2, 1).unwrap(),
+            vec![3, 2, 1]
         );
+    }
 
-        self.connection.execute(&table_statement, [])?;
+    #[test]
+    fn chat_tables_are_migrated() {
+        let path = std::env::temp_dir().join(format!("ohsumbot-migrate-{}.db", std::process::id()));
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE g5 (
+                    id INTEGER PRIMARY KEY,
+                    timestamp TEXT NOT NULL,
+                    message_id INTEGER NOT NULL
+                );
+                INSERT INTO g5 (timestamp, message_id) VALUES
+                    ('2024-03-10 00:00:00', 10),
+                    ('2024-03-10 00:01:00', 11),
+                    ('2024-03-10 00:02:00', 12);",
+            )
+            .unwrap();
+        drop(connection);
 
-        let insert_statement =
-            format!("INSERT INTO g{chat_id} (timestamp, message_id) VALUES (datetime('now'), ?)",);
-        let _inserted = self.connection.execute(&insert_statement, [message_id])?;
+        let db = Db::new_with_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(db.get_messages_id(5, None, 10).unwrap(), vec![12, 11, 10]);
+        // 2024-03-10 00:00 to 00:01 UTC
+        assert_eq!(
+            db.get_messages_id_in_period(5, None, 10, 1_710_028_800, 1_710_028_860)
+                .unwrap(),
+            vec![10]
+        );
+        let tables: i64 = db
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'g5'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+        db.add_message_id(5, 13).unwrap();
+        assert_eq!(db.get_messages_id(5, None, 2).unwrap(), vec![13, 12]);
 
-        let delete_statement = format!(
-            "DELETE FROM g{chat_id} WHERE id NOT IN (
-                SELECT id FROM g{chat_id} ORDER BY id DESC LIMIT ?
-            )",
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn users_opt_out_per_chat() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        db.set_opted_out(1, 10, true).unwrap();
+        db.set_opted_out(1, 10, true).unwrap();
+        db.set_opted_out(1, 11, true).unwrap();
+        db.set_opted_out(2, 12, true).unwrap();
+        assert_eq!(db.get_opted_out_users(1).unwrap(), vec![10, 11]);
+
+        db.set_opted_out(1, 10, false).unwrap();
+        assert_eq!(db.get_opted_out_users(1).unwrap(), vec![11]);
+        assert_eq!(db.get_opted_out_users(2).unwrap(), vec![12]);
+        assert!(db.get_opted_out_users(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ask_thread_keeps_latest_exchanges() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for i in 0..consts::ASK_THREAD_SIZE + 1 {
+            db.add_ask_exchange(
+                1,
+                10,
+                100 + u64::from(i),
+                &format!("Q{i}"),
+                &format!("A{i}"),
+            )
+            .unwrap();
+        }
+        db.add_ask_exchange(1, 11, 100, "Other user", "Other answer")
+            .unwrap();
+
+        let thread = db.get_ask_thread(1, 10, 0).unwrap();
+        assert_eq!(thread.len(), consts::ASK_THREAD_SIZE as usize);
+        assert_eq!(thread[0], ("Q1".to_string(), "A1".to_string()));
+        // Older exchanges are out of the session.
+        assert_eq!(
+            db.get_ask_thread(1, 10, 100 + u64::from(consts::ASK_THREAD_SIZE))
+                .unwrap()
+                .len(),
+            1
         );
-        let _removed = self
+
+        db.clear_ask_thread(1, 10).unwrap();
+        assert!(db.get_ask_thread(1, 10, 0).unwrap().is_empty());
+        assert_eq!(db.get_ask_thread(1, 11, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn expired_ask_threads_are_purged() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let ttl = consts::ASK_THREAD_TTL.as_secs();
+        db.add_ask_exchange(1, 10, 1000, "Old", "Answer").unwrap();
+        db.add_ask_exchange(1, 11, 1000 + ttl, "Recent", "Answer")
+            .unwrap();
+
+        db.purge_expired(1000 + ttl + 1).unwrap();
+        assert!(db.get_ask_thread(1, 10, 0).unwrap().is_empty());
+        assert_eq!(db.get_ask_thread(1, 11, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn chat_config_columns_are_added_to_old_tables() {
+        let db = Db {
+            connection: Connection::open_in_memory().unwrap(),
+        };
+        db.connection
+            .execute(
+                "CREATE TABLE chat_config (chat_id INTEGER PRIMARY KEY, min_message_length INTEGER NOT NULL DEFAULT 0)",
+                [],
+            )
+            .unwrap();
+        db.connection
+            .execute("INSERT INTO chat_config VALUES (1, 5)", [])
+            .unwrap();
+
+        db.create_tables().unwrap();
+        assert_eq!(
+            db.get_chat_config(1).unwrap(),
+            ChatConfig {
+                min_message_length: 5,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn dead_letters_are_recorded() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        db.record_dead_letter("summarize", Some(1), Some(2), "OpenAI is down")
+            .unwrap();
+        db.record_dead_letter("send_prompt", None, Some(2), "Flood wait")
+            .unwrap();
+
+        let dead_letters = db.get_dead_letters(10).unwrap();
+        assert_eq!(dead_letters.len(), 2);
+        assert_eq!(dead_letters[0].command, "send_prompt");
+        assert_eq!(dead_letters[0].chat_id, None);
+        assert_eq!(dead_letters[1].command, "summarize");
+        assert_eq!(dead_letters[1].chat_id, Some(1));
+        assert_eq!(dead_letters[1].user_id, Some(2));
+        assert_eq!(dead_letters[1].error, "OpenAI is down");
+        assert_eq!(db.get_dead_letters(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pending_commands_are_kept_until_removed() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let ack = PendingAck {
+            chat: vec![1, 2, 3],
+            message_id: 7,
+        };
+        let first = db
+            .add_pending_command(Some(1), Some(2), "summarize", Some(&ack))
+            .unwrap();
+        let second = db
+            .add_pending_command(None, Some(3), "digest", None)
+            .unwrap();
+        let third = db
+            .add_pending_command(Some(4), Some(1), "ask", None)
+            .unwrap();
+        assert_eq!(
+            db.get_pending_commands().unwrap(),
+            vec![
+                (first, "summarize".to_string(), Some(ack)),
+                (second, "digest".to_string(), None),
+                (third, "ask".to_string(), None)
+            ]
+        );
+
+        db.remove_pending_command(second).unwrap();
+        db.remove_chat_pending_commands(1).unwrap();
+        assert!(db.get_pending_commands().unwrap().is_empty());
+    }
+
+    #[test]
+    fn purge_removed_chats_after_grace_period() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        db.add_message_id(1, 10).unwrap();
+        db.add_message_id(2, 20).unwrap();
+        db.remember_chat(1, &[1, 2, 3]).unwrap();
+        db.mark_chat_removed(1).unwrap();
+        db.mark_chat_removed(2).unwrap();
+        db.unmark_chat_removed(2).unwrap();
+
+        let hour = std::time::Duration::from_secs(3600);
+        assert!(db.purge_removed_chats(hour).unwrap().is_empty());
+        assert_eq!(
+            db.purge_removed_chats(std::time::Duration::ZERO).unwrap(),
+            vec![1]
+        );
+        assert_eq!(db.get_known_chat(1).unwrap(), None);
+        db.add_message_id(1, 11).unwrap();
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![11]);
+        assert_eq!(db.get_messages_id(2, None, 10).unwrap(), vec![20]);
+    }
+
+    #[test]
+    fn rated_summaries_are_purged_with_either_chat() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let ttl = consts::RATED_SUMMARY_TTL.as_secs();
+        let of_group = db.add_rated_summary(1, 10, "", "", ttl).unwrap();
+        let to_group = db.add_rated_summary(2, 1, "", "", ttl).unwrap();
+        let expired = db.add_rated_summary(3, 10, "", "", 0).unwrap();
+        let kept = db.add_rated_summary(3, 10, "", "", ttl).unwrap();
+        for summary_id in [of_group, to_group, expired, kept] {
+            db.set_feedback(summary_id, 10, 1).unwrap();
+        }
+
+        db.purge_expired(ttl + 1).unwrap();
+        db.mark_chat_removed(1).unwrap();
+        db.purge_removed_chats(std::time::Duration::ZERO).unwrap();
+        for summary_id in [of_group, to_group, expired] {
+            assert!(!db.set_feedback(summary_id, 11, 1).unwrap());
+        }
+        assert!(db.set_feedback(kept, 11, 1).unwrap());
+        let feedback: i64 = db
             .connection
-            .execute(&delete_statement, [consts::MESSAGE_TO_STORE])?;
+            .query_row("SELECT COUNT(*) FROM feedback", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(feedback, 2);
+    }
 
-        Ok(())
+    #[test]
+    fn older_messages_stop_when_the_chat_is_full() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let latest = consts::MESSAGE_TO_STORE as i32 + 10;
+        for message_id in 13..latest {
+            db.add_message_id(1, message_id).unwrap();
+        }
+
+        let older = (1..=12).rev().map(|id| (id, 0)).collect::<Vec<_>>();
+        assert_eq!(db.add_older_messages(1, &older, 100).unwrap(), 3);
+        assert_eq!(
+            db.count_messages(1).unwrap(),
+            consts::MESSAGE_TO_STORE as usize
+        );
+        assert_eq!(db.add_older_messages(1, &older, 100).unwrap(), 0);
+        let stored = db
+            .get_messages_id(1, None, consts::MESSAGE_TO_STORE)
+            .unwrap();
+        assert_eq!(stored.first(), Some(&(latest - 1)));
+        assert_eq!(stored.last(), Some(&10));
+    }
+
+    #[test]
+    fn maintenance_compacts_and_analyzes() {
+        let path =
+            std::env::temp_dir().join(format!("ohsumbot-maintain-{}.db", std::process::id()));
+        let db = Db::new_with_file(path.to_str().unwrap()).unwrap();
+        for message_id in 0..(consts::MESSAGE_TO_STORE as i32 + 500) {
+            db.add_message_id(1, message_id).unwrap();
+            db.add_message_id(2, message_id).unwrap();
+        }
+        // Freed pages of trimmed messages are reused, purging a chat leaves them empty.
+        db.connection
+            .execute("DELETE FROM messages WHERE chat_id = 2", [])
+            .unwrap();
+        let pragma = |name: &str| -> i64 {
+            db.connection
+                .query_row(&format!("PRAGMA {name}"), [], |row| row.get(0))
+                .unwrap()
+        };
+        assert!(pragma("freelist_count") > 0);
+
+        db.maintain().unwrap();
+        assert_eq!(pragma("freelist_count"), 0);
+        let analyzed: i64 = db
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_stat1 WHERE tbl = 'messages'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(analyzed, 1);
+        assert_eq!(db.get_messages_id(1, None, 1).unwrap(), vec![1499]);
+
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_checkpoints_the_log() {
+        let path =
+            std::env::temp_dir().join(format!("ohsumbot-checkpoint-{}.db", std::process::id()));
+        let wal = path.with_extension("db-wal");
+        let db = Db::new_with_file(path.to_str().unwrap()).unwrap();
+        db.connection
+            .query_row("PRAGMA journal_mode = WAL", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap();
+        db.add_message_id(1, 42).unwrap();
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+
+        db.checkpoint().unwrap();
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+        drop(db);
+
+        let db = Db::new_with_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![42]);
+        // Without WAL there is nothing to checkpoint.
+        Db::new_with_file(":memory:").unwrap().checkpoint().unwrap();
+
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(wal).ok();
+    }
+
+    #[test]
+    fn messages_are_loaded_within_window() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in 1..=4 {
+            db.add_message_id(1, message_id).unwrap();
+        }
+        db.connection
+            .execute(
+                "UPDATE messages SET timestamp = datetime('now', '-2 days') WHERE chat_id = 1 AND message_id <= 2",
+                [],
+            )
+            .unwrap();
+
+        let day = std::time::Duration::from_secs(24 * 3600);
+        assert_eq!(
+            db.get_messages_id_within(1, None, 10, day).unwrap(),
+            vec![4, 3]
+        );
+        assert_eq!(db.get_messages_id_within(1, None, 1, day).unwrap(), vec![4]);
+        assert_eq!(
+            db.get_messages_id_within(1, None, 10, 3 * day).unwrap(),
+            vec![4, 3, 2, 1]
+        );
+    }
+
+    #[test]
+    fn messages_are_loaded_for_a_day() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in 1..=4 {
+            db.add_message_id(1, message_id).unwrap();
+        }
+        for (message_id, timestamp) in [
+            (1, "2024-03-09 23:59:59"),
+            (2, "2024-03-10 00:00:00"),
+            (3, "2024-03-10 18:30:00"),
+            (4, "2024-03-11 00:00:00"),
+        ] {
+            db.connection
+                .execute(
+                    "UPDATE messages SET timestamp = ? WHERE chat_id = 1 AND message_id = ?",
+                    rusqlite::params![timestamp, message_id],
+                )
+                .unwrap();
+        }
+
+        // 2024-03-10 UTC
+        let (start, end) = (1_710_028_800, 1_710_115_200);
+        assert_eq!(
+            db.get_messages_id_in_period(1, None, 10, start, end)
+                .unwrap(),
+            vec![3, 2]
+        );
+        assert_eq!(
+            db.get_messages_id_in_period(1, None, 1, start, end)
+                .unwrap(),
+            vec![3]
+        );
+        assert!(db
+            .get_messages_id_in_period(1, None, 10, end + 86_400, end + 2 * 86_400)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn messages_are_scoped_to_forum_topic() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in 1..=5 {
+            db.add_message_id(1, message_id).unwrap();
+        }
+        db.set_message_topic(1, 2, 100).unwrap();
+        db.set_message_topic(1, 4, 100).unwrap();
+        db.set_message_topic(1, 5, 200).unwrap();
+
+        assert_eq!(db.get_messages_id(1, Some(100), 10).unwrap(), vec![4, 2]);
+        assert_eq!(db.get_messages_id(1, Some(100), 1).unwrap(), vec![4]);
+        assert_eq!(
+            db.get_messages_id_within(1, Some(200), 10, std::time::Duration::from_secs(60))
+                .unwrap(),
+            vec![5]
+        );
+        assert_eq!(
+            db.get_messages_id_around(1, Some(100), 4, 1).unwrap(),
+            vec![4, 2]
+        );
+        // Without a topic, like in the General topic, the whole chat is summarized.
+        assert_eq!(
+            db.get_messages_id(1, None, 10).unwrap(),
+            vec![5, 4, 3, 2, 1]
+        );
+        assert!(db.get_messages_id(1, Some(300), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn messages_are_loaded_around_message() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in [10, 11, 12, 14, 15, 16, 17] {
+            db.add_message_id(1, message_id).unwrap();
+        }
+
+        assert_eq!(
+            db.get_messages_id_around(1, None, 14, 2).unwrap(),
+            vec![16, 15, 14, 12, 11]
+        );
+        // The start and the end of the stored history.
+        assert_eq!(
+            db.get_messages_id_around(1, None, 11, 3).unwrap(),
+            vec![15, 14, 12, 11, 10]
+        );
+        assert_eq!(
+            db.get_messages_id_around(1, None, 17, 2).unwrap(),
+            vec![17, 16, 15]
+        );
+        // A message that wasn't stored, like a short one, is still the center.
+        assert_eq!(
+            db.get_messages_id_around(1, None, 13, 1).unwrap(),
+            vec![14, 13, 12]
+        );
+    }
+
+    #[test]
+    fn message_texts_are_kept_until_the_sender_opts_out() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in [1, 2, 3] {
+            db.add_message_id(1, message_id).unwrap();
+        }
+        db.add_message_text(1, 1, Some(10), Some("alice"), "Deploy on Monday")
+            .unwrap();
+        db.add_message_text(1, 2, Some(11), None, "Fine by me")
+            .unwrap();
+
+        // Message 3 is media, it has no text and is fetched instead.
+        let mut messages = db.get_messages_with_text(1, &[3, 2, 1]).unwrap();
+        messages.sort_by_key(|message| message.message_id);
+        assert_eq!(
+            messages,
+            vec![
+                StoredMessage {
+                    message_id: 1,
+                    sender_id: Some(10),
+                    sender_username: Some("alice".to_string()),
+                    text: "Deploy on Monday".to_string(),
+                },
+                StoredMessage {
+                    message_id: 2,
+                    sender_id: Some(11),
+                    sender_username: None,
+                    text: "Fine by me".to_string(),
+                },
+            ]
+        );
+        assert!(db.get_messages_with_text(2, &[1, 2]).unwrap().is_empty());
+
+        db.set_opted_out(1, 10, true).unwrap();
+        let messages = db.get_messages_with_text(1, &[3, 2, 1]).unwrap();
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.message_id)
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![3, 2, 1]);
     }
 }
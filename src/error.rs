@@ -0,0 +1,217 @@
+use grammers_client::InvocationError;
+
+pub type Result<T, E = BotError> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BotError {
+    // Problem with the user's request. The message is shown to the user as is.
+    #[error("{0}")]
+    UserFacing(String),
+    #[error("OpenAI request failed: {0}")]
+    OpenAi(String),
+    #[error("Telegram request failed: {0}")]
+    Telegram(#[from] InvocationError),
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    // Downloading, converting or transcribing media failed. The message is shown to the user.
+    #[error("{0}")]
+    Media(String),
+    #[error("Command queue is closed")]
+    QueueClosed,
+}
+
+// Why an OpenAI request failed. The client only reports errors as text, so they are told apart by it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenAiFailure {
+    RateLimited,
+    // The model is overloaded or down.
+    Unavailable,
+    Other,
+}
+
+impl OpenAiFailure {
+    pub fn of(error: &str) -> Self {
+        let error = error.to_lowercase();
+        let status = http_status(&error);
+        if status == Some(429)
+            || ["rate limit", "rate_limit"]
+                .iter()
+                .any(|code| error.contains(code))
+        {
+            OpenAiFailure::RateLimited
+        } else if matches!(status, Some(500..=599))
+            || ["overloaded", "server_error"]
+                .iter()
+                .any(|code| error.contains(code))
+        {
+            OpenAiFailure::Unavailable
+        } else {
+            OpenAiFailure::Other
+        }
+    }
+
+    // Rate limits and outages pass, so the request is worth sending again.
+    pub fn is_transient(self) -> bool {
+        self != OpenAiFailure::Other
+    }
+}
+
+// Status of the failed HTTP request, like "status code 503", "http status: 503" or
+// "503 Service Unavailable". Other numbers in the error, like token counts, aren't statuses.
+fn http_status(error: &str) -> Option<u16> {
+    let status = ["status code ", "status: "]
+        .iter()
+        .find_map(|prefix| error.split_once(prefix).map(|(_, status)| status))
+        .unwrap_or(error);
+    let digits = status.bytes().take_while(u8::is_ascii_digit).count();
+    if digits != 3 {
+        return None;
+    }
+    status[..digits].parse().ok()
+}
+
+impl BotError {
+    pub fn openai(error: impl std::fmt::Display) -> Self {
+        BotError::OpenAi(error.to_string())
+    }
+
+    // Reply to the user who made the failed request. It's also the final error once
+    // the command's retries are exhausted, so it says why the request failed.
+    pub fn user_message(&self) -> &str {
+        match self {
+            BotError::UserFacing(message) | BotError::Media(message) => message,
+            BotError::OpenAi(error) => match OpenAiFailure::of(error) {
+                OpenAiFailure::RateLimited => {
+                    "OpenAI is rate limiting the bot. Try again in a few minutes"
+                }
+                OpenAiFailure::Unavailable => "The model is unavailable right now. Try again later",
+                OpenAiFailure::Other => "Failed to summarize the chat. Try again later",
+            },
+            BotError::Telegram(_) => "Telegram request failed. Try again later",
+            BotError::Db(_) | BotError::QueueClosed => "Something went wrong. Try again later",
+        }
+    }
+
+    // User errors are expected, so they aren't logged as errors.
+    pub fn log_level(&self) -> log::Level {
+        match self {
+            BotError::UserFacing(_) => log::Level::Info,
+            BotError::Media(_) => log::Level::Warn,
+            BotError::OpenAi(_)
+            | BotError::Telegram(_)
+            | BotError::Db(_)
+            | BotError::QueueClosed => log::Level::Error,
+        }
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for BotError {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        BotError::QueueClosed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    #[test]
+    fn database_failures_are_db_errors() {
+        let missing = std::env::temp_dir().join("ohsumbot-missing-dir/bot.db");
+        let error = BotError::from(Db::new_with_file(missing.to_str().unwrap()).err().unwrap());
+        assert!(matches!(error, BotError::Db(_)));
+        assert_eq!(
+            error.user_message(),
+            "Something went wrong. Try again later"
+        );
+        assert_eq!(error.log_level(), log::Level::Error);
+    }
+
+    #[test]
+    fn user_errors_are_shown_as_is() {
+        let error = BotError::UserFacing("No messages found".to_string());
+        assert_eq!(error.user_message(), "No messages found");
+        assert_eq!(error.log_level(), log::Level::Info);
+
+        let error = BotError::openai("timeout");
+        assert_eq!(error.to_string(), "OpenAI request failed: timeout");
+        assert_eq!(
+            error.user_message(),
+            "Failed to summarize the chat. Try again later"
+        );
+    }
+
+    #[test]
+    fn exhausted_retries_are_explained() {
+        let rate_limited = BotError::openai("Rate limit exceeded");
+        assert_eq!(
+            rate_limited.user_message(),
+            "OpenAI is rate limiting the bot. Try again in a few minutes"
+        );
+        assert_eq!(
+            BotError::openai("{\"code\": \"rate_limit_exceeded\"}").user_message(),
+            rate_limited.user_message()
+        );
+
+        let unavailable = BotError::openai("http status: 503");
+        assert_eq!(
+            unavailable.user_message(),
+            "The model is unavailable right now. Try again later"
+        );
+        assert_eq!(
+            BotError::openai("The engine is currently overloaded").user_message(),
+            unavailable.user_message()
+        );
+
+        let media = BotError::Media(
+            "Failed to download media after 3 attempts, try sending it again later".to_string(),
+        );
+        assert_eq!(
+            media.user_message(),
+            "Failed to download media after 3 attempts, try sending it again later"
+        );
+
+        assert!(OpenAiFailure::of("Rate limit exceeded").is_transient());
+        assert!(OpenAiFailure::of("503 Service Unavailable").is_transient());
+        assert!(!OpenAiFailure::of("No choices in the response").is_transient());
+    }
+
+    #[test]
+    fn failures_are_classified_by_status_and_code() {
+        assert_eq!(
+            OpenAiFailure::of("https://api.openai.com/v1/chat/completions: status code 429"),
+            OpenAiFailure::RateLimited
+        );
+        assert_eq!(
+            OpenAiFailure::of("status code 500"),
+            OpenAiFailure::Unavailable
+        );
+        assert_eq!(
+            OpenAiFailure::of(r#"{"type": "server_error"}"#),
+            OpenAiFailure::Unavailable
+        );
+        // A missing model won't appear on a retry.
+        assert_eq!(
+            OpenAiFailure::of(r#"{"code": "model_not_found"}"#),
+            OpenAiFailure::Other
+        );
+        // Numbers that aren't statuses.
+        assert_eq!(
+            OpenAiFailure::of("This model's maximum context length is 4290 tokens"),
+            OpenAiFailure::Other
+        );
+        assert_eq!(
+            OpenAiFailure::of("Requested 5000 tokens, org-5002"),
+            OpenAiFailure::Other
+        );
+    }
+
+    #[test]
+    fn closed_queue_is_reported() {
+        let (sender, receiver) = tokio::sync::mpsc::channel::<i32>(1);
+        drop(receiver);
+        let error = BotError::from(futures::executor::block_on(sender.send(1)).unwrap_err());
+        assert!(matches!(error, BotError::QueueClosed));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::{BotError, Result};
+
+const DISABLED_MESSAGE: &str = "This feature is disabled";
+
+// Features operators can turn off without recompiling, with FEATURE_<NAME>=false.
+// Everything is enabled by default.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Features {
+    // Transcribed summaries of audio and video.
+    pub media_enabled: bool,
+    // /ask in groups and follow-up questions in DM.
+    pub ask_enabled: bool,
+    // /links and /multidigest.
+    pub digests_enabled: bool,
+    // Summaries of the messages that get the chat's trigger reaction.
+    pub reactions_enabled: bool,
+    // 👍/👎 buttons under summaries, the ratings are stored for the operators.
+    pub feedback_enabled: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            media_enabled: true,
+            ask_enabled: true,
+            digests_enabled: true,
+            reactions_enabled: true,
+            feedback_enabled: true,
+        }
+    }
+}
+
+impl Features {
+    pub fn from_env() -> std::result::Result<Self, envy::Error> {
+        envy::prefixed("FEATURE_").from_env()
+    }
+
+    // Commands that don't belong to any feature are always allowed.
+    pub fn check_command(&self, cmd: &str) -> Result<()> {
+        let enabled = match cmd {
+            "/ask" | "/reset" => self.ask_enabled,
+            "/links" | "/multidigest" => self.digests_enabled,
+            _ => true,
+        };
+        ensure_enabled(enabled)
+    }
+
+    pub fn check_media(&self) -> Result<()> {
+        ensure_enabled(self.media_enabled)
+    }
+}
+
+fn ensure_enabled(enabled: bool) -> Result<()> {
+    if enabled {
+        Ok(())
+    } else {
+        Err(BotError::UserFacing(DISABLED_MESSAGE.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_features_are_rejected() {
+        let features: Features = envy::prefixed("FEATURE_")
+            .from_iter([
+                ("FEATURE_ASK_ENABLED".to_string(), "false".to_string()),
+                ("FEATURE_MEDIA_ENABLED".to_string(), "false".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(
+            features,
+            Features {
+                media_enabled: false,
+                ask_enabled: false,
+                ..Default::default()
+            }
+        );
+
+        let rejected = features.check_command("/ask").unwrap_err();
+        assert_eq!(rejected.user_message(), "This feature is disabled");
+        assert!(features.check_media().is_err());
+        assert!(features.check_command("/links").is_ok());
+        assert!(features.check_command("/summarize").is_ok());
+
+        let features = Features {
+            digests_enabled: false,
+            ..Default::default()
+        };
+        assert!(features.check_command("/links").is_err());
+        assert!(features.check_command("/multidigest").is_err());
+        assert!(features.check_command("/ask").is_ok());
+        assert!(features.check_media().is_ok());
+    }
+}
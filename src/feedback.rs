@@ -0,0 +1,116 @@
+use grammers_client::{button, reply_markup};
+
+use crate::openai::api::{version_tag, Prompt};
+
+// Callback data of the rating buttons is `feedback:<summary id>:<up|down>`.
+const CALLBACK_PREFIX: &str = "feedback:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl Rating {
+    // Stored as 1 and -1, so the ratings of a setting can be summed up.
+    pub fn score(self) -> i32 {
+        match self {
+            Rating::Up => 1,
+            Rating::Down => -1,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Rating::Up => "up",
+            Rating::Down => "down",
+        }
+    }
+}
+
+// 👍/👎 buttons attached to the last part of a summary.
+pub fn buttons(summary_id: i64) -> reply_markup::Inline {
+    let button = |label: &str, rating: Rating| {
+        button::inline(label, callback_data(summary_id, rating).into_bytes())
+    };
+    reply_markup::inline(vec![vec![
+        button("👍", Rating::Up),
+        button("👎", Rating::Down),
+    ]])
+}
+
+fn callback_data(summary_id: i64, rating: Rating) -> String {
+    format!("{CALLBACK_PREFIX}{summary_id}:{}", rating.as_str())
+}
+
+// None if the callback isn't a rating.
+pub fn parse_callback(data: &[u8]) -> Option<(i64, Rating)> {
+    let data = std::str::from_utf8(data)
+        .ok()?
+        .strip_prefix(CALLBACK_PREFIX)?;
+    let (summary_id, rating) = data.split_once(':')?;
+    let rating = match rating {
+        "up" => Rating::Up,
+        "down" => Rating::Down,
+        _ => return None,
+    };
+    Some((summary_id.parse().ok()?, rating))
+}
+
+// Settings the summary was made with, stored with its ratings. The version tag is stored separately.
+pub fn summary_parameters(prompt: &Prompt, message_count: usize) -> (String, String) {
+    let parameters = format!(
+        "length={:?} messages={message_count} model={}",
+        prompt.gpt_length(),
+        prompt.model()
+    );
+    (parameters, version_tag(prompt.model()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    #[test]
+    fn rating_is_linked_to_the_summary() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let first = db
+            .add_rated_summary(1, 1, "length=Short messages=100", "gpt-4o/p1", 0)
+            .unwrap();
+        let second = db
+            .add_rated_summary(1, 1, "length=Long messages=400", "gpt-4o/p1", 0)
+            .unwrap();
+
+        let (summary_id, rating) =
+            parse_callback(callback_data(second, Rating::Down).as_bytes()).unwrap();
+        assert_eq!((summary_id, rating), (second, Rating::Down));
+        assert!(db.set_feedback(summary_id, 10, rating.score()).unwrap());
+        // A user's vote is replaced when they vote again.
+        assert!(db.set_feedback(first, 10, Rating::Down.score()).unwrap());
+        assert!(db.set_feedback(first, 10, Rating::Up.score()).unwrap());
+        assert!(db.set_feedback(first, 11, Rating::Up.score()).unwrap());
+        assert!(!db.set_feedback(42, 10, Rating::Up.score()).unwrap());
+
+        assert_eq!(
+            db.get_feedback_totals().unwrap(),
+            vec![
+                (
+                    "gpt-4o/p1".to_string(),
+                    "length=Long messages=400".to_string(),
+                    0,
+                    1
+                ),
+                (
+                    "gpt-4o/p1".to_string(),
+                    "length=Short messages=100".to_string(),
+                    2,
+                    0
+                ),
+            ]
+        );
+
+        assert_eq!(parse_callback(b"feedback:1:meh"), None);
+        assert_eq!(parse_callback(b"something:1:up"), None);
+    }
+}
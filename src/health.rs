@@ -0,0 +1,124 @@
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::db::unix_time;
+
+// Connection events of the process, shared by the reconnection policy, the update loop and /health.
+pub static HEALTH: ConnectionHealth = ConnectionHealth::new();
+
+pub struct ConnectionHealth {
+    reconnects_total: AtomicU64,
+    update_errors_total: AtomicU64,
+    // Unix time of the last disconnect, 0 if the connection never dropped.
+    last_disconnect: AtomicU64,
+}
+
+impl ConnectionHealth {
+    const fn new() -> Self {
+        Self {
+            reconnects_total: AtomicU64::new(0),
+            update_errors_total: AtomicU64::new(0),
+            last_disconnect: AtomicU64::new(0),
+        }
+    }
+
+    pub fn reconnects_total(&self) -> u64 {
+        self.reconnects_total.load(Ordering::Relaxed)
+    }
+
+    pub fn update_errors_total(&self) -> u64 {
+        self.update_errors_total.load(Ordering::Relaxed)
+    }
+
+    pub fn last_disconnect(&self) -> Option<u64> {
+        Some(self.last_disconnect.load(Ordering::Relaxed)).filter(|time| *time != 0)
+    }
+
+    // The first attempt after a drop marks the disconnect, every attempt counts as a reconnect.
+    fn record_reconnect(&self, attempt: usize, now: u64) {
+        if attempt == 0 {
+            self.last_disconnect.store(now, Ordering::Relaxed);
+        }
+        self.reconnects_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_update_error(&self, now: u64) {
+        self.last_disconnect.store(now, Ordering::Relaxed);
+        self.update_errors_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, now: u64) -> String {
+        let last_disconnect = match self.last_disconnect() {
+            Some(time) => format!("{}s ago", now.saturating_sub(time)),
+            None => "never".to_string(),
+        };
+        format!(
+            "reconnects_total: {}\nupdate_errors_total: {}\nlast_disconnect: {last_disconnect}",
+            self.reconnects_total(),
+            self.update_errors_total()
+        )
+    }
+}
+
+// Reconnects a fixed number of times with a fixed delay, recording every attempt.
+pub struct ReconnectionPolicy {
+    pub attempts: usize,
+    pub delay: Duration,
+    pub health: &'static ConnectionHealth,
+}
+
+impl grammers_mtsender::retry::RetryPolicy for ReconnectionPolicy {
+    fn should_retry(&self, attempt: usize) -> ControlFlow<(), Duration> {
+        let now = unix_time(SystemTime::now());
+        if attempt < self.attempts {
+            self.health.record_reconnect(attempt, now);
+            let since_disconnect = now.saturating_sub(self.health.last_disconnect().unwrap_or(now));
+            log::warn!(
+                "Telegram connection lost {}s ago, reconnect attempt {} of {} in {:?}",
+                since_disconnect,
+                attempt + 1,
+                self.attempts,
+                self.delay
+            );
+            ControlFlow::Continue(self.delay)
+        } else {
+            log::error!(
+                "Telegram connection lost, giving up after {} reconnect attempts",
+                self.attempts
+            );
+            ControlFlow::Break(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grammers_mtsender::retry::RetryPolicy;
+
+    #[test]
+    fn retries_increment_the_reconnect_counter() {
+        static HEALTH: ConnectionHealth = ConnectionHealth::new();
+        let policy = ReconnectionPolicy {
+            attempts: 2,
+            delay: Duration::from_secs(5),
+            health: &HEALTH,
+        };
+        assert_eq!(HEALTH.last_disconnect(), None);
+        assert!(HEALTH.report(100).ends_with("last_disconnect: never"));
+
+        assert_eq!(policy.should_retry(0), ControlFlow::Continue(policy.delay));
+        assert_eq!(policy.should_retry(1), ControlFlow::Continue(policy.delay));
+        assert_eq!(policy.should_retry(2), ControlFlow::Break(()));
+        assert_eq!(HEALTH.reconnects_total(), 2);
+        assert!(HEALTH.last_disconnect().is_some());
+
+        HEALTH.record_update_error(1_000);
+        assert_eq!(HEALTH.update_errors_total(), 1);
+        assert_eq!(
+            HEALTH.report(1_030),
+            "reconnects_total: 2\nupdate_errors_total: 1\nlast_disconnect: 30s ago"
+        );
+    }
+}
@@ -3,12 +3,20 @@ use std::sync::Arc;
 use grammers_client::{Client, Config};
 use grammers_session::Session;
 use tokio::sync::Mutex;
-use std::ops::ControlFlow;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod admins;
+mod calendar;
 pub mod consts;
+mod cooldown;
 mod db;
+mod error;
+mod features;
+mod feedback;
+mod health;
 mod openai;
+mod quiet_hours;
+mod selftest;
 mod telegram;
 
 const DB_NAME: &str = "./db/db.sqlite3";
@@ -20,31 +28,146 @@ struct BotInfo {
     tg_api_id: i32,
     tg_api_hash: String,
     bot_token: String,
+    // Text added before and after every summary. Supports {count}, {length} and {version} placeholders.
+    summary_header: Option<String>,
+    summary_footer: Option<String>,
+    // Telegram user ids allowed to run the bot-wide admin commands, comma separated.
+    #[serde(default)]
+    bot_admins: Vec<i64>,
 
     // Values required by OpenAI.
     openai_api_key: String,
+    // Verify generated summaries and retry once if they look broken.
+    #[serde(default)]
+    summary_self_check: bool,
+    // Completion token budget for /ask answers. Defaults to the answer length budget.
+    ask_max_output_tokens: Option<i32>,
+    // Estimated tokens of /ask context above which the messages are summarized first and the
+    // question is answered over the summary. Not limited if unset.
+    ask_max_context_tokens: Option<usize>,
+    // Pass message reaction counts to the model, so popular messages get more weight in summaries.
+    #[serde(default)]
+    summary_reactions: bool,
+    // Pass the captions of photos, videos and files to the model. Enabled by default.
+    summary_captions: Option<bool>,
+    // Where voice messages and videos are transcribed: "openai" (default) or "local" whisper.cpp.
+    #[serde(default)]
+    transcribe_backend: openai::transcription::TranscribeBackend,
+    // whisper.cpp binary and model used by the local backend. The model is required for it.
+    whisper_command: Option<String>,
+    whisper_model: Option<String>,
+    // Model that summarizes the transcripts of audio and video, e.g. a cheaper one. The chat model if unset.
+    media_summary_model: Option<String>,
+    // Never send audio or video to OpenAI. Media is transcribed only with the local backend.
+    #[serde(default)]
+    disable_outbound_media: bool,
+    // Mask emails, phone numbers and the words below in the responses and/or in the messages sent to OpenAI.
+    #[serde(default)]
+    redact_output: bool,
+    #[serde(default)]
+    redact_input: bool,
+    // Comma separated words to mask, case insensitive.
+    #[serde(default)]
+    redact_words: Vec<String>,
+    // Additional regex to mask.
+    redact_pattern: Option<String>,
+    // Comma separated MIME types of the media to transcribe, like `audio/*,video/mp4`. Audio and video by default.
+    media_types: Option<Vec<String>>,
+    // Minimum number of seconds between the requests of a user. Not limited if unset.
+    command_cooldown_secs: Option<u64>,
+    // Reply to mistyped commands in groups with the closest known command.
+    #[serde(default)]
+    suggest_commands: bool,
+    // Reply in groups to users the bot can't DM, `{link}` is replaced with the bot's start link. Localized by default.
+    dm_unavailable_message: Option<String>,
+    // Media downloads are cancelled after this many seconds and retried this many times.
+    media_download_timeout_secs: Option<u64>,
+    media_download_retries: Option<u32>,
+    // Automatic retries of a request across media downloads, completions and self checks. 3 by default.
+    command_retry_budget: Option<u32>,
+    // Media files processed at once, the rest wait in line. Two by default.
+    max_media_jobs: Option<usize>,
+    // Chats whose commands are processed at once. Four by default.
+    max_concurrent_commands: Option<usize>,
+    // Estimated OpenAI spending per UTC day in USD, requests over it are deferred to a later day.
+    daily_cost_limit: Option<f64>,
+    // Summarize a sample of the messages of large windows, `every_nth` or `most_engaged`. Off if unset.
+    summary_sampling: Option<openai::sampling::SamplingStrategy>,
+    // Windows with more messages than this are sampled. 300 by default.
+    summary_sample_size: Option<usize>,
+    // Request summaries as JSON with topics, participants, decisions and sentiment, and render them.
+    #[serde(default)]
+    structured_summaries: bool,
+    // Completions generated per request, the best one is sent. One by default, each one is billed.
+    openai_choices: Option<u32>,
+    // Chat model of the summaries and answers, gpt-4o if unset.
+    openai_model: Option<String>,
+    // Model of the OpenAI transcription endpoint, whisper-1 if unset.
+    openai_transcription_model: Option<String>,
+    // Show responses while they are generated by editing the message.
+    #[serde(default)]
+    stream_responses: bool,
+    // Truncate responses in DM to this many characters, the rest is sent on /more. Not truncated if unset.
+    max_response_length: Option<usize>,
+    // UTC hour to compact the database at once a day, best set to the quietest hour. Not run if unset.
+    db_maintenance_hour: Option<u64>,
+    // Delete stored data of chats the bot was removed from after this many hours. Kept forever if unset.
+    purge_removed_chats_after_hours: Option<u64>,
+    // Store the texts of the latest messages next to their ids, so summaries don't fetch them from Telegram.
+    #[serde(default)]
+    store_message_text: bool,
+    // React to /summarize while it's processed and when it's done. Needs the bot to be allowed to react.
+    #[serde(default)]
+    command_reactions: bool,
 }
 
-struct ReconnectionPolicy {
-    attempts: usize,
-    delay: std::time::Duration,
+static FIXED_RECONNECT_POLICY: health::ReconnectionPolicy =
+health::ReconnectionPolicy {
+        attempts: 5,
+        delay: std::time::Duration::from_secs(5),
+        health: &health::HEALTH,
+    };
+
+async fn purge_removed_chats(db: Arc<Mutex<db::Db>>, grace_period: Duration) {
+    loop {
+        match db.lock().await.purge_removed_chats(grace_period) {
+            Ok(chat_ids) if !chat_ids.is_empty() => {
+                log::info!("Purged data of removed chats: {:?}", chat_ids)
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("Failed to purge removed chats: {:?}", err),
+        }
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
 }
 
-impl grammers_mtsender::retry::RetryPolicy for ReconnectionPolicy {
-    fn should_retry(&self, attempt: usize) -> ControlFlow<(), Duration>  {
-        if attempt < self.attempts {
-            ControlFlow::Continue(self.delay)
-        } else {
-            ControlFlow::Break(())
+// Maintenance holds the DB lock, so messages aren't written while the file is rebuilt.
+async fn maintain_db(db: Arc<Mutex<db::Db>>, utc_hour: u64) {
+    loop {
+        tokio::time::sleep(until_utc_hour(utc_hour, SystemTime::now())).await;
+        let started = Instant::now();
+        let maintained = {
+            let db = db.lock().await;
+            db.purge_expired(db::unix_time(SystemTime::now()))
+                .and_then(|()| db.maintain())
+        };
+        match maintained {
+            Ok(()) => log::info!("Database maintenance took {:?}", started.elapsed()),
+            Err(err) => log::error!("Database maintenance failed: {:?}", err),
         }
     }
 }
 
-static FIXED_RECONNECT_POLICY: ReconnectionPolicy =
-ReconnectionPolicy {
-        attempts: 5,
-        delay: std::time::Duration::from_secs(5),
-    };
+// Time left until the start of the next given hour in UTC.
+fn until_utc_hour(utc_hour: u64, now: SystemTime) -> Duration {
+    const DAY: u64 = 24 * 60 * 60;
+    let since_midnight = now
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() % DAY)
+        .unwrap_or_default();
+    let target = utc_hour % 24 * 60 * 60;
+    Duration::from_secs((target + DAY - since_midnight - 1) % DAY + 1)
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -55,6 +178,7 @@ async fn main() -> anyhow::Result<()> {
 
     let db = Arc::new(Mutex::new(db::Db::new_with_file(DB_NAME)?));
     let env: BotInfo = envy::from_env()?;
+    let features = features::Features::from_env()?;
 
     let client = Client::connect(Config {
         session: Session::load_file_or_create(SESSION_NAME)?,
@@ -72,22 +196,112 @@ async fn main() -> anyhow::Result<()> {
         client.bot_sign_in(&env.bot_token).await?;
     }
 
-    let openai_api: openai::api::OpenAIClient = openai::api::OpenAIClient::new(env.openai_api_key);
-    let processor = openai::processor::Processor::new(client.clone(), db.clone(), openai_api);
-    let (processor_handle, processor_queue) = processor.run().await;
+    let redactor = Arc::new(openai::redaction::Redactor::new(
+        &env.redact_words,
+        env.redact_pattern.as_deref(),
+    )?);
+    let openai_api: openai::api::OpenAIClient = openai::api::OpenAIClient::new(env.openai_api_key)
+        .with_model(env.openai_model)
+        .with_transcription_model(env.openai_transcription_model)
+        .with_self_check(env.summary_self_check)
+        .with_ask_max_output_tokens(env.ask_max_output_tokens)
+        .with_ask_max_context_tokens(env.ask_max_context_tokens)
+        .with_reactions(env.summary_reactions)
+        .with_captions(env.summary_captions.unwrap_or(true))
+        .with_structured_output(env.structured_summaries)
+        .with_choices(env.openai_choices)
+        .with_input_redaction(env.redact_input.then(|| redactor.clone()));
+    let transcriber = openai::transcription::transcriber(
+        env.transcribe_backend,
+        &openai_api,
+        env.whisper_command,
+        env.whisper_model,
+    )?;
+    let default_download = openai::download::DownloadPolicy::default();
+    let media_download = openai::download::DownloadPolicy {
+        timeout: env
+            .media_download_timeout_secs
+            .map_or(default_download.timeout, Duration::from_secs),
+        retries: env
+            .media_download_retries
+            .unwrap_or(default_download.retries),
+    };
+    let processor =
+        openai::processor::Processor::new(client.clone(), db.clone(), openai_api.clone())
+            .with_streaming(env.stream_responses)
+            .with_summary_template(openai::template::SummaryTemplate {
+                header: env.summary_header,
+                footer: env.summary_footer,
+            })
+            .with_transcriber(transcriber)
+            .with_outbound_media(!env.disable_outbound_media)
+            .with_media_summary_model(env.media_summary_model)
+            .with_max_response_length(env.max_response_length)
+            .with_media_download(media_download)
+            .with_features(features)
+            .with_max_media_jobs(env.max_media_jobs.unwrap_or(consts::MAX_MEDIA_JOBS))
+            .with_max_concurrent_commands(env.max_concurrent_commands.unwrap_or(consts::MAX_CONCURRENT_COMMANDS))
+            .with_daily_cost_limit(env.daily_cost_limit)
+            .with_sampling(env.summary_sampling.map(|strategy| openai::sampling::Sampling {
+                strategy,
+                size: env.summary_sample_size.unwrap_or(consts::SUMMARY_SAMPLE_SIZE),
+            }))
+            .with_retry_budget(env.command_retry_budget.unwrap_or(consts::COMMAND_RETRY_BUDGET))
+            .with_media_types(env.media_types.map_or_else(Default::default, |types| {
+                openai::media::MediaTypes::new(&types)
+            }))
+            .with_output_redaction(env.redact_output.then_some(redactor));
+    let (shutdown, shutdown_signal) = tokio::sync::watch::channel(false);
+    let (processor_handle, processor_queue) = processor.run(shutdown_signal).await;
+    tokio::pin!(processor_handle);
+
+    let mut bot = telegram::Processor::new(client.clone(), db.clone(), processor_queue)
+        .await?
+        .with_bot_admins(env.bot_admins)
+        .with_command_cooldown(env.command_cooldown_secs.map(Duration::from_secs))
+        .with_command_suggestions(env.suggest_commands)
+        .with_dm_unavailable_message(env.dm_unavailable_message)
+        .with_message_text_storage(env.store_message_text)
+        .with_command_reactions(env.command_reactions)
+        .with_features(features)
+        .with_reconnection_policy(&FIXED_RECONNECT_POLICY);
 
-    let mut bot = telegram::Processor::new(client.clone(), db.clone(), processor_queue).await?;
+    if let Some(hours) = env.purge_removed_chats_after_hours {
+        tokio::spawn(purge_removed_chats(
+            db.clone(),
+            Duration::from_secs(hours * 60 * 60),
+        ));
+    }
+
+    if let Some(hour) = env.db_maintenance_hour {
+        tokio::spawn(maintain_db(db.clone(), hour));
+    }
 
-    tokio::select! {
+    let processor_stopped = tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             println!("Ctrl-C received, shutting down...");
+            false
         }
         r = bot.process_updates() => {
             println!("Error processing updates: {:?}", r);
+            false
         }
-        _ = processor_handle => {
+        _ = &mut processor_handle => {
             println!("Error processing commands");
+            true
         }
+    };
+
+    // New commands aren't taken anymore, the running ones are finished before exiting.
+    if !processor_stopped {
+        shutdown.send(true).ok();
+        processor_handle.await;
+    }
+
+    // The lock is held until exit, so nothing is written after the checkpoint.
+    let db = db.lock().await;
+    if let Err(err) = db.checkpoint() {
+        log::error!("Failed to checkpoint the database: {:?}", err);
     }
 
     Ok(())
@@ -0,0 +1,87 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::consts;
+
+// Recent summaries sent to each user in DM, so follow-up questions have something to refer to.
+pub struct DmContext {
+    ttl: Duration,
+    entries: HashMap<i64, VecDeque<(Instant, String)>>,
+}
+
+impl DmContext {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn remember(&mut self, user_id: i64, text: String) {
+        self.remember_at(user_id, text, Instant::now())
+    }
+
+    pub fn recall(&mut self, user_id: i64) -> Vec<String> {
+        self.recall_at(user_id, Instant::now())
+    }
+
+    fn remember_at(&mut self, user_id: i64, text: String, now: Instant) {
+        let entries = self.entries.entry(user_id).or_default();
+        entries.push_back((now, text));
+        while entries.len() > consts::DM_CONTEXT_SIZE {
+            entries.pop_front();
+        }
+    }
+
+    fn recall_at(&mut self, user_id: i64, now: Instant) -> Vec<String> {
+        let Some(entries) = self.entries.get_mut(&user_id) else {
+            return vec![];
+        };
+
+        entries.retain(|(added, _)| now.duration_since(*added) < self.ttl);
+        if entries.is_empty() {
+            self.entries.remove(&user_id);
+            return vec![];
+        }
+
+        entries.iter().map(|(_, text)| text.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_expires() {
+        let mut context = DmContext::new(Duration::from_secs(60));
+        let now = Instant::now();
+        context.remember_at(1, "old".to_string(), now);
+        context.remember_at(1, "new".to_string(), now + Duration::from_secs(30));
+
+        assert_eq!(
+            context.recall_at(1, now + Duration::from_secs(45)),
+            vec!["old", "new"]
+        );
+        assert_eq!(
+            context.recall_at(1, now + Duration::from_secs(75)),
+            vec!["new"]
+        );
+        assert!(context
+            .recall_at(1, now + Duration::from_secs(120))
+            .is_empty());
+        assert!(context.recall_at(2, now).is_empty());
+    }
+
+    #[test]
+    fn context_keeps_latest_entries() {
+        let mut context = DmContext::new(Duration::from_secs(60));
+        for i in 0..consts::DM_CONTEXT_SIZE + 2 {
+            context.remember(1, i.to_string());
+        }
+
+        let recalled = context.recall(1);
+        assert_eq!(recalled.len(), consts::DM_CONTEXT_SIZE);
+        assert_eq!(recalled[0], "2");
+    }
+}
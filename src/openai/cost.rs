@@ -0,0 +1,167 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::db::Db;
+use crate::openai::api::Prompt;
+
+// USD per million input and output tokens, matched by prefix like the context windows.
+// More specific names go first.
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4", 30.0, 60.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+];
+// Assumed for models missing above, the price of the default model.
+const DEFAULT_PRICE: (f64, f64) = (2.5, 10.0);
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// What the prompts would cost if the model used the whole output budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CostEstimate {
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    pub usd: f64,
+}
+
+impl CostEstimate {
+    pub fn of<'a>(prompts: impl IntoIterator<Item = &'a Prompt>) -> Self {
+        prompts
+            .into_iter()
+            .fold(CostEstimate::default(), |estimate, prompt| {
+                let (input_tokens, output_tokens) = prompt.estimated_tokens();
+                let (input_price, output_price) = price(prompt.model());
+                CostEstimate {
+                    input_tokens: estimate.input_tokens + input_tokens,
+                    output_tokens: estimate.output_tokens + output_tokens,
+                    usd: estimate.usd
+                        + (input_tokens as f64 * input_price + output_tokens as f64 * output_price)
+                            / 1_000_000.0,
+                }
+            })
+    }
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "about {} tokens, up to ${:.2}",
+            self.input_tokens + self.output_tokens,
+            self.usd
+        )
+    }
+}
+
+fn price(model: &str) -> (f64, f64) {
+    PRICES
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))
+        .map_or(DEFAULT_PRICE, |(_, input, output)| (*input, *output))
+}
+
+// Limit of the estimated spending per UTC day. The spending is kept in the database, so it
+// survives restarts. Requests that don't fit into today's limit are deferred to a later day.
+#[derive(Clone, Copy)]
+pub struct CostBudget {
+    limit: Option<f64>,
+}
+
+impl CostBudget {
+    pub fn new(limit: Option<f64>) -> Self {
+        Self { limit }
+    }
+
+    pub fn limit(&self) -> Option<f64> {
+        self.limit
+    }
+
+    // Number of days after today until the first day the cost fits into. None if it's over the
+    // limit of a whole day.
+    pub fn fits_in(&self, db: &Db, usd: f64, now: u64) -> rusqlite::Result<Option<u64>> {
+        let today = now / SECONDS_PER_DAY;
+        let Some(limit) = self.limit else {
+            return Ok(Some(0));
+        };
+        if usd > limit {
+            return Ok(None);
+        }
+        let mut day = today;
+        while db.get_daily_cost(day)? + usd > limit {
+            day += 1;
+        }
+        Ok(Some(day - today))
+    }
+
+    // Adds the cost to the spending of the first day it fits into, see `fits_in`.
+    pub fn spend(&self, db: &Db, usd: f64, now: u64) -> rusqlite::Result<Option<u64>> {
+        let days = self.fits_in(db, usd, now)?;
+        if let Some(days) = days {
+            db.add_daily_cost(now / SECONDS_PER_DAY + days, usd)?;
+        }
+        Ok(days)
+    }
+}
+
+// Start of the UTC day `days` after the day of `now`.
+pub fn day_start(now: u64, days: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs((now / SECONDS_PER_DAY + days) * SECONDS_PER_DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::api::{GPTLenght, OpenAIClient};
+
+    #[test]
+    fn estimate_is_close_and_budget_is_enforced() {
+        // 1000 messages of about 100 bytes, roughly 18k tokens.
+        let text = (0..1000)
+            .map(|i| {
+                format!(
+                    "User{i}: {}",
+                    "we should ship the release on monday ".repeat(2)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let prompts =
+            OpenAIClient::new(String::new()).prepare_text_summary(&text, GPTLenght::Long, None);
+        let estimate = CostEstimate::of(&prompts);
+        assert!(
+            (15_000..22_000).contains(&estimate.input_tokens),
+            "{estimate:?}"
+        );
+        assert!(estimate.output_tokens >= 1024, "{estimate:?}");
+        assert!(estimate.usd > 0.05 && estimate.usd < 0.1, "{estimate:?}");
+
+        let db = Db::new_with_file(":memory:").unwrap();
+        let now = 10 * SECONDS_PER_DAY + 60;
+        let budget = CostBudget::new(Some(0.2));
+        assert_eq!(budget.spend(&db, estimate.usd, now).unwrap(), Some(0));
+        assert_eq!(budget.spend(&db, estimate.usd, now).unwrap(), Some(0));
+        // Deferred to tomorrow.
+        assert_eq!(budget.spend(&db, 0.1, now).unwrap(), Some(1));
+        // A small request still fits.
+        assert_eq!(budget.spend(&db, 0.01, now).unwrap(), Some(0));
+        // Never fits.
+        assert_eq!(budget.spend(&db, 0.3, now).unwrap(), None);
+        assert_eq!(budget.fits_in(&db, 0.1, now).unwrap(), Some(1));
+
+        // The spending is kept across restarts, tomorrow is filled up next.
+        let budget = CostBudget::new(Some(0.2));
+        assert_eq!(budget.spend(&db, 0.1, now).unwrap(), Some(1));
+        assert_eq!(budget.spend(&db, 0.1, now).unwrap(), Some(2));
+        // Tomorrow's requests see the deferred ones.
+        let tomorrow = 11 * SECONDS_PER_DAY + 60;
+        assert_eq!(budget.spend(&db, 0.1, tomorrow).unwrap(), Some(1));
+        assert_eq!(
+            day_start(now, 1),
+            UNIX_EPOCH + Duration::from_secs(11 * SECONDS_PER_DAY)
+        );
+
+        let unlimited = CostBudget::new(None);
+        assert_eq!(unlimited.spend(&db, 1_000.0, now).unwrap(), Some(0));
+    }
+}
@@ -0,0 +1,129 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::consts;
+use crate::error::{BotError, Result};
+use crate::openai::retry::RetryBudget;
+
+// How long a media download may take and how many times it's retried after a failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DownloadPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Default for DownloadPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: consts::MEDIA_DOWNLOAD_TIMEOUT,
+            retries: consts::MEDIA_DOWNLOAD_RETRIES,
+        }
+    }
+}
+
+// Runs the download to `path` until it succeeds or the retries of the policy or the request's
+// budget are exhausted. `download` returns false if there was nothing to download, which isn't retried.
+// A partially written file is removed before the next attempt, so it's never mistaken for the media.
+pub async fn download_with_retries<F, Fut>(
+    path: &str,
+    policy: DownloadPolicy,
+    retries: &RetryBudget,
+    mut download: F,
+) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let attempts = policy.retries + 1;
+    let mut attempt = 1;
+    loop {
+        let error = match tokio::time::timeout(policy.timeout, download()).await {
+            Ok(Ok(true)) => return Ok(()),
+            Ok(Ok(false)) => return Err(BotError::Media("Failed to download media".to_string())),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("timed out after {} s", policy.timeout.as_secs()),
+        };
+        log::warn!("Media download attempt {attempt} of {attempts} failed: {error}");
+        remove_partial(path).await;
+        if attempt == attempts || !retries.try_spend() {
+            break;
+        }
+        attempt += 1;
+    }
+
+    Err(BotError::Media(format!(
+        "Failed to download media after {attempt} attempts, try sending it again later"
+    )))
+}
+
+async fn remove_partial(path: &str) {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            log::warn!("Failed to remove partial download {path}: {e}")
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_download_is_retried() {
+        let path =
+            std::env::temp_dir().join(format!("ohsumbot-download-{}.ogg", std::process::id()));
+        let path = path.to_str().unwrap();
+        let policy = DownloadPolicy {
+            timeout: Duration::from_secs(5),
+            retries: 2,
+        };
+
+        let mut partial_seen = vec![];
+        let mut attempts = 0;
+        let result = download_with_retries(path, policy, &RetryBudget::default(), || {
+            attempts += 1;
+            partial_seen.push(std::path::Path::new(path).exists());
+            let attempt = attempts;
+            async move {
+                if attempt == 1 {
+                    tokio::fs::write(path, b"part").await.unwrap();
+                    return Err(BotError::Media("connection reset".to_string()));
+                }
+                tokio::fs::write(path, b"whole file").await.unwrap();
+                Ok(true)
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+        // The partial file of the first attempt was removed before the second one.
+        assert_eq!(partial_seen, vec![false, false]);
+        assert_eq!(tokio::fs::read(path).await.unwrap(), b"whole file");
+        tokio::fs::remove_file(path).await.unwrap();
+
+        // A download that hangs is given up on after every attempt times out.
+        let policy = DownloadPolicy {
+            timeout: Duration::from_millis(10),
+            ..policy
+        };
+        let mut attempts = 0;
+        let result = download_with_retries(path, policy, &RetryBudget::default(), || {
+            attempts += 1;
+            std::future::pending::<Result<bool>>()
+        })
+        .await;
+        assert_eq!(attempts, 3);
+        assert!(matches!(result, Err(BotError::Media(message)) if message.contains("3 attempts")));
+
+        // The request had only one retry left.
+        let mut attempts = 0;
+        let result = download_with_retries(path, policy, &RetryBudget::new(1), || {
+            attempts += 1;
+            std::future::pending::<Result<bool>>()
+        })
+        .await;
+        assert_eq!(attempts, 2);
+        assert!(matches!(result, Err(BotError::Media(message)) if message.contains("2 attempts")));
+    }
+}
@@ -0,0 +1,94 @@
+use mime::Mime;
+
+use crate::error::{BotError, Result};
+
+// Enabled when MEDIA_TYPES isn't set.
+const DEFAULT_MEDIA_TYPES: &[&str] = &["audio/*", "video/*"];
+
+// How accepted media is turned into text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaRoute {
+    // Transcribed, converted with ffmpeg first if the transcriber can't read it.
+    Audio,
+    // The audio track is extracted with ffmpeg and transcribed.
+    Video,
+}
+
+// MIME types the bot processes, like `audio/*` or `video/mp4`. Set with MEDIA_TYPES.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaTypes {
+    patterns: Vec<String>,
+}
+
+impl Default for MediaTypes {
+    fn default() -> Self {
+        Self {
+            patterns: DEFAULT_MEDIA_TYPES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
+}
+
+impl MediaTypes {
+    pub fn new(patterns: &[String]) -> Self {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| pattern.trim().to_lowercase())
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+        Self { patterns }
+    }
+
+    // Picks the processing of the media, or tells the user which type isn't supported.
+    pub fn route(&self, mime_type: &str) -> Result<MediaRoute> {
+        let unsupported = || BotError::UserFacing(format!("Unsupported media type: {mime_type}"));
+        let mime: Mime = mime_type.parse().map_err(|_| unsupported())?;
+        let route = match mime.type_() {
+            mime::AUDIO => MediaRoute::Audio,
+            mime::VIDEO => MediaRoute::Video,
+            _ => return Err(unsupported()),
+        };
+        if !self.is_enabled(&mime) {
+            return Err(unsupported());
+        }
+        Ok(route)
+    }
+
+    fn is_enabled(&self, mime: &Mime) -> bool {
+        let wildcard = format!("{}/*", mime.type_());
+        self.patterns
+            .iter()
+            .any(|pattern| *pattern == wildcard || pattern == mime.essence_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_types_are_rejected() {
+        let default = MediaTypes::default();
+        assert_eq!(default.route("audio/ogg").unwrap(), MediaRoute::Audio);
+        assert_eq!(default.route("video/mp4").unwrap(), MediaRoute::Video);
+
+        let audio_only = MediaTypes::new(&["audio/*".to_string(), " Video/MP4 ".to_string()]);
+        assert_eq!(audio_only.route("audio/mpeg").unwrap(), MediaRoute::Audio);
+        assert_eq!(audio_only.route("video/mp4").unwrap(), MediaRoute::Video);
+        let rejected = audio_only.route("video/quicktime").unwrap_err();
+        assert_eq!(
+            rejected.user_message(),
+            "Unsupported media type: video/quicktime"
+        );
+
+        // Types without a route can't be enabled.
+        let images = MediaTypes::new(&["image/*".to_string()]);
+        assert_eq!(
+            images.route("image/png").unwrap_err().user_message(),
+            "Unsupported media type: image/png"
+        );
+        assert!(default.route("not a mime").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+use crate::error::{BotError, Result};
+
+// Runs media jobs (download, ffmpeg and transcription) in the background, separately from
+// the command queue, so text commands don't wait for them. At most `concurrency` jobs run at once,
+// so a burst of forwarded videos can't exhaust the host. The rest wait in line, and new jobs are
+// rejected once `capacity` of them are waiting.
+#[derive(Clone)]
+pub struct MediaJobs {
+    permits: Arc<Semaphore>,
+    concurrency: usize,
+    capacity: usize,
+    // Running and waiting jobs.
+    jobs: Arc<AtomicUsize>,
+    // Kept to stop the jobs on shutdown. Finished ones are dropped on the next spawn.
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl MediaJobs {
+    pub fn new(concurrency: usize, capacity: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
+            capacity,
+            jobs: Arc::new(AtomicUsize::new(0)),
+            handles: Arc::default(),
+        }
+    }
+
+    // Starts the job once a slot is free. Returns the place of the job in line,
+    // or None if it started right away. Waiting jobs are started in the order they were spawned.
+    pub fn spawn(&self, job: impl Future<Output = ()> + Send + 'static) -> Result<Option<usize>> {
+        let limit = self.concurrency + self.capacity;
+        let jobs_ahead = self
+            .jobs
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |jobs| {
+                (jobs < limit).then_some(jobs + 1)
+            })
+            .map_err(|_| {
+                BotError::UserFacing(
+                    "Too many media files are being processed, please try again later.".to_string(),
+                )
+            })?;
+
+        let permits = self.permits.clone();
+        let jobs = self.jobs.clone();
+        let handle = tokio::spawn(async move {
+            // The semaphore is never closed.
+            let _permit = permits.acquire_owned().await;
+            job.await;
+            jobs.fetch_sub(1, Ordering::SeqCst);
+        });
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle);
+        Ok((jobs_ahead >= self.concurrency).then(|| jobs_ahead - self.concurrency + 1))
+    }
+
+    // Aborts the running and waiting jobs and waits until they are gone, so their files can be
+    // removed. Returns the number of stopped jobs.
+    pub async fn stop(&self) -> usize {
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        let mut stopped = 0;
+        for handle in handles {
+            if handle.is_finished() {
+                continue;
+            }
+            handle.abort();
+            // Cancelled, or finished meanwhile.
+            let _ = handle.await;
+            stopped += 1;
+        }
+        stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn media_jobs_respect_the_concurrency_cap() {
+        let media_jobs = MediaJobs::new(2, 2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        // Jobs are blocked until a permit is added for each of them.
+        let gate = Arc::new(Semaphore::new(0));
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let job = || {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            let gate = gate.clone();
+            let done_tx = done_tx.clone();
+            async move {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                gate.acquire().await.unwrap().forget();
+                running.fetch_sub(1, Ordering::SeqCst);
+                done_tx.send(()).unwrap();
+            }
+        };
+
+        let places = (0..4)
+            .map(|_| media_jobs.spawn(job()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(places, vec![None, None, Some(1), Some(2)]);
+        assert!(matches!(
+            media_jobs.spawn(job()),
+            Err(BotError::UserFacing(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(running.load(Ordering::SeqCst), 2);
+
+        // Text commands don't wait for the media.
+        let text_command = tokio::spawn(async { "summary" });
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), text_command)
+                .await
+                .unwrap()
+                .unwrap(),
+            "summary"
+        );
+        assert_eq!(running.load(Ordering::SeqCst), 2);
+
+        gate.add_permits(4);
+        for _ in 0..4 {
+            tokio::time::timeout(Duration::from_secs(1), done_rx.recv())
+                .await
+                .unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+
+        // The finished jobs freed their slots.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(media_jobs.spawn(async {}).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn stopped_jobs_dont_finish() {
+        let media_jobs = MediaJobs::new(1, 2);
+        let finished = Arc::new(AtomicUsize::new(0));
+        let gate = Arc::new(Semaphore::new(0));
+        media_jobs.spawn(async {}).unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        for _ in 0..2 {
+            let finished = finished.clone();
+            let gate = gate.clone();
+            media_jobs
+                .spawn(async move {
+                    gate.acquire().await.unwrap().forget();
+                    finished.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+        }
+
+        assert_eq!(media_jobs.stop().await, 2);
+        gate.add_permits(2);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(finished.load(Ordering::SeqCst), 0);
+        assert_eq!(media_jobs.stop().await, 0);
+    }
+}
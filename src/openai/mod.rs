@@ -1,2 +1,23 @@
 pub mod api;
+pub mod context;
+pub mod cost;
+pub mod download;
+pub mod media;
+pub mod media_jobs;
+pub mod pacing;
+pub mod partials;
+pub mod pending;
 pub mod processor;
+pub mod prompts;
+pub mod queue;
+pub mod redaction;
+pub mod remainder;
+pub mod requests;
+pub mod retry;
+pub mod sampling;
+pub mod sentences;
+pub mod streaming;
+pub mod structured;
+pub mod template;
+pub mod tokens;
+pub mod transcription;
@@ -0,0 +1,162 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::consts;
+
+// Below this many remaining requests, the calls are spread over the time left until the quota resets.
+const LOW_REMAINING_REQUESTS: u32 = 10;
+
+// Request quota reported by OpenAI in the `x-ratelimit-*` response headers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub remaining: u32,
+    pub reset: Duration,
+}
+
+impl RateLimit {
+    // None if the response doesn't have the headers.
+    pub fn from_headers<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Option<Self> {
+        Some(Self {
+            remaining: header("x-ratelimit-remaining-requests")?
+                .trim()
+                .parse()
+                .ok()?,
+            reset: parse_reset(header("x-ratelimit-reset-requests")?)?,
+        })
+    }
+}
+
+// Paces the OpenAI calls of the client and all its clones, so the bot slows down before it hits 429.
+// Responses without the rate limit headers don't pace anything, then the bot only backs off after a 429.
+#[derive(Debug, Default)]
+pub struct Pacer {
+    next_call: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    // Blocks until the next call is allowed. The OpenAI calls are blocking, so is the wait.
+    pub fn wait(&self) {
+        let delay = self.delay(Instant::now());
+        if !delay.is_zero() {
+            log::info!("Pacing OpenAI requests: waiting {} ms", delay.as_millis());
+            std::thread::sleep(delay);
+        }
+    }
+
+    pub fn delay(&self, now: Instant) -> Duration {
+        self.next_call
+            .lock()
+            .unwrap()
+            .map_or(Duration::ZERO, |next_call| {
+                next_call.saturating_duration_since(now)
+            })
+    }
+
+    // Spreads the remaining requests evenly until the quota resets.
+    pub fn observe(&self, limit: RateLimit, now: Instant) {
+        if limit.remaining >= LOW_REMAINING_REQUESTS {
+            return;
+        }
+        self.postpone(now + limit.reset / (limit.remaining + 1));
+    }
+
+    // The request was rejected with 429. Waits as long as the server asks, or a fixed backoff.
+    pub fn rate_limited(&self, retry_after: Option<Duration>, now: Instant) {
+        log::warn!("OpenAI rate limit hit");
+        self.postpone(now + retry_after.unwrap_or(consts::RATE_LIMIT_BACKOFF));
+    }
+
+    fn postpone(&self, until: Instant) {
+        let mut next_call = self.next_call.lock().unwrap();
+        *next_call = Some(next_call.map_or(until, |next_call| next_call.max(until)));
+    }
+}
+
+// OpenAI formats the reset time like `1s`, `6m0s` or `20ms`.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut reset = Duration::ZERO;
+    while !rest.is_empty() {
+        let (number, tail) = rest.split_at(rest.find(|c: char| !c.is_ascii_digit() && c != '.')?);
+        let (unit, tail) = tail.split_at(
+            tail.find(|c: char| c.is_ascii_digit())
+                .unwrap_or(tail.len()),
+        );
+        let number: f64 = number.parse().ok()?;
+        let seconds = match unit {
+            "ms" => number / 1000.0,
+            "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 60.0 * 60.0,
+            _ => return None,
+        };
+        reset += Duration::try_from_secs_f64(seconds).ok()?;
+        rest = tail;
+    }
+    Some(reset)
+}
+
+// Seconds to wait from the `retry-after` header of a 429 response.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_remaining_quota_slows_down_the_calls() {
+        let headers = |remaining: &'static str, reset: &'static str| {
+            move |name: &str| match name {
+                "x-ratelimit-remaining-requests" => Some(remaining),
+                "x-ratelimit-reset-requests" => Some(reset),
+                _ => None,
+            }
+        };
+        let now = Instant::now();
+
+        let pacer = Pacer::default();
+        let plenty = RateLimit::from_headers(headers("499", "120ms")).unwrap();
+        pacer.observe(plenty, now);
+        assert_eq!(pacer.delay(now), Duration::ZERO);
+
+        let low = RateLimit::from_headers(headers("3", "6m0s")).unwrap();
+        assert_eq!(
+            low,
+            RateLimit {
+                remaining: 3,
+                reset: Duration::from_secs(360)
+            }
+        );
+        pacer.observe(low, now);
+        assert_eq!(pacer.delay(now), Duration::from_secs(90));
+        assert_eq!(
+            pacer.delay(now + Duration::from_secs(60)),
+            Duration::from_secs(30)
+        );
+
+        // The last requests of the window are spaced further apart.
+        let pacer = Pacer::default();
+        pacer.observe(RateLimit::from_headers(headers("0", "1.5s")).unwrap(), now);
+        assert_eq!(pacer.delay(now), Duration::from_millis(1500));
+
+        assert_eq!(RateLimit::from_headers(|_| None), None);
+        assert_eq!(RateLimit::from_headers(headers("3", "soon")), None);
+    }
+
+    #[test]
+    fn rate_limit_without_headers_backs_off() {
+        let now = Instant::now();
+        let pacer = Pacer::default();
+        pacer.rate_limited(None, now);
+        assert_eq!(pacer.delay(now), consts::RATE_LIMIT_BACKOFF);
+
+        let pacer = Pacer::default();
+        pacer.rate_limited(parse_retry_after("7"), now);
+        assert_eq!(pacer.delay(now), Duration::from_secs(7));
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::consts;
+
+// Summaries of the chunks of long summaries, keyed by `Prompt::content_hash`. Chunks are cut
+// starting from the newest message, so overlapping requests like /summarize 500 followed by
+// /summarize 600 share most of them and only the new chunks are sent to the model.
+pub struct PartialSummaries {
+    ttl: Duration,
+    entries: HashMap<u64, (Instant, String)>,
+}
+
+impl PartialSummaries {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: u64) -> Option<String> {
+        self.get_at(key, Instant::now())
+    }
+
+    pub fn insert(&mut self, key: u64, summary: String) {
+        self.insert_at(key, summary, Instant::now())
+    }
+
+    fn get_at(&self, key: u64, now: Instant) -> Option<String> {
+        self.entries
+            .get(&key)
+            .filter(|(added, _)| now.duration_since(*added) < self.ttl)
+            .map(|(_, summary)| summary.clone())
+    }
+
+    // Expired partials are dropped first, then the oldest ones if there are still too many.
+    fn insert_at(&mut self, key: u64, summary: String, now: Instant) {
+        self.entries
+            .retain(|_, (added, _)| now.duration_since(*added) < self.ttl);
+        while self.entries.len() >= consts::PARTIAL_SUMMARIES_SIZE {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (added, _))| *added)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(key, (now, summary));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partials_expire() {
+        let mut partials = PartialSummaries::new(Duration::from_secs(60));
+        let now = Instant::now();
+        partials.insert_at(1, "Release on Monday".to_string(), now);
+
+        assert_eq!(
+            partials.get_at(1, now + Duration::from_secs(30)).as_deref(),
+            Some("Release on Monday")
+        );
+        assert_eq!(partials.get_at(2, now), None);
+        assert_eq!(partials.get_at(1, now + Duration::from_secs(60)), None);
+
+        for key in 0..consts::PARTIAL_SUMMARIES_SIZE as u64 + 10 {
+            partials.insert_at(key + 10, key.to_string(), now + Duration::from_millis(key));
+        }
+        assert_eq!(partials.entries.len(), consts::PARTIAL_SUMMARIES_SIZE);
+        // The oldest ones were dropped.
+        assert_eq!(partials.get_at(10, now + Duration::from_secs(1)), None);
+    }
+}
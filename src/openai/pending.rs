@@ -0,0 +1,269 @@
+use grammers_client::types::Chat;
+use grammers_client::Client;
+use grammers_session::PackedChat;
+
+use crate::openai::api::{GPTLenght, SummaryOptions, SummarySource};
+use crate::openai::processor::Command;
+
+// A user request kept in the database until it's done, so it's processed again after a restart.
+// Chats are stored packed, like the known chats.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub enum PendingCommand {
+    Summarize {
+        chat: Vec<u8>,
+        recipient: Vec<u8>,
+        message_count: u32,
+        gpt_length: GPTLenght,
+        mentione_by_user: Option<String>,
+        options: SummaryOptions,
+    },
+    SummarizeMessage {
+        chat: Vec<u8>,
+        recipient: Vec<u8>,
+        message_id: i32,
+        gpt_length: GPTLenght,
+    },
+    LinkDigest {
+        chat: Vec<u8>,
+        recipient: Vec<u8>,
+        message_count: u32,
+    },
+    Ask {
+        chat: Vec<u8>,
+        recipient: Vec<u8>,
+        question: String,
+        message_count: u32,
+        gpt_length: GPTLenght,
+    },
+    Elaborate {
+        chat: Vec<u8>,
+        recipient: Vec<u8>,
+        summary_message_id: i32,
+        instruction: String,
+        source: SummarySource,
+    },
+    MultiDigest {
+        chats: Vec<Vec<u8>>,
+        recipient: Vec<u8>,
+        message_count: u32,
+        gpt_length: GPTLenght,
+    },
+}
+
+impl PendingCommand {
+    // Only requests are kept. Their prompts are made again, and the other commands depend on
+    // the state in memory that is lost on restart anyway.
+    pub fn of(command: &Command) -> Option<Self> {
+        let pending = match command {
+            Command::Summarize {
+                chat,
+                recipient,
+                message_count,
+                gpt_length,
+                mentione_by_user,
+                options,
+            } => PendingCommand::Summarize {
+                chat: pack(chat),
+                recipient: pack(recipient),
+                message_count: *message_count,
+                gpt_length: *gpt_length,
+                mentione_by_user: mentione_by_user.clone(),
+                options: options.clone(),
+            },
+            Command::SummarizeMessage {
+                chat,
+                recipient,
+                message_id,
+                gpt_length,
+            } => PendingCommand::SummarizeMessage {
+                chat: pack(chat),
+                recipient: pack(recipient),
+                message_id: *message_id,
+                gpt_length: *gpt_length,
+            },
+            Command::LinkDigest {
+                chat,
+                recipient,
+                message_count,
+            } => PendingCommand::LinkDigest {
+                chat: pack(chat),
+                recipient: pack(recipient),
+                message_count: *message_count,
+            },
+            Command::Ask {
+                chat,
+                recipient,
+                question,
+                message_count,
+                gpt_length,
+            } => PendingCommand::Ask {
+                chat: pack(chat),
+                recipient: pack(recipient),
+                question: question.clone(),
+                message_count: *message_count,
+                gpt_length: *gpt_length,
+            },
+            Command::Elaborate {
+                chat,
+                recipient,
+                summary_message_id,
+                instruction,
+                source,
+            } => PendingCommand::Elaborate {
+                chat: pack(chat),
+                recipient: pack(recipient),
+                summary_message_id: *summary_message_id,
+                instruction: instruction.clone(),
+                source: *source,
+            },
+            Command::MultiDigest {
+                chats,
+                recipient,
+                message_count,
+                gpt_length,
+            } => PendingCommand::MultiDigest {
+                chats: chats.iter().map(pack).collect(),
+                recipient: pack(recipient),
+                message_count: *message_count,
+                gpt_length: *gpt_length,
+            },
+            _ => return None,
+        };
+        Some(pending)
+    }
+
+    // Fails if a chat can't be reached anymore.
+    pub async fn unpack(self, client: &Client) -> Result<Command, String> {
+        let command = match self {
+            PendingCommand::Summarize {
+                chat,
+                recipient,
+                message_count,
+                gpt_length,
+                mentione_by_user,
+                options,
+            } => Command::Summarize {
+                chat: unpack(client, &chat).await?,
+                recipient: unpack(client, &recipient).await?,
+                message_count,
+                gpt_length,
+                mentione_by_user,
+                options,
+            },
+            PendingCommand::SummarizeMessage {
+                chat,
+                recipient,
+                message_id,
+                gpt_length,
+            } => Command::SummarizeMessage {
+                chat: unpack(client, &chat).await?,
+                recipient: unpack(client, &recipient).await?,
+                message_id,
+                gpt_length,
+            },
+            PendingCommand::LinkDigest {
+                chat,
+                recipient,
+                message_count,
+            } => Command::LinkDigest {
+                chat: unpack(client, &chat).await?,
+                recipient: unpack(client, &recipient).await?,
+                message_count,
+            },
+            PendingCommand::Ask {
+                chat,
+                recipient,
+                question,
+                message_count,
+                gpt_length,
+            } => Command::Ask {
+                chat: unpack(client, &chat).await?,
+                recipient: unpack(client, &recipient).await?,
+                question,
+                message_count,
+                gpt_length,
+            },
+            PendingCommand::Elaborate {
+                chat,
+                recipient,
+                summary_message_id,
+                instruction,
+                source,
+            } => Command::Elaborate {
+                chat: unpack(client, &chat).await?,
+                recipient: unpack(client, &recipient).await?,
+                summary_message_id,
+                instruction,
+                source,
+            },
+            PendingCommand::MultiDigest {
+                chats,
+                recipient,
+                message_count,
+                gpt_length,
+            } => {
+                let mut unpacked = Vec::with_capacity(chats.len());
+                for chat in &chats {
+                    unpacked.push(unpack(client, chat).await?);
+                }
+                Command::MultiDigest {
+                    chats: unpacked,
+                    recipient: unpack(client, &recipient).await?,
+                    message_count,
+                    gpt_length,
+                }
+            }
+        };
+        Ok(command)
+    }
+}
+
+pub fn pack(chat: &Chat) -> Vec<u8> {
+    chat.pack().to_bytes()
+}
+
+pub async fn unpack(client: &Client, packed: &[u8]) -> Result<Chat, String> {
+    let packed =
+        PackedChat::from_bytes(packed).map_err(|_| "the stored chat is malformed".to_string())?;
+    client
+        .unpack_chat(packed)
+        .await
+        .map_err(|e| format!("the chat can't be reached: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_commands_round_trip() {
+        let commands = vec![
+            PendingCommand::Summarize {
+                chat: vec![1, 2, 3],
+                recipient: vec![4, 5, 6],
+                message_count: 100,
+                gpt_length: GPTLenght::Long,
+                mentione_by_user: Some("alice".to_string()),
+                options: SummaryOptions {
+                    topic: Some("release".to_string()),
+                    within: Some(std::time::Duration::from_secs(3600)),
+                    template: Some(crate::openai::api::SummaryKind::Mood),
+                    ..Default::default()
+                },
+            },
+            PendingCommand::MultiDigest {
+                chats: vec![vec![1], vec![2]],
+                recipient: vec![3],
+                message_count: 50,
+                gpt_length: GPTLenght::Short,
+            },
+        ];
+        for command in commands {
+            let json = serde_json::to_string(&command).unwrap();
+            assert_eq!(
+                serde_json::from_str::<PendingCommand>(&json).unwrap(),
+                command
+            );
+        }
+    }
+}
@@ -1,22 +1,96 @@
+use std::collections::HashSet;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use futures::future::join;
 use grammers_client::types::{Chat, Media, Message};
-use grammers_client::Client;
+use grammers_client::{Client, InputMessage};
+use grammers_tl_types as tl;
 use mime::Mime;
 use tokio::sync::{Mutex, RwLock};
 
 use crate::consts;
-use crate::db::Db;
+use crate::db::{unix_time, ChatConfig, Db, PendingAck, StoredMessage};
+use crate::error::{BotError, Result};
+use crate::features::Features;
+use crate::feedback;
 use crate::openai::api::OpenAIClient;
+use crate::openai::context::DmContext;
+use crate::openai::cost::{self, CostBudget, CostEstimate};
+use crate::openai::download::{download_with_retries, DownloadPolicy};
+use crate::openai::media::{MediaRoute, MediaTypes};
+use crate::openai::media_jobs::MediaJobs;
+use crate::openai::partials::PartialSummaries;
+use crate::openai::pending::{self, PendingCommand};
+use crate::openai::queue::{run_workers, CommandQueue, Priority, Queued};
+use crate::openai::redaction::Redactor;
+use crate::openai::remainder::Remainders;
+use crate::openai::requests::Requests;
+use crate::openai::retry::{FailedCommands, RetryBudget};
+use crate::openai::sampling::Sampling;
+use crate::openai::streaming::{split_message, StreamingMessage};
+use crate::openai::template::SummaryTemplate;
+use crate::openai::transcription::{
+    chunk_file, chunk_length, chunk_starts, combine_transcripts, join_transcripts, trim_overlaps,
+    Transcriber,
+};
+use crate::selftest;
 
-pub use super::api::GPTLenght;
-use super::api::Prompt;
+use super::api::{AskThread, Prompt, PromptKind, QuestionContext, SummarySource};
+pub use super::api::{GPTLenght, SummaryOptions};
 
 pub struct Processor {
     client: Client,
     db: Arc<Mutex<Db>>,
     openai: OpenAIClient,
+    dm_context: Mutex<DmContext>,
+    stream_responses: bool,
+    summary_template: SummaryTemplate,
+    // Responses in DM longer than this are truncated, the rest is kept for /more.
+    max_response_length: Option<usize>,
+    remainders: Mutex<Remainders>,
+    // Masks PII and configured words in the responses before they are sent.
+    output_redactor: Option<Arc<Redactor>>,
+    tracker: Tracker,
+    // Automatic retries of every user request.
+    retry_budget: u32,
+    partials: Mutex<PartialSummaries>,
+    media: MediaWorker,
+    media_jobs: MediaJobs,
+    features: Features,
+    queue: Arc<RwLock<CommandQueue<Tracked>>>,
+    // Commands of different chats processed at once.
+    max_concurrent_commands: usize,
+    cost_budget: CostBudget,
+    // Large windows are summarized from a sample of their messages if set.
+    sampling: Option<Sampling>,
+}
+
+// Turns media into summary prompts. Cloned into the background media jobs.
+#[derive(Clone)]
+struct MediaWorker {
+    // Transcripts are cached here.
+    db: Arc<Mutex<Db>>,
+    openai: OpenAIClient,
+    transcriber: Arc<dyn Transcriber>,
+    // Media isn't transcribed by a third party if disabled, only by a local transcriber.
+    outbound_media: bool,
+    media_download: DownloadPolicy,
+    // Model the transcripts are summarized with. The default model if unset.
+    summary_model: Option<String>,
+    media_types: MediaTypes,
+}
+
+// Follows the requests through their commands and media jobs. Failures are reported to the user
+// and kept for /retry and /deadletters. Once the last command of a request is done, its stored
+// copy is dropped and its command message gets the outcome reaction. Cloned into the media jobs.
+#[derive(Clone)]
+struct Tracker {
+    client: Client,
+    db: Arc<Mutex<Db>>,
+    requests: Arc<Mutex<Requests<Request>>>,
+    failed_commands: Arc<Mutex<FailedCommands<Command>>>,
 }
 
 #[derive(Clone)]
@@ -27,6 +101,7 @@ pub enum Command {
         message_count: u32,
         gpt_length: GPTLenght,
         mentione_by_user: Option<String>,
+        options: SummaryOptions,
     },
     SummarizeMessage {
         chat: Chat,
@@ -38,6 +113,17 @@ pub enum Command {
         recipient: Chat,
         prompt: Prompt,
     },
+    // Chunks of a long summary, sent as one message. Failed chunks are skipped and noted.
+    SendSections {
+        recipient: Chat,
+        prompts: Vec<Prompt>,
+    },
+    // Links shared in the latest messages with a short description.
+    LinkDigest {
+        chat: Chat,
+        recipient: Chat,
+        message_count: u32,
+    },
     Ask {
         chat: Chat,
         recipient: Chat,
@@ -45,38 +131,1241 @@ pub enum Command {
         message_count: u32,
         gpt_length: GPTLenght,
     },
+    FollowUp {
+        recipient: Chat,
+        question: String,
+        gpt_length: GPTLenght,
+    },
+    // A reply to a summary sent to the recipient, answered over the messages it was made from.
+    Elaborate {
+        chat: Chat,
+        recipient: Chat,
+        summary_message_id: i32,
+        instruction: String,
+        source: SummarySource,
+    },
+    // The rest of the latest truncated response.
+    More {
+        recipient: Chat,
+    },
+    // Enqueues the latest failed command of the recipient again.
+    Retry {
+        recipient: Chat,
+    },
+    // Combined summary of several chats for an operator running them.
+    MultiDigest {
+        chats: Vec<Chat>,
+        recipient: Chat,
+        message_count: u32,
+        gpt_length: GPTLenght,
+    },
+    // The bot was removed from the chat, queued commands for it can't be completed.
+    ChatRemoved {
+        chat_id: i64,
+    },
+    // Drops the commands queued for the recipient, the running one is finished.
+    Cancel {
+        recipient: Chat,
+    },
+    // Checks the dependencies of the bot and sends the report to the recipient.
+    SelfTest {
+        recipient: Chat,
+    },
+    // A request whose command message got the in-progress reaction, it's swapped for the outcome
+    // once the request is done. Unwrapped on receive, the wrapped command is queued.
+    Acknowledged {
+        chat: Chat,
+        message_id: i32,
+        command: Box<Command>,
+    },
+    // Waits in the queue until the day whose cost budget it was charged to.
+    Deferred {
+        until: SystemTime,
+        command: Box<Command>,
+    },
+}
+
+impl Queued for Command {
+    fn targets_chat(&self, chat_id: i64) -> bool {
+        match self {
+            Command::Summarize {
+                chat, recipient, ..
+            }
+            | Command::SummarizeMessage {
+                chat, recipient, ..
+            }
+            | Command::Ask {
+                chat, recipient, ..
+            }
+            | Command::Elaborate {
+                chat, recipient, ..
+            }
+            | Command::LinkDigest {
+                chat, recipient, ..
+            } => chat.id() == chat_id || recipient.id() == chat_id,
+            Command::SendPrompt { recipient, .. }
+            | Command::SendSections { recipient, .. }
+            | Command::FollowUp { recipient, .. }
+            | Command::More { recipient }
+            | Command::Retry { recipient }
+            | Command::Cancel { recipient }
+            | Command::SelfTest { recipient } => recipient.id() == chat_id,
+            Command::MultiDigest {
+                chats, recipient, ..
+            } => recipient.id() == chat_id || chats.iter().any(|chat| chat.id() == chat_id),
+            Command::ChatRemoved { .. } => false,
+            Command::Acknowledged { command, .. } | Command::Deferred { command, .. } => {
+                command.targets_chat(chat_id)
+            }
+        }
+    }
+
+    // Responses to a chat are sent in the order they were requested.
+    fn ordering_key(&self) -> Option<i64> {
+        self.recipient_id()
+    }
+
+    // Quick requests about a single message or question go ahead of the long summaries.
+    fn priority(&self) -> Priority {
+        match self {
+            Command::SummarizeMessage { .. }
+            | Command::Ask { .. }
+            | Command::FollowUp { .. }
+            | Command::Elaborate { .. }
+            | Command::More { .. } => Priority::High,
+            // Time windows and days are summarized from up to all stored messages, only a large
+            // count asked for is a bulk summary.
+            Command::Summarize {
+                message_count,
+                options,
+                ..
+            } if options.within.is_none()
+                && options.period.is_none()
+                && *message_count > consts::BULK_SUMMARY_MESSAGES =>
+            {
+                Priority::Low
+            }
+            Command::MultiDigest { .. } => Priority::Low,
+            Command::Acknowledged { command, .. } | Command::Deferred { command, .. } => {
+                command.priority()
+            }
+            _ => Priority::Normal,
+        }
+    }
+
+    fn not_before(&self) -> Option<SystemTime> {
+        match self {
+            Command::Deferred { until, .. } => Some(*until),
+            _ => None,
+        }
+    }
+}
+
+// The command message of a request that got the in-progress reaction.
+#[derive(Clone)]
+struct Ack {
+    chat: Chat,
+    message_id: i32,
+}
+
+// A received or restored request, kept until all of its commands are done. Cancellations match
+// the command it was received with.
+struct Request<C = Command> {
+    command: C,
+    pending_id: Option<i64>,
+    ack: Option<Ack>,
+}
+
+// A queued command with the request it was made for. Prompts made for
+// a request keep its priority, so a bulk summary doesn't get ahead once it's prepared.
+struct Tracked<C = Command> {
+    command: C,
+    request: u64,
+    priority: Priority,
+}
+
+impl<C: Queued> Queued for Tracked<C> {
+    fn targets_chat(&self, chat_id: i64) -> bool {
+        self.command.targets_chat(chat_id)
+    }
+
+    fn ordering_key(&self) -> Option<i64> {
+        self.command.ordering_key()
+    }
+
+    fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    fn not_before(&self) -> Option<SystemTime> {
+        self.command.not_before()
+    }
+}
+
+impl Command {
+    // Summaries of a forwarded post show where it was posted.
+    fn with_attribution(self, attribution: &str) -> Self {
+        match self {
+            Command::SendPrompt { recipient, prompt } => Command::SendPrompt {
+                recipient,
+                prompt: prompt.with_attribution(attribution.to_string()),
+            },
+            Command::SendSections { recipient, prompts } => Command::SendSections {
+                recipient,
+                prompts: prompts
+                    .into_iter()
+                    .map(|prompt| prompt.with_attribution(attribution.to_string()))
+                    .collect(),
+            },
+            command => command,
+        }
+    }
+
+    // Prompts made for a request share its retry budget.
+    fn with_retry_budget(self, retries: &RetryBudget) -> Self {
+        match self {
+            Command::SendPrompt { recipient, prompt } => Command::SendPrompt {
+                recipient,
+                prompt: prompt.with_retry_budget(retries.clone()),
+            },
+            Command::SendSections { recipient, prompts } => Command::SendSections {
+                recipient,
+                prompts: prompts
+                    .into_iter()
+                    .map(|prompt| prompt.with_retry_budget(retries.clone()))
+                    .collect(),
+            },
+            command => command,
+        }
+    }
+
+    // Prompts the command sends to OpenAI, their estimated cost is charged to the daily budget.
+    fn prompts(&self) -> &[Prompt] {
+        match self {
+            Command::SendPrompt { prompt, .. } => std::slice::from_ref(prompt),
+            Command::SendSections { prompts, .. } => prompts,
+            _ => &[],
+        }
+    }
+
+    // Prompts carry the budget of their request, other commands start a new request.
+    fn retry_budget(&self, retries: u32) -> RetryBudget {
+        match self {
+            Command::SendPrompt { prompt, .. } => prompt.retry_budget().clone(),
+            Command::SendSections { prompts, .. } if !prompts.is_empty() => {
+                prompts[0].retry_budget().clone()
+            }
+            Command::Deferred { command, .. } => command.retry_budget(retries),
+            _ => RetryBudget::new(retries),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Summarize { .. } => "summarize",
+            Command::SummarizeMessage { .. } => "summarize_message",
+            Command::SendPrompt { .. } => "send_prompt",
+            Command::SendSections { .. } => "send_sections",
+            Command::Ask { .. } => "ask",
+            Command::Elaborate { .. } => "elaborate",
+            Command::LinkDigest { .. } => "link_digest",
+            Command::FollowUp { .. } => "follow_up",
+            Command::More { .. } => "more",
+            Command::Retry { .. } => "retry",
+            Command::MultiDigest { .. } => "multi_digest",
+            Command::ChatRemoved { .. } => "chat_removed",
+            Command::Cancel { .. } => "cancel",
+            Command::SelfTest { .. } => "self_test",
+            Command::Acknowledged { command, .. } | Command::Deferred { command, .. } => {
+                command.name()
+            }
+        }
+    }
+
+    fn chat_id(&self) -> Option<i64> {
+        match self {
+            Command::Summarize { chat, .. }
+            | Command::SummarizeMessage { chat, .. }
+            | Command::Ask { chat, .. }
+            | Command::Elaborate { chat, .. }
+            | Command::LinkDigest { chat, .. } => Some(chat.id()),
+            Command::ChatRemoved { chat_id } => Some(*chat_id),
+            Command::Acknowledged { command, .. } | Command::Deferred { command, .. } => {
+                command.chat_id()
+            }
+            Command::SendPrompt { .. }
+            | Command::SendSections { .. }
+            | Command::FollowUp { .. }
+            | Command::More { .. }
+            | Command::Retry { .. }
+            | Command::Cancel { .. }
+            | Command::SelfTest { .. }
+            | Command::MultiDigest { .. } => None,
+        }
+    }
+
+    fn recipient(&self) -> Option<&Chat> {
+        match self {
+            Command::Summarize { recipient, .. }
+            | Command::SummarizeMessage { recipient, .. }
+            | Command::Ask { recipient, .. }
+            | Command::Elaborate { recipient, .. }
+            | Command::LinkDigest { recipient, .. }
+            | Command::SendPrompt { recipient, .. }
+            | Command::SendSections { recipient, .. }
+            | Command::FollowUp { recipient, .. }
+            | Command::More { recipient }
+            | Command::Retry { recipient }
+            | Command::Cancel { recipient }
+            | Command::SelfTest { recipient }
+            | Command::MultiDigest { recipient, .. } => Some(recipient),
+            Command::ChatRemoved { .. } => None,
+            Command::Acknowledged { command, .. } | Command::Deferred { command, .. } => {
+                command.recipient()
+            }
+        }
+    }
+
+    fn recipient_id(&self) -> Option<i64> {
+        self.recipient().map(|recipient| recipient.id())
+    }
+
+    // User errors fail the same way again, and /more and /retry don't keep what they need to repeat.
+    fn is_retryable(&self, error: &BotError) -> bool {
+        !matches!(error, BotError::UserFacing(_))
+            && !matches!(
+                self,
+                Command::More { .. }
+                    | Command::Retry { .. }
+                    | Command::ChatRemoved { .. }
+                    | Command::Cancel { .. }
+                    | Command::SelfTest { .. }
+            )
+    }
+}
+
+fn cancelled_message(count: usize) -> String {
+    match count {
+        0 => "Nothing to cancel.".to_string(),
+        1 => "Cancelled 1 pending request.".to_string(),
+        count => format!("Cancelled {count} pending requests."),
+    }
+}
+
+// Stores the request, so it's processed again if the bot restarts before it's done. Its command
+// message is kept too, so it still gets the reaction.
+async fn store_pending_command(
+    db: &Mutex<Db>,
+    command: &Command,
+    ack: Option<&Ack>,
+) -> Option<i64> {
+    let pending = PendingCommand::of(command)?;
+    let serialized = match serde_json::to_string(&pending) {
+        Ok(serialized) => serialized,
+        Err(e) => {
+            log::error!("Failed to serialize pending command: {e}");
+            return None;
+        }
+    };
+    let ack = ack.map(|ack| PendingAck {
+        chat: pending::pack(&ack.chat),
+        message_id: ack.message_id,
+    });
+    let result = db.lock().await.add_pending_command(
+        command.chat_id(),
+        command.recipient_id(),
+        &serialized,
+        ack.as_ref(),
+    );
+    result
+        .map_err(|e| log::error!("Failed to store pending command: {e}"))
+        .ok()
+}
+
+// Starts tracking a received or restored request and returns its command to queue.
+fn track<C: Queued + Clone>(
+    requests: &mut Requests<Request<C>>,
+    command: C,
+    pending_id: Option<i64>,
+    ack: Option<Ack>,
+) -> Tracked<C> {
+    let request = requests.start(Request {
+        command: command.clone(),
+        pending_id,
+        ack,
+    });
+    Tracked {
+        priority: command.priority(),
+        request,
+        command,
+    }
+}
+
+// The commands left by a command of the request are queued for the same request, unless it was
+// cancelled meanwhile.
+fn follow_ups<C>(
+    requests: &mut Requests<Request<C>>,
+    request: u64,
+    priority: Priority,
+    commands: Vec<C>,
+) -> Vec<Tracked<C>> {
+    if requests.is_cancelled(request) {
+        return vec![];
+    }
+    commands
+        .into_iter()
+        .map(|command| {
+            requests.fork(request);
+            Tracked {
+                command,
+                request,
+                priority,
+            }
+        })
+        .collect()
+}
+
+// A command or media job of the request is done. Once the whole request is, its stored copy is
+// dropped and it's returned with whether it succeeded.
+async fn finish_request<C>(
+    requests: &Mutex<Requests<Request<C>>>,
+    db: &Mutex<Db>,
+    request: u64,
+    failed: bool,
+) -> Option<(Request<C>, bool)> {
+    let finished = requests.lock().await.finish(request, failed)?;
+    if let Some(id) = finished.0.pending_id {
+        forget_pending_command(db, id).await;
+    }
+    Some(finished)
+}
+
+// Cancels the requests whose command matches. Their stored copies are dropped right away, so they
+// aren't made again after a restart. Returns the number of cancelled requests and their dropped
+// queued commands, which are finished by the caller.
+async fn cancel_requests<C: Queued>(
+    queue: &RwLock<CommandQueue<Tracked<C>>>,
+    requests: &Mutex<Requests<Request<C>>>,
+    db: &Mutex<Db>,
+    matches: impl Fn(&C) -> bool,
+) -> (usize, Vec<Tracked<C>>) {
+    let (cancelled, pending_ids): (HashSet<u64>, Vec<Option<i64>>) = requests
+        .lock()
+        .await
+        .cancel(|request| matches(&request.command))
+        .into_iter()
+        .map(|(id, request)| (id, request.pending_id))
+        .unzip();
+    for id in pending_ids.into_iter().flatten() {
+        forget_pending_command(db, id).await;
+    }
+    let dropped = queue
+        .write()
+        .await
+        .cancel(|tracked| cancelled.contains(&tracked.request));
+    (cancelled.len(), dropped)
+}
+
+// /retry queues the latest failed command of the recipient again.
+fn retry_failed<C>(failed_commands: &mut FailedCommands<C>, recipient_id: i64) -> Result<C> {
+    failed_commands
+        .take(recipient_id)
+        .ok_or_else(|| BotError::UserFacing("Nothing to retry.".to_string()))
+}
+
+async fn forget_pending_command(db: &Mutex<Db>, id: i64) {
+    if let Err(e) = db.lock().await.remove_pending_command(id) {
+        log::error!("Failed to remove pending command {id}: {e}");
+    }
+}
+
+impl Tracker {
+    // Returns the number of cancelled requests.
+    async fn cancel(
+        &self,
+        queue: &RwLock<CommandQueue<Tracked>>,
+        matches: impl Fn(&Command) -> bool,
+    ) -> usize {
+        let (cancelled, dropped) = cancel_requests(queue, &self.requests, &self.db, matches).await;
+        for tracked in dropped {
+            self.finish(tracked.request, true).await;
+        }
+        cancelled
+    }
+
+    async fn finish(&self, request: u64, failed: bool) {
+        let Some((request, succeeded)) =
+            finish_request(&self.requests, &self.db, request, failed).await
+        else {
+            return;
+        };
+        if let Some(ack) = request.ack {
+            let reaction = if succeeded {
+                consts::DONE_REACTION
+            } else {
+                consts::FAILED_REACTION
+            };
+            self.react(&ack, reaction).await;
+        }
+    }
+
+    // Replaces the in-progress reaction. The command message may be gone by now, it's only logged.
+    async fn react(&self, ack: &Ack, reaction: &str) {
+        if let Err(e) = self
+            .client
+            .send_reactions(&ack.chat, ack.message_id, reaction)
+            .await
+        {
+            log::warn!("Failed to react to the command message: {:?}", e);
+        }
+    }
+
+    // Reports the failure to the user and keeps the command for /retry, and for /deadletters
+    // unless the user is at fault.
+    async fn fail(&self, command: Command, error: &BotError) {
+        let retryable = command.is_retryable(error);
+        if !matches!(error, BotError::UserFacing(_)) {
+            self.record_dead_letter(&command, error).await;
+        }
+        self.report_error(&command, error, retryable).await;
+        if let (true, Some(recipient_id)) = (retryable, command.recipient_id()) {
+            self.failed_commands
+                .lock()
+                .await
+                .remember(command.chat_id(), recipient_id, command);
+        }
+    }
+
+    // Keeps permanently failed commands, so operators can inspect them with /deadletters.
+    async fn record_dead_letter(&self, command: &Command, error: &BotError) {
+        let result = self.db.lock().await.record_dead_letter(
+            command.name(),
+            command.chat_id(),
+            command.recipient_id(),
+            &error.to_string(),
+        );
+        if let Err(e) = result {
+            log::error!("Failed to record dead letter: {e}");
+        }
+    }
+
+    // Tells the user who made the request why it failed.
+    async fn report_error(&self, command: &Command, error: &BotError, retryable: bool) {
+        let Some(recipient) = command.recipient() else {
+            return;
+        };
+        let message = if retryable {
+            format!("{}\nSend /retry to try again.", error.user_message())
+        } else {
+            error.user_message().to_string()
+        };
+        if let Err(e) = self.client.send_message(recipient, message).await {
+            log::error!("Failed to report error: {:?}", e);
+        }
+    }
+}
+
+// Keeps messages mentioning any of the comma separated keywords, ignoring case.
+fn filter_by_topic<T>(messages: Vec<T>, topic: Option<&str>, text: impl Fn(&T) -> &str) -> Vec<T> {
+    let Some(topic) = topic else {
+        return messages;
+    };
+    let keywords = topic
+        .split(',')
+        .map(|keyword| keyword.trim().to_lowercase())
+        .filter(|keyword| !keyword.is_empty())
+        .collect::<Vec<_>>();
+    messages
+        .into_iter()
+        .filter(|message| {
+            let text = text(message).to_lowercase();
+            keywords.iter().any(|keyword| text.contains(keyword))
+        })
+        .collect()
+}
+
+// Whether a message of the user with `username` passes the /summarize @user filter.
+fn sent_by(username: Option<&str>, mentioned_by_user: Option<&str>) -> bool {
+    match mentioned_by_user {
+        Some(mentioned_by_user) => username == Some(mentioned_by_user),
+        None => true,
+    }
+}
+
+// Messages of the members who opted out with /optout are never summarized.
+fn summarizable(sender_id: Option<i64>, opted_out: &[i64]) -> bool {
+    !sender_id.is_some_and(|sender_id| opted_out.contains(&sender_id))
+}
+
+// Stored ids of the requested messages, newest first, and the members who opted out.
+fn load_message_ids(
+    db: &Db,
+    chat_id: i64,
+    topic_id: Option<i32>,
+    message_count: u32,
+    within: Option<Duration>,
+    window: Option<MessageWindow>,
+) -> Result<(Vec<i32>, Vec<i64>)> {
+    let messages_id_to_load = match (window, within) {
+        (Some(MessageWindow::Around(message_id, radius)), _) => {
+            db.get_messages_id_around(chat_id, topic_id, message_id, radius)?
+        }
+        (Some(MessageWindow::Between(first, last)), _) => {
+            let mut message_ids = db.get_messages_id_between(chat_id, topic_id, first, last)?;
+            message_ids.truncate(message_count as usize);
+            message_ids
+        }
+        (Some(MessageWindow::Period(start, end)), _) => {
+            db.get_messages_id_in_period(chat_id, topic_id, message_count, start, end)?
+        }
+        (None, Some(window)) => {
+            db.get_messages_id_within(chat_id, topic_id, message_count, window)?
+        }
+        (None, None) => db.get_messages_id(chat_id, topic_id, message_count)?,
+    };
+    Ok((messages_id_to_load, db.get_opted_out_users(chat_id)?))
+}
+
+// Splits the requested messages into the stored texts to summarize and the ids of the rest, which
+// are fetched. Stored texts are filtered like the fetched messages.
+fn split_stored(
+    db: &Db,
+    chat_id: i64,
+    message_ids: &[i32],
+    opted_out: &[i64],
+    mentioned_by_user: Option<&str>,
+) -> Result<(Vec<StoredMessage>, Vec<i32>)> {
+    let stored = db.get_messages_with_text(chat_id, message_ids)?;
+    let to_fetch = message_ids
+        .iter()
+        .copied()
+        .filter(|message_id| {
+            !stored
+                .iter()
+                .any(|message| message.message_id == *message_id)
+        })
+        .collect();
+    let stored = stored
+        .into_iter()
+        .filter(|message| {
+            summarizable(message.sender_id, opted_out)
+                && sent_by(message.sender_username.as_deref(), mentioned_by_user)
+        })
+        .collect();
+    Ok((stored, to_fetch))
+}
+
+// Time window in the same units it's requested with, like 24h.
+fn format_window(window: Duration) -> String {
+    let minutes = window.as_secs() / 60;
+    let (hours, minutes_left) = (minutes / 60, minutes % 60);
+    let (days, hours_left) = (hours / 24, hours % 24);
+    if minutes_left != 0 {
+        format!("{minutes}m")
+    } else if hours_left != 0 {
+        format!("{hours}h")
+    } else {
+        format!("{days}d")
+    }
+}
+
+// The rating buttons are attached to the last part of a summary.
+fn with_feedback(part: String, summary_id: Option<i64>) -> InputMessage {
+    match summary_id {
+        Some(summary_id) => InputMessage::text(part).reply_markup(&feedback::buttons(summary_id)),
+        None => part.into(),
+    }
+}
+
+// Wraps summaries into the configured header and footer, preceded by the source of a forwarded post.
+// Answers are sent as is.
+fn decorate(
+    template: &SummaryTemplate,
+    prompt: &Prompt,
+    message: &str,
+    message_count: usize,
+) -> String {
+    match prompt.kind() {
+        PromptKind::Summary => {
+            let summary =
+                template.apply(message, message_count, prompt.gpt_length(), prompt.model());
+            match prompt.attribution() {
+                Some(attribution) => format!("{attribution}\n\n{summary}"),
+                None => summary,
+            }
+        }
+        PromptKind::Question | PromptKind::LinkDigest => message.to_string(),
+    }
+}
+
+// Where a message forwarded from a channel was originally posted.
+fn forward_attribution(message: &Message) -> Option<String> {
+    let tl::enums::MessageFwdHeader::Header(header) = message.forward_header()?;
+    let Some(tl::enums::Peer::Channel(channel)) = &header.from_id else {
+        return None;
+    };
+    let post_id = header.channel_post?;
+    Some(channel_post_attribution(
+        channel.channel_id,
+        post_id,
+        header.post_author.as_deref(),
+    ))
+}
+
+// The t.me/c link opens the post for everyone who can see the channel, public or private.
+fn channel_post_attribution(channel_id: i64, post_id: i32, author: Option<&str>) -> String {
+    let link = format!("https://t.me/c/{channel_id}/{post_id}");
+    match author {
+        Some(author) => format!("📢 Forwarded from a channel post by {author}: {link}"),
+        None => format!("📢 Forwarded from a channel post: {link}"),
+    }
+}
+
+fn with_model(prompts: Vec<Prompt>, model: Option<&str>) -> Vec<Prompt> {
+    match model {
+        Some(model) => prompts
+            .into_iter()
+            .map(|prompt| prompt.with_model(model.to_string()))
+            .collect(),
+        None => prompts,
+    }
+}
+
+// A summary that didn't fit into one prompt is summarized in sections, merged into a single message.
+fn summary_commands(recipient: &Chat, mut prompts: Vec<Prompt>) -> Vec<Command> {
+    if prompts.len() == 1 {
+        return vec![Command::SendPrompt {
+            recipient: recipient.clone(),
+            prompt: prompts.remove(0),
+        }];
+    }
+    vec![Command::SendSections {
+        recipient: recipient.clone(),
+        prompts,
+    }]
+}
+
+// Charges the estimated cost of the prompts among the commands to the daily budget. The ones over
+// today's budget are deferred to the day they fit into. Large and deferred requests are answered
+// with their estimate.
+async fn charge(
+    budget: CostBudget,
+    db: &Mutex<Db>,
+    client: &Client,
+    place: usize,
+    commands: Vec<Command>,
+) -> Result<Vec<Command>> {
+    let estimate = CostEstimate::of(commands.iter().flat_map(Command::prompts));
+    let Some(recipient) = commands.iter().find_map(Command::recipient).cloned() else {
+        return Ok(commands);
+    };
+    if estimate == CostEstimate::default() {
+        return Ok(commands);
+    }
+    let now = unix_time(SystemTime::now());
+    let days = budget.spend(&*db.lock().await, estimate.usd, now)?;
+    match days {
+        None => Err(over_budget(budget, estimate)),
+        Some(0) => {
+            if estimate.input_tokens >= consts::COST_NOTICE_TOKENS {
+                client
+                    .send_message(
+                        &recipient,
+                        format!("The request is queued, it's number {place} in line: {estimate}."),
+                    )
+                    .await?;
+            }
+            Ok(commands)
+        }
+        Some(days) => {
+            client
+                .send_message(&recipient, deferred_message(budget, estimate, days))
+                .await?;
+            let until = cost::day_start(now, days);
+            Ok(commands
+                .into_iter()
+                .map(|command| Command::Deferred {
+                    until,
+                    command: Box::new(command),
+                })
+                .collect())
+        }
+    }
+}
+
+fn over_budget(budget: CostBudget, estimate: CostEstimate) -> BotError {
+    BotError::UserFacing(format!(
+        "The request is over the daily budget of ${:.2}: {estimate}.",
+        budget.limit().unwrap_or_default()
+    ))
+}
+
+fn deferred_message(budget: CostBudget, estimate: CostEstimate, days: u64) -> String {
+    let when = match days {
+        1 => "tomorrow".to_string(),
+        days => format!("in {days} days"),
+    };
+    format!(
+        "Today's budget of ${:.2} is spent, the request is queued for {when} (UTC): {estimate}.",
+        budget.limit().unwrap_or_default()
+    )
+}
+
+// Like `merge_sections`, but sections with a cached partial summary aren't sent again.
+// Returns the summary and the partial summaries of the sent sections.
+fn summarize_with_partials(
+    sections: Vec<(u64, Option<String>, Prompt)>,
+    mut send: impl FnMut(Prompt) -> Result<String>,
+    merge: impl FnOnce(Vec<String>) -> Result<String>,
+) -> (Result<String>, Vec<(u64, String)>) {
+    let mut summarized = vec![];
+    let summary = merge_sections(
+        sections,
+        |(key, partial, prompt)| match partial {
+            Some(partial) => Ok(partial),
+            None => {
+                let partial = send(prompt)?;
+                summarized.push((key, partial.clone()));
+                Ok(partial)
+            }
+        },
+        merge,
+    );
+    (summary, summarized)
+}
+
+// Summarizes every section and joins the results.
+fn summarize_sections<T>(
+    sections: Vec<T>,
+    summarize: impl FnMut(T) -> Result<String>,
+) -> Result<String> {
+    merge_sections(sections, summarize, |summaries| Ok(summaries.join("\n\n")))
+}
+
+// Summarizes every section and merges the results with `merge`. A single summary isn't merged,
+// and if merging fails the summaries are joined as they are. A failed section doesn't abort the
+// rest, it's noted at the end instead. Fails only if no section was summarized.
+fn merge_sections<T>(
+    sections: Vec<T>,
+    mut summarize: impl FnMut(T) -> Result<String>,
+    merge: impl FnOnce(Vec<String>) -> Result<String>,
+) -> Result<String> {
+    let mut summaries = vec![];
+    let mut failed = 0;
+    let mut last_error = None;
+    for (index, section) in sections.into_iter().enumerate() {
+        match summarize(section) {
+            Ok(summary) => summaries.push(summary),
+            Err(e) => {
+                log::warn!("Failed to summarize section {}: {e}", index + 1);
+                failed += 1;
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if summaries.is_empty() {
+        return Err(last_error.unwrap_or_else(|| no_messages_found(None)));
+    }
+    let mut summary = if summaries.len() == 1 {
+        summaries.remove(0)
+    } else {
+        let joined = summaries.join("\n\n");
+        merge(summaries).unwrap_or_else(|e| {
+            log::warn!("Failed to merge the section summaries: {e}");
+            joined
+        })
+    };
+    match failed {
+        0 => {}
+        1 => summary.push_str("\n\n(1 section could not be summarized)"),
+        failed => summary.push_str(&format!("\n\n({failed} sections could not be summarized)")),
+    }
+    Ok(summary)
+}
+
+// Replies and reactions the message got, the most engaging messages are kept when sampling.
+fn engagement(message: &Message) -> u32 {
+    let replies = match &message.raw.replies {
+        Some(tl::enums::MessageReplies::Replies(replies)) => replies.replies,
+        None => 0,
+    };
+    let reactions = match &message.raw.reactions {
+        Some(tl::enums::MessageReactions::Reactions(reactions)) => reactions
+            .results
+            .iter()
+            .map(|tl::enums::ReactionCount::Count(count)| count.count)
+            .sum(),
+        None => 0,
+    };
+    (replies + reactions).max(0) as u32
+}
+
+fn has_link(text: &str) -> bool {
+    text.split_whitespace().any(|word| {
+        let word = word.trim_start_matches(|c: char| !c.is_alphanumeric());
+        ["http://", "https://", "www."]
+            .iter()
+            .any(|prefix| word.len() > prefix.len() && word.starts_with(prefix))
+    })
+}
+
+fn no_messages_found(topic: Option<&str>) -> BotError {
+    BotError::UserFacing(match topic {
+        Some(topic) => {
+            format!("No messages about \"{topic}\" found. Try another keyword or more messages.")
+        }
+        None => "No messages found".to_string(),
+    })
+}
+
+// The OpenAI client and local transcription block, so they run on the blocking thread pool
+// instead of stalling the runtime for the whole request.
+async fn run_blocking<T: Send + 'static>(
+    call: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(call)
+        .await
+        .map_err(BotError::openai)?
+}
+
+// Length of the media, if ffprobe can read it.
+async fn media_duration(file: &str) -> Option<Duration> {
+    let output = tokio::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "csv=p=0",
+            file,
+        ])
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()),
+        Ok(_) => None,
+        Err(e) => {
+            log::warn!("Failed to run ffprobe: {e}");
+            None
+        }
+    }
+}
+
+// Media is identified by its file id, which doesn't change, so the same media summarized again
+// isn't downloaded and transcribed again while its transcript is cached.
+async fn cached_transcript<F, Fut>(
+    db: &Mutex<Db>,
+    media_id: i64,
+    now: SystemTime,
+    transcribe: F,
+) -> Result<Option<(String, bool)>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Option<(String, bool)>>>,
+{
+    let now = unix_time(now);
+    let since = now.saturating_sub(consts::MEDIA_TRANSCRIPT_TTL.as_secs());
+    if let Some(transcript) = db.lock().await.get_media_transcript(media_id, since)? {
+        log::info!("Using the cached transcript of media {media_id}");
+        return Ok(Some(transcript));
+    }
+
+    let transcript = transcribe().await?;
+    if let Some((text, recording)) = &transcript {
+        db.lock()
+            .await
+            .add_media_transcript(media_id, now, text, *recording, since)?;
+    }
+    Ok(transcript)
+}
+
+// Privacy focused instances keep the media on the host, so only a local transcriber is allowed.
+fn check_transcriber(transcriber: &dyn Transcriber, outbound_media: bool) -> Result<()> {
+    if transcriber.uploads_audio() && !outbound_media {
+        return Err(BotError::UserFacing(
+            "Media transcription is disabled on this instance".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Leftover media files aren't worth failing the summary for.
+async fn remove_media(path: &str) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        log::warn!("Failed to remove media file {path}: {e}");
+    }
+}
+
+// Files of a media job, removed when the job ends on any path, including failed downloads and
+// conversions.
+struct MediaFiles {
+    paths: Vec<String>,
+}
+
+impl MediaFiles {
+    fn new(path: &str) -> Self {
+        Self {
+            paths: vec![path.to_string()],
+        }
+    }
+
+    fn track(&mut self, path: &str) {
+        self.paths.push(path.to_string());
+    }
+}
+
+impl Drop for MediaFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                // Not downloaded or converted yet, or already removed.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::warn!("Failed to remove media file {path}: {e}"),
+            }
+        }
+    }
+}
+
+// Media of the jobs cut off by a shutdown, including partial downloads.
+async fn remove_media_files() {
+    let mut entries = match tokio::fs::read_dir(consts::MEDIA_DIR).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to list media files: {e}");
+            return;
+        }
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if let Some(path) = entry.path().to_str() {
+            remove_media(path).await;
+        }
+    }
+}
+
+// Stored messages loaded instead of the latest ones.
+#[derive(Clone, Copy, Debug)]
+enum MessageWindow {
+    // The message and up to this many messages before and after it.
+    Around(i32, u32),
+    // The messages from the first to the last id.
+    Between(i32, i32),
+    // The messages stored from the first unix time until the second one.
+    Period(u64, u64),
 }
 
 struct CommandResult {
     new_commands: Vec<Command>,
 }
 
+// The request a command is processed for, shared by everything done for it.
+#[derive(Clone)]
+struct RequestScope {
+    id: u64,
+    retries: RetryBudget,
+}
+
 impl Processor {
     // Creates processor and writing stream
     pub fn new(client: Client, db: Arc<Mutex<Db>>, openai: OpenAIClient) -> Self {
-        Self { client, db, openai }
+        Self {
+            media: MediaWorker {
+                db: db.clone(),
+                openai: openai.clone(),
+                transcriber: Arc::new(openai.clone()),
+                outbound_media: true,
+                media_download: DownloadPolicy::default(),
+                summary_model: None,
+                media_types: MediaTypes::default(),
+            },
+            tracker: Tracker {
+                client: client.clone(),
+                db: db.clone(),
+                requests: Arc::new(Mutex::new(Requests::new())),
+                failed_commands: Arc::new(Mutex::new(FailedCommands::new())),
+            },
+            client,
+            db,
+            openai,
+            dm_context: Mutex::new(DmContext::new(consts::DM_CONTEXT_TTL)),
+            stream_responses: false,
+            summary_template: SummaryTemplate::default(),
+            max_response_length: None,
+            remainders: Mutex::new(Remainders::default()),
+            output_redactor: None,
+            retry_budget: consts::COMMAND_RETRY_BUDGET,
+            partials: Mutex::new(PartialSummaries::new(consts::PARTIAL_SUMMARIES_TTL)),
+            media_jobs: MediaJobs::new(consts::MAX_MEDIA_JOBS, consts::MEDIA_QUEUE_SIZE),
+            features: Features::default(),
+            queue: Arc::new(RwLock::new(CommandQueue::new())),
+            max_concurrent_commands: consts::MAX_CONCURRENT_COMMANDS,
+            cost_budget: CostBudget::new(None),
+            sampling: None,
+        }
+    }
+
+    pub fn with_streaming(mut self, stream_responses: bool) -> Self {
+        self.stream_responses = stream_responses;
+        self
+    }
+
+    pub fn with_summary_template(mut self, summary_template: SummaryTemplate) -> Self {
+        self.summary_template = summary_template;
+        self
+    }
+
+    pub fn with_max_response_length(mut self, max_response_length: Option<usize>) -> Self {
+        self.max_response_length = max_response_length;
+        self
+    }
+
+    pub fn with_media_types(mut self, media_types: MediaTypes) -> Self {
+        self.media.media_types = media_types;
+        self
+    }
+
+    pub fn with_media_download(mut self, media_download: DownloadPolicy) -> Self {
+        self.media.media_download = media_download;
+        self
+    }
+
+    pub fn with_media_summary_model(mut self, summary_model: Option<String>) -> Self {
+        self.media.summary_model = summary_model;
+        self
+    }
+
+    pub fn with_outbound_media(mut self, outbound_media: bool) -> Self {
+        self.media.outbound_media = outbound_media;
+        self
+    }
+
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn with_max_media_jobs(mut self, max_media_jobs: usize) -> Self {
+        self.media_jobs = MediaJobs::new(max_media_jobs, consts::MEDIA_QUEUE_SIZE);
+        self
     }
 
+    pub fn with_max_concurrent_commands(mut self, max_concurrent_commands: usize) -> Self {
+        self.max_concurrent_commands = max_concurrent_commands;
+        self
+    }
+
+    // Requests are deferred to a later UTC day once their estimated cost would exceed the
+    // day's limit, in USD.
+    pub fn with_daily_cost_limit(mut self, daily_cost_limit: Option<f64>) -> Self {
+        self.cost_budget = CostBudget::new(daily_cost_limit);
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: Option<Sampling>) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    pub fn with_retry_budget(mut self, retry_budget: u32) -> Self {
+        self.retry_budget = retry_budget;
+        self
+    }
+
+    pub fn with_output_redaction(mut self, output_redactor: Option<Arc<Redactor>>) -> Self {
+        self.output_redactor = output_redactor;
+        self
+    }
+
+    pub fn with_transcriber(mut self, transcriber: Arc<dyn Transcriber>) -> Self {
+        self.media.transcriber = transcriber;
+        self
+    }
+
+    // Stops taking commands once `shutdown` is set. The running commands are finished, the queued
+    // requests stay stored and are restored on the next start.
     pub async fn run(
-        mut self,
+        self,
+        shutdown: tokio::sync::watch::Receiver<bool>,
     ) -> (
         impl std::future::Future<Output = ((), ())>,
         tokio::sync::mpsc::Sender<Command>,
     ) {
-        let queue = Arc::new(RwLock::new(Vec::<Command>::new()));
-        let (tx, mut rx) = tokio::sync::mpsc::channel(1000);
+        let queue = self.queue.clone();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(consts::COMMAND_CHANNEL_SIZE);
+
+        let restored = self.restore_pending_commands().await;
+        if !restored.is_empty() {
+            log::info!("Restored {} unfinished commands", restored.len());
+            queue.write().await.extend(restored);
+        }
 
         let msg_handler = {
             let queue = queue.clone();
+            let db = self.db.clone();
+            let client = self.client.clone();
+            let tracker = self.tracker.clone();
+            let mut shutdown = shutdown.clone();
 
             async move {
                 loop {
-                    let command = rx.recv().await;
+                    let command = tokio::select! {
+                        command = rx.recv() => command,
+                        _ = shutdown.changed() => {
+                            log::info!(
+                                "Shutting down, finishing {} running commands",
+                                queue.read().await.running()
+                            );
+                            break;
+                        }
+                    };
                     match command {
+                        Some(Command::ChatRemoved { chat_id }) => {
+                            let cancelled = tracker
+                                .cancel(&queue, |command| command.targets_chat(chat_id))
+                                .await;
+                            if let Err(e) = db.lock().await.remove_chat_pending_commands(chat_id) {
+                                log::error!("Failed to remove pending commands: {e}");
+                            }
+                            log::info!(
+                                "Removed from chat {chat_id}: cancelled {cancelled} requests"
+                            );
+                        }
+                        Some(Command::Cancel { recipient }) => {
+                            let cancelled = tracker
+                                .cancel(&queue, |command| {
+                                    command.recipient_id() == Some(recipient.id())
+                                })
+                                .await;
+                            let message = cancelled_message(cancelled);
+                            if let Err(e) = client.send_message(&recipient, message).await {
+                                log::error!("Failed to confirm cancellation: {:?}", e);
+                            }
+                        }
                         Some(command) => {
-                            let mut queue = queue.write().await;
+                            let (command, ack) = match command {
+                                Command::Acknowledged {
+                                    chat,
+                                    message_id,
+                                    command,
+                                } => (*command, Some(Ack { chat, message_id })),
+                                command => (command, None),
+                            };
+                            let pending_id =
+                                store_pending_command(&db, &command, ack.as_ref()).await;
+                            let tracked = track(
+                                &mut *tracker.requests.lock().await,
+                                command,
+                                pending_id,
+                                ack,
+                            );
                             log::info!("Received command: adding to queue");
-                            queue.push(command);
+                            queue.write().await.push(tracked);
                         }
                         None => break,
                     }
@@ -84,39 +1373,154 @@ impl Processor {
             }
         };
 
-        let processor = {
-            async move {
-                // Read from the front of the queue process and remove
-                loop {
-                    // Check if there is a command in the queue
-                    let command = {
-                        let queue = queue.read().await;
-                        queue.first().cloned()
+        let processor = async move {
+            run_workers(
+                &queue,
+                self.max_concurrent_commands,
+                consts::COMMAND_POLL_INTERVAL,
+                &shutdown,
+                |tracked| self.handle_tracked(tracked),
+            )
+            .await;
+
+            // Stored requests are made again from the start, the others are lost.
+            queue.write().await.cancel(|_| true);
+            let stopped = self.media_jobs.stop().await;
+            let (kept, dropped) = {
+                let requests = self.tracker.requests.lock().await;
+                let kept = requests
+                    .unfinished()
+                    .filter(|request| request.pending_id.is_some())
+                    .count();
+                (kept, requests.unfinished().count() - kept)
+            };
+            log::info!(
+                "Stopped with {kept} requests kept for the restart, {dropped} requests dropped \
+                and {stopped} media jobs stopped"
+            );
+            remove_media_files().await;
+        };
+        (join(msg_handler, processor), tx)
+    }
+
+    // Loads the requests that weren't done before the restart. The ones whose chats can't be
+    // reached anymore are dropped.
+    async fn restore_pending_commands(&self) -> Vec<Tracked> {
+        let pending = match self.db.lock().await.get_pending_commands() {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::error!("Failed to load pending commands: {e}");
+                return vec![];
+            }
+        };
+        let mut restored = Vec::with_capacity(pending.len());
+        for (id, serialized, ack) in pending {
+            let command = match serde_json::from_str::<PendingCommand>(&serialized) {
+                Ok(command) => command.unpack(&self.client).await,
+                Err(e) => Err(format!("it can't be parsed: {e}")),
+            };
+            match command {
+                Ok(command) => {
+                    let ack = match ack {
+                        Some(ack) => self.restore_ack(ack).await,
+                        None => None,
                     };
-                    if let Some(command) = command {
-                        log::info!("Processing command");
-                        match self.process_command(command).await {
-                            Ok(result) => {
-                                let mut queue = queue.write().await;
-                                queue.extend(result.new_commands);
-                                queue.remove(0);
-                            }
-                            Err(e) => {
-                                log::error!("Error processing command: {e}");
-                                let mut queue = queue.write().await;
-                                queue.remove(0);
-                            }
-                        }
-                    } else {
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    restored.push(track(
+                        &mut *self.tracker.requests.lock().await,
+                        command,
+                        Some(id),
+                        ack,
+                    ));
+                }
+                Err(e) => {
+                    log::warn!("Skipping pending command {id}: {e}");
+                    forget_pending_command(&self.db, id).await;
+                }
+            }
+        }
+        restored
+    }
+
+    // The request is processed without the reaction if its command message can't be reached.
+    async fn restore_ack(&self, ack: PendingAck) -> Option<Ack> {
+        match pending::unpack(&self.client, &ack.chat).await {
+            Ok(chat) => Some(Ack {
+                chat,
+                message_id: ack.message_id,
+            }),
+            Err(e) => {
+                log::warn!("Dropping the reaction of a pending command: {e}");
+                None
+            }
+        }
+    }
+
+    // The request is done once its last command or media job doesn't leave anything else.
+    async fn handle_tracked(&self, tracked: Tracked) -> Vec<Tracked> {
+        let Tracked {
+            command,
+            request,
+            priority,
+        } = tracked;
+        let new_commands = self.handle_command(command, request).await;
+        let failed = new_commands.is_none();
+        let follow_ups = follow_ups(
+            &mut *self.tracker.requests.lock().await,
+            request,
+            priority,
+            new_commands.unwrap_or_default(),
+        );
+        self.tracker.finish(request, failed).await;
+        follow_ups
+    }
+
+    // Returns the commands to queue next, None if the command failed.
+    async fn handle_command(&self, command: Command, request: u64) -> Option<Vec<Command>> {
+        log::info!("Processing command");
+        let scope = RequestScope {
+            id: request,
+            retries: command.retry_budget(self.retry_budget),
+        };
+        let result = match self.process_command(command.clone(), &scope).await {
+            Ok(result) => {
+                let new_commands = result
+                    .new_commands
+                    .into_iter()
+                    .map(|command| command.with_retry_budget(&scope.retries))
+                    .collect();
+                match command {
+                    // Charged when it was deferred.
+                    Command::Deferred { .. } => Ok(new_commands),
+                    _ => {
+                        let place = self.queue.read().await.waiting() + 1;
+                        charge(
+                            self.cost_budget,
+                            &self.db,
+                            &self.client,
+                            place,
+                            new_commands,
+                        )
+                        .await
                     }
                 }
             }
+            Err(e) => Err(e),
         };
-        (join(msg_handler, processor), tx)
+        match result {
+            Ok(new_commands) => Some(new_commands),
+            Err(e) => {
+                log::log!(e.log_level(), "Error processing command: {e}");
+                self.tracker.fail(command, &e).await;
+                None
+            }
+        }
     }
 
-    async fn process_command(&mut self, command: Command) -> anyhow::Result<CommandResult> {
+    async fn process_command(
+        &self,
+        command: Command,
+        scope: &RequestScope,
+    ) -> Result<CommandResult> {
         match command {
             Command::Summarize {
                 chat,
@@ -124,6 +1528,7 @@ impl Processor {
                 message_count,
                 gpt_length,
                 mentione_by_user,
+                options,
             } => {
                 self.prepare_summary_prompt(
                     chat,
@@ -131,54 +1536,377 @@ impl Processor {
                     message_count,
                     gpt_length,
                     mentione_by_user,
+                    options,
                 )
                 .await
             }
             Command::SummarizeMessage {
                 chat,
                 recipient,
-                message_id,
+                message_id,
+                gpt_length,
+            } => {
+                self.summarize_message(chat, recipient, message_id, gpt_length, scope)
+                    .await
+            }
+            Command::Ask {
+                chat,
+                recipient,
+                question,
+                message_count,
+                gpt_length,
+            } => {
+                self.ask_on_summary(chat, recipient, question, message_count, gpt_length)
+                    .await
+            }
+            Command::FollowUp {
+                recipient,
+                question,
                 gpt_length,
+            } => self.follow_up(recipient, question, gpt_length).await,
+            Command::Elaborate {
+                chat,
+                recipient,
+                summary_message_id,
+                instruction,
+                source,
             } => {
-                self.summarize_message(chat, recipient, message_id, gpt_length)
+                self.elaborate(chat, recipient, summary_message_id, instruction, source)
                     .await
             }
-            Command::Ask {
-                chat,
+            Command::MultiDigest {
+                chats,
                 recipient,
-                question,
                 message_count,
                 gpt_length,
             } => {
-                self.ask_on_summary(chat, recipient, question, message_count, gpt_length)
+                self.multi_digest(chats, recipient, message_count, gpt_length)
                     .await
             }
+            Command::LinkDigest {
+                chat,
+                recipient,
+                message_count,
+            } => self.link_digest(chat, recipient, message_count).await,
+            Command::More { recipient } => self.more(recipient).await,
+            Command::Retry { recipient } => {
+                let mut failed_commands = self.tracker.failed_commands.lock().await;
+                Ok(CommandResult {
+                    new_commands: vec![retry_failed(&mut failed_commands, recipient.id())?],
+                })
+            }
+            Command::SelfTest { recipient } => {
+                let checks = selftest::run(&self.client, &self.db, &self.openai).await;
+                self.client
+                    .send_message(&recipient, selftest::report(&checks))
+                    .await?;
+                Ok(CommandResult {
+                    new_commands: vec![],
+                })
+            }
+            // Handled on receive, never queued.
+            Command::ChatRemoved { .. } | Command::Cancel { .. } => Ok(CommandResult {
+                new_commands: vec![],
+            }),
+            Command::Acknowledged { command, .. } | Command::Deferred { command, .. } => {
+                Ok(CommandResult {
+                    new_commands: vec![*command],
+                })
+            }
             Command::SendPrompt { recipient, prompt } => {
                 log::info!("Sending prompt");
-                let result = self.openai.send_prompt(prompt);
-                match result {
-                    Ok(result) => {
-                        let message = result.choices[0].message.as_ref().unwrap().content.as_ref();
-                        self.client
-                            .send_message(&recipient, message)
-                            .await
-                            .map_err(|e| anyhow::anyhow!(e))?;
-                    }
-                    Err(e) => {
-                        log::error!("Error sending prompt: {:?}", e);
-                        self.client
-                            .send_message(
-                                recipient,
-                                "Failed to summarize the chat. Try again later",
-                            )
-                            .await?;
-                    }
+                let thread = prompt.thread().cloned();
+                // Structured summaries are rendered once complete, partial JSON can't be shown.
+                let result = if self.stream_responses && !self.openai.is_structured(&prompt) {
+                    self.stream_prompt(&recipient, prompt).await
+                } else {
+                    self.send_prompt(&recipient, prompt).await
+                };
+                let message = result?;
+                if let Some(thread) = thread {
+                    self.db.lock().await.add_ask_exchange(
+                        thread.chat_id,
+                        thread.user_id,
+                        unix_time(SystemTime::now()),
+                        &thread.question,
+                        &message,
+                    )?;
+                }
+                if let Chat::User(user) = &recipient {
+                    self.dm_context.lock().await.remember(user.id(), message);
                 }
                 Ok(CommandResult {
                     new_commands: vec![],
                 })
             }
+            Command::SendSections { recipient, prompts } => {
+                log::info!("Sending {} prompts", prompts.len());
+                let message = self.send_sections(&recipient, prompts).await?;
+                if let Chat::User(user) = &recipient {
+                    self.dm_context.lock().await.remember(user.id(), message);
+                }
+                Ok(CommandResult {
+                    new_commands: vec![],
+                })
+            }
+        }
+    }
+
+    async fn send_prompt(&self, recipient: &Chat, prompt: Prompt) -> Result<String> {
+        let openai = self.openai.clone();
+        let sent_prompt = prompt.clone();
+        let message = run_blocking(move || openai.send_checked_prompt(sent_prompt)).await?;
+        let message = self.redact(message);
+        let text = self.render(recipient, &prompt, &message).await;
+        let feedback = self
+            .feedback_id(recipient, &prompt, prompt.message_count())
+            .await?;
+        let sent = self.send_parts(recipient, &text, feedback).await?;
+        self.remember_source(recipient, &prompt, sent).await?;
+        Ok(message)
+    }
+
+    // Returns the id of the last sent message.
+    async fn send_parts(
+        &self,
+        recipient: &Chat,
+        text: &str,
+        feedback: Option<i64>,
+    ) -> Result<Option<i32>> {
+        let parts = split_message(text);
+        let last = parts.len().saturating_sub(1);
+        let mut sent = None;
+        for (index, part) in parts.into_iter().enumerate() {
+            let buttons = feedback.filter(|_| index == last);
+            let message = self
+                .client
+                .send_message(recipient, with_feedback(part, buttons))
+                .await?;
+            sent = Some(message.id());
+        }
+        Ok(sent)
+    }
+
+    // Summaries sent to users are linked to their messages, so replies to them can be answered.
+    async fn remember_source(
+        &self,
+        recipient: &Chat,
+        prompt: &Prompt,
+        sent: Option<i32>,
+    ) -> Result<()> {
+        let (Chat::User(_), Some(source), Some(message_id)) = (recipient, prompt.source(), sent)
+        else {
+            return Ok(());
+        };
+        let now = unix_time(SystemTime::now());
+        self.db.lock().await.add_summary_source(
+            recipient.id(),
+            message_id,
+            (
+                source.chat_id,
+                source.first_message_id,
+                source.last_message_id,
+            ),
+            now,
+            now.saturating_sub(consts::SUMMARY_SOURCE_TTL.as_secs()),
+        )?;
+        Ok(())
+    }
+
+    // Summaries are sent with the rating buttons, the settings they were made with are stored
+    // so the ratings can be linked to them.
+    async fn feedback_id(
+        &self,
+        recipient: &Chat,
+        prompt: &Prompt,
+        message_count: usize,
+    ) -> Result<Option<i64>> {
+        if !self.features.feedback_enabled || prompt.kind() != PromptKind::Summary {
+            return Ok(None);
+        }
+        let (parameters, prompt_version) = feedback::summary_parameters(prompt, message_count);
+        // Summaries of media have no source, they're of the chat they're sent to.
+        let chat_id = prompt
+            .source()
+            .map_or(recipient.id(), |source| source.chat_id);
+        let summary_id = self.db.lock().await.add_rated_summary(
+            chat_id,
+            recipient.id(),
+            &parameters,
+            &prompt_version,
+            unix_time(SystemTime::now()),
+        )?;
+        Ok(Some(summary_id))
+    }
+
+    // Shows the response while it's being generated by editing a placeholder message.
+    // Messages are sent as plain text, so incomplete Markdown can't break them.
+    // If streaming fails, the placeholder is removed and the response is sent when complete.
+    async fn stream_prompt(&self, recipient: &Chat, prompt: Prompt) -> Result<String> {
+        let placeholder = self.client.send_message(recipient, "…").await?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let openai = self.openai.clone();
+        let streamed_prompt = prompt.clone();
+        let request = tokio::task::spawn_blocking(move || {
+            openai.send_prompt_streaming(streamed_prompt, |delta| {
+                tx.send(delta.to_string()).ok();
+            })
+        });
+
+        let mut streamed = StreamingMessage::new(consts::STREAM_EDIT_INTERVAL, Instant::now());
+        let mut shown = String::new();
+        while let Some(delta) = rx.recv().await {
+            if let Some(text) = streamed.push(&delta, Instant::now()) {
+                let text = self.redact(text);
+                // Failed intermediate edits aren't fatal, the final text is set below.
+                if let Err(e) = self
+                    .client
+                    .edit_message(recipient, placeholder.id(), text.as_str())
+                    .await
+                {
+                    log::warn!("Failed to edit streamed message: {:?}", e);
+                }
+                shown = text;
+            }
+        }
+
+        let message = match request.await.map_err(BotError::openai)? {
+            Ok(message) => {
+                let openai = self.openai.clone();
+                let checked_prompt = prompt.clone();
+                let message =
+                    run_blocking(move || openai.recheck_summary(&checked_prompt, message)).await?;
+                self.redact(message)
+            }
+            Err(e) => {
+                log::warn!("Streaming failed: {e}. Sending the response at once");
+                self.client
+                    .delete_messages(recipient, &[placeholder.id()])
+                    .await
+                    .ok();
+                return self.send_prompt(recipient, prompt).await;
+            }
+        };
+
+        let parts = split_message(&self.render(recipient, &prompt, &message).await);
+        let feedback = self
+            .feedback_id(recipient, &prompt, prompt.message_count())
+            .await?;
+        let last = parts.len().saturating_sub(1);
+        let mut sent = placeholder.id();
+        for (index, part) in parts.into_iter().enumerate() {
+            let buttons = feedback.filter(|_| index == last);
+            if index == 0 {
+                // Telegram rejects edits that don't change the message.
+                if part != shown || buttons.is_some() {
+                    self.client
+                        .edit_message(recipient, placeholder.id(), with_feedback(part, buttons))
+                        .await?;
+                }
+            } else {
+                sent = self
+                    .client
+                    .send_message(recipient, with_feedback(part, buttons))
+                    .await?
+                    .id();
+            }
+        }
+        self.remember_source(recipient, &prompt, Some(sent)).await?;
+        Ok(message)
+    }
+
+    fn redact(&self, message: String) -> String {
+        match &self.output_redactor {
+            Some(redactor) => redactor.redact(&message),
+            None => message,
+        }
+    }
+
+    // Summarizes every chunk and merges the summaries into one message.
+    async fn send_sections(&self, recipient: &Chat, prompts: Vec<Prompt>) -> Result<String> {
+        let Some(first) = prompts.first().cloned() else {
+            return Err(no_messages_found(None));
+        };
+        let message_count = prompts.iter().map(Prompt::message_count).sum();
+        let sections = {
+            let partials = self.partials.lock().await;
+            prompts
+                .into_iter()
+                .map(|prompt| {
+                    let key = prompt.content_hash();
+                    (key, partials.get(key), prompt)
+                })
+                .collect()
+        };
+
+        let openai = self.openai.clone();
+        let section = first.clone();
+        let (message, summarized) = run_blocking(move || {
+            Ok(summarize_with_partials(
+                sections,
+                |prompt| openai.send_checked_prompt(prompt),
+                |parts| openai.send_checked_prompt(openai.prepare_merge_prompt(&section, &parts)),
+            ))
+        })
+        .await?;
+        {
+            let mut partials = self.partials.lock().await;
+            for (key, summary) in summarized {
+                partials.insert(key, summary);
+            }
+        }
+        let message = self.redact(message?);
+        let text = decorate(&self.summary_template, &first, &message, message_count);
+        let text = self.truncate(recipient, text).await;
+        let feedback = self.feedback_id(recipient, &first, message_count).await?;
+        let sent = self.send_parts(recipient, &text, feedback).await?;
+        self.remember_source(recipient, &first, sent).await?;
+        Ok(message)
+    }
+
+    // Truncates long responses in DM if configured, the rest is shown on /more.
+    async fn render(&self, recipient: &Chat, prompt: &Prompt, message: &str) -> String {
+        let text = decorate(
+            &self.summary_template,
+            prompt,
+            message,
+            prompt.message_count(),
+        );
+        self.truncate(recipient, text).await
+    }
+
+    async fn truncate(&self, recipient: &Chat, text: String) -> String {
+        match (recipient, self.max_response_length) {
+            (Chat::User(user), Some(max_length)) => {
+                self.remainders
+                    .lock()
+                    .await
+                    .truncate(user.id(), &text, max_length)
+            }
+            _ => text,
+        }
+    }
+
+    async fn more(&self, recipient: Chat) -> Result<CommandResult> {
+        let part = match self.max_response_length {
+            Some(max_length) => self
+                .remainders
+                .lock()
+                .await
+                .more(recipient.id(), max_length),
+            None => None,
+        };
+        let Some(part) = part else {
+            return Err(BotError::UserFacing("Nothing more to show.".to_string()));
+        };
+
+        for part in split_message(&part) {
+            self.client.send_message(&recipient, part).await?;
         }
+        Ok(CommandResult {
+            new_commands: vec![],
+        })
     }
 
     async fn ask_on_summary(
@@ -188,20 +1916,290 @@ impl Processor {
         question: String,
         message_count: u32,
         gpt_length: GPTLenght,
-    ) -> anyhow::Result<CommandResult> {
-        let messages = self.load_messages(&chat, message_count, None).await?;
+    ) -> Result<CommandResult> {
+        let messages = self
+            .load_messages(&chat, message_count, None, None, None)
+            .await?;
+        if messages.is_empty() {
+            return Err(no_messages_found(None));
+        }
+
+        let (config, thread) = {
+            let db = self.db.lock().await;
+            let since = unix_time(SystemTime::now() - consts::ASK_THREAD_TTL);
+            (
+                db.get_chat_config(chat.id())?,
+                db.get_ask_thread(chat.id(), recipient.id(), since)?,
+            )
+        };
+        let context = self.openai.prepare_question_prompt(
+            &messages,
+            &question,
+            &thread,
+            gpt_length,
+            config.ask_prompt.as_deref(),
+        );
+        // The sections of a long history are summarized before the question is asked, so they
+        // are charged here. The question is asked again on the first day they fit into.
+        if let QuestionContext::Summary(prompts) = &context {
+            let estimate = CostEstimate::of(prompts);
+            let now = unix_time(SystemTime::now());
+            let days = {
+                let db = self.db.lock().await;
+                match self.cost_budget.fits_in(&db, estimate.usd, now)? {
+                    Some(0) => self.cost_budget.spend(&db, estimate.usd, now)?,
+                    days => days,
+                }
+            };
+            match days {
+                None => return Err(over_budget(self.cost_budget, estimate)),
+                Some(0) => {}
+                Some(days) => {
+                    self.client
+                        .send_message(
+                            &recipient,
+                            deferred_message(self.cost_budget, estimate, days),
+                        )
+                        .await?;
+                    let command = Command::Ask {
+                        chat,
+                        recipient,
+                        question,
+                        message_count,
+                        gpt_length,
+                    };
+                    return Ok(CommandResult {
+                        new_commands: vec![Command::Deferred {
+                            until: cost::day_start(now, days),
+                            command: Box::new(command),
+                        }],
+                    });
+                }
+            }
+        }
+        let prompt = self
+            .question_prompt(
+                &recipient,
+                context,
+                &question,
+                &thread,
+                gpt_length,
+                config.ask_prompt.as_deref(),
+            )
+            .await?;
+        let prompt = prompt.with_thread(AskThread {
+            chat_id: chat.id(),
+            user_id: recipient.id(),
+            question,
+        });
+        Ok(CommandResult {
+            new_commands: vec![Command::SendPrompt { recipient, prompt }],
+        })
+    }
+
+    // Elaborates on the summary with the messages it was made from, the summary itself is
+    // loaded from the recipient's chat.
+    async fn elaborate(
+        &self,
+        chat: Chat,
+        recipient: Chat,
+        summary_message_id: i32,
+        instruction: String,
+        source: SummarySource,
+    ) -> Result<CommandResult> {
+        let summary = self
+            .client
+            .get_messages_by_id(&recipient, &[summary_message_id])
+            .await?
+            .into_iter()
+            .flatten()
+            .next()
+            .map(|message| message.text().to_string())
+            .filter(|summary| !summary.is_empty())
+            .ok_or_else(|| {
+                BotError::UserFacing("The summary is no longer available".to_string())
+            })?;
+        let window = MessageWindow::Between(source.first_message_id, source.last_message_id);
+        let messages = self
+            .load_messages(&chat, consts::MESSAGE_TO_STORE, None, None, Some(window))
+            .await?;
         if messages.is_empty() {
+            return Err(no_messages_found(None));
+        }
+
+        let context = self.openai.prepare_elaboration_prompt(
+            &messages,
+            &summary,
+            &instruction,
+            GPTLenght::Medium,
+        );
+        let prompt = self
+            .question_prompt(
+                &recipient,
+                context,
+                &instruction,
+                &[],
+                GPTLenght::Medium,
+                None,
+            )
+            .await?;
+        Ok(CommandResult {
+            new_commands: vec![Command::SendPrompt { recipient, prompt }],
+        })
+    }
+
+    // Messages exceeding the /ask context are summarized first, the recipient is told about it
+    // and about the messages that didn't fit.
+    async fn question_prompt(
+        &self,
+        recipient: &Chat,
+        context: QuestionContext,
+        question: &str,
+        thread: &[(String, String)],
+        gpt_length: GPTLenght,
+        ask_prompt: Option<&str>,
+    ) -> Result<Prompt> {
+        let (prompt, dropped) = match context {
+            QuestionContext::Messages { prompt, dropped } => {
+                (prompt.map(|prompt| *prompt), dropped)
+            }
+            QuestionContext::Summary(prompts) => {
+                self.client
+                    .send_message(
+                        recipient,
+                        "The messages exceed the /ask context limit, the answer is based on their summary.",
+                    )
+                    .await?;
+                let openai = self.openai.clone();
+                let summary = run_blocking(move || {
+                    summarize_sections(prompts, |prompt| openai.send_checked_prompt(prompt))
+                })
+                .await?;
+                let prompt = self.openai.prepare_condensed_question_prompt(
+                    &summary, question, thread, gpt_length, ask_prompt,
+                );
+                (prompt, 0)
+            }
+        };
+        let Some(prompt) = prompt else {
+            return Err(BotError::UserFacing(
+                "The question is too long, please make it shorter.".to_string(),
+            ));
+        };
+        if dropped > 0 {
             self.client
-                .send_message(recipient, "No messages found")
+                .send_message(
+                    recipient,
+                    format!(
+                        "The messages don't fit into the model context, the oldest {dropped} of them were skipped."
+                    ),
+                )
                 .await?;
-            return Ok(CommandResult {
-                new_commands: vec![],
-            });
         }
+        Ok(prompt)
+    }
 
-        let prompt = self
+    async fn follow_up(
+        &self,
+        recipient: Chat,
+        question: String,
+        gpt_length: GPTLenght,
+    ) -> Result<CommandResult> {
+        let context = self.dm_context.lock().await.recall(recipient.id());
+        if context.is_empty() {
+            return Err(BotError::UserFacing(
+                "Nothing to follow up on. Forward a message to summarize first.".to_string(),
+            ));
+        }
+
+        let prompts = self
+            .openai
+            .prepare_follow_up_prompt(&context, &question, gpt_length)
+            .into_iter()
+            .map(|prompt| -> Command {
+                Command::SendPrompt {
+                    recipient: recipient.clone(),
+                    prompt,
+                }
+            })
+            .collect();
+        Ok(CommandResult {
+            new_commands: prompts,
+        })
+    }
+
+    async fn link_digest(
+        &self,
+        chat: Chat,
+        recipient: Chat,
+        message_count: u32,
+    ) -> Result<CommandResult> {
+        let language = self
+            .db
+            .lock()
+            .await
+            .get_chat_config(chat.id())?
+            .forced_language;
+        let messages = self
+            .load_messages(&chat, message_count, None, None, None)
+            .await?
+            .into_iter()
+            .filter(|message| has_link(message.text()))
+            .collect::<Vec<_>>();
+        if messages.is_empty() {
+            return Err(BotError::UserFacing(format!(
+                "No links found in the last {message_count} messages"
+            )));
+        }
+
+        let prompts = self
+            .openai
+            .prepare_link_digest(&messages, language.as_deref())
+            .into_iter()
+            .map(|prompt| -> Command {
+                Command::SendPrompt {
+                    recipient: recipient.clone(),
+                    prompt,
+                }
+            })
+            .collect();
+        Ok(CommandResult {
+            new_commands: prompts,
+        })
+    }
+
+    async fn multi_digest(
+        &self,
+        chats: Vec<Chat>,
+        recipient: Chat,
+        message_count: u32,
+        gpt_length: GPTLenght,
+    ) -> Result<CommandResult> {
+        let mut chat_messages = Vec::with_capacity(chats.len());
+        let mut languages = HashSet::new();
+        for chat in chats {
+            let config = self.db.lock().await.get_chat_config(chat.id())?;
+            languages.insert(config.forced_language);
+            let messages = self
+                .load_messages(&chat, message_count, None, None, None)
+                .await?;
+            if !messages.is_empty() {
+                chat_messages.push((chat.name().to_string(), messages));
+            }
+        }
+
+        if chat_messages.is_empty() {
+            return Err(no_messages_found(None));
+        }
+
+        // Written in a forced language only if all the chats force the same one.
+        let language = match languages.len() {
+            1 => languages.into_iter().next().flatten(),
+            _ => None,
+        };
+        let prompts = self
             .openai
-            .prepare_question_prompt(&messages, &question, gpt_length)
+            .prepare_multi_chat_summary(&chat_messages, gpt_length, language.as_deref())
             .into_iter()
             .map(|prompt| -> Command {
                 Command::SendPrompt {
@@ -211,7 +2209,7 @@ impl Processor {
             })
             .collect();
         Ok(CommandResult {
-            new_commands: prompt,
+            new_commands: prompts,
         })
     }
 
@@ -221,28 +2219,54 @@ impl Processor {
         recipient: Chat,
         message_id: i32,
         gpt_length: GPTLenght,
-    ) -> anyhow::Result<CommandResult> {
+        scope: &RequestScope,
+    ) -> Result<CommandResult> {
+        let (opted_out, language) = {
+            let db = self.db.lock().await;
+            let language = db.get_chat_config(chat.id())?.forced_language;
+            (db.get_opted_out_users(chat.id())?, language)
+        };
         let message = self
             .client
             .get_messages_by_id(&chat, &[message_id])
             .await?
             .into_iter()
             .flatten()
+            .filter(|message| summarizable(message.sender().map(|sender| sender.id()), &opted_out))
             .collect::<Vec<_>>();
         let mut commands = vec![];
+        let mut media_queued = false;
 
         if let [message, ..] = message.as_slice() {
+            let attribution = forward_attribution(message);
             if let Some(media) = message.media() {
-                commands.extend(
-                    self.process_media(message, media, recipient.clone(), gpt_length)
-                        .await?,
-                );
+                match self
+                    .queue_media(
+                        message.clone(),
+                        media,
+                        recipient.clone(),
+                        gpt_length,
+                        attribution.clone(),
+                        scope.clone(),
+                    )
+                    .await
+                {
+                    Ok(()) => media_queued = true,
+                    // The text can still be summarized, so only report the media failure.
+                    Err(e) if !message.text().is_empty() => {
+                        log::log!(e.log_level(), "Failed to queue media: {e}");
+                        self.client
+                            .send_message(&recipient, e.user_message())
+                            .await?;
+                    }
+                    Err(e) => return Err(e),
+                }
             }
 
             if !message.text().is_empty() {
                 let prompt = self
                     .openai
-                    .prepare_text_summary(message.text(), gpt_length)
+                    .prepare_text_summary(message.text(), gpt_length, language.as_deref())
                     .into_iter()
                     .map(|prompt| -> Command {
                         Command::SendPrompt {
@@ -252,115 +2276,365 @@ impl Processor {
                     });
                 commands.extend(prompt);
             }
-        }
-
-        if commands.is_empty() {
+
+            if let Some(attribution) = attribution {
+                commands = commands
+                    .into_iter()
+                    .map(|command| command.with_attribution(&attribution))
+                    .collect();
+            }
+        }
+
+        if commands.is_empty() && !media_queued {
+            return Err(BotError::UserFacing(
+                "No messages found. Please be aware that messages from bots are not available."
+                    .to_string(),
+            ));
+        }
+
+        Ok(CommandResult {
+            new_commands: commands,
+        })
+    }
+
+    // Media is processed in the background by the media jobs, its summary is queued for the request
+    // once it's ready. Its failures are handled like the failures of the command. The request isn't
+    // done until the job is.
+    async fn queue_media(
+        &self,
+        message: Message,
+        media: Media,
+        recipient: Chat,
+        gpt_length: GPTLenght,
+        attribution: Option<String>,
+        scope: RequestScope,
+    ) -> Result<()> {
+        self.features.check_media()?;
+        check_transcriber(self.media.transcriber.as_ref(), self.media.outbound_media)?;
+        let config = self.db.lock().await.get_chat_config(message.chat().id())?;
+        let worker = self.media.clone();
+        let queue = self.queue.clone();
+        let tracker = self.tracker.clone();
+        let cost_budget = self.cost_budget;
+        let job_recipient = recipient.clone();
+        let RequestScope {
+            id: request,
+            retries,
+        } = scope;
+        tracker.requests.lock().await.fork(request);
+        let spawned = self.media_jobs.spawn(async move {
+            let result = match worker
+                .process_media(
+                    &message,
+                    media,
+                    job_recipient.clone(),
+                    gpt_length,
+                    &config,
+                    &retries,
+                )
+                .await
+            {
+                Ok(commands) => {
+                    let commands = commands
+                        .into_iter()
+                        .map(|command| {
+                            let command = command.with_retry_budget(&retries);
+                            match &attribution {
+                                Some(attribution) => command.with_attribution(attribution),
+                                None => command,
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let place = queue.read().await.waiting() + 1;
+                    charge(cost_budget, &tracker.db, &tracker.client, place, commands).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(commands) => {
+                    let priority = commands.first().map_or(Priority::Normal, Queued::priority);
+                    let follow_ups = follow_ups(
+                        &mut *tracker.requests.lock().await,
+                        request,
+                        priority,
+                        commands,
+                    );
+                    queue.write().await.extend(follow_ups);
+                    tracker.finish(request, false).await;
+                }
+                Err(e) => {
+                    log::log!(e.log_level(), "Failed to process media: {e}");
+                    // Failed like the command that queued the job, so it's retried from the start.
+                    let command = Command::SummarizeMessage {
+                        chat: message.chat(),
+                        recipient: job_recipient,
+                        message_id: message.id(),
+                        gpt_length,
+                    };
+                    tracker.fail(command, &e).await;
+                    tracker.finish(request, true).await;
+                }
+            }
+        });
+        let place = match spawned {
+            Ok(place) => place,
+            Err(e) => {
+                // The job won't run, the request is still held by its command.
+                self.tracker.requests.lock().await.finish(request, false);
+                return Err(e);
+            }
+        };
+        if let Some(place) = place {
             self.client
-                .send_message(recipient, "No messages found. Please be aware that messages from bots are not available.")
+                .send_message(
+                    &recipient,
+                    format!("The media is queued, it's number {place} in line."),
+                )
                 .await?;
         }
-
-        Ok(CommandResult {
-            new_commands: commands,
-        })
+        Ok(())
     }
+}
 
+impl MediaWorker {
     async fn process_media(
         &self,
         message: &Message,
         media: Media,
         recipient: Chat,
         gpt_length: GPTLenght,
-    ) -> anyhow::Result<Vec<Command>> {
+        // The whisper prompt and the forced language of the chat.
+        config: &ChatConfig,
+        retries: &RetryBudget,
+    ) -> Result<Vec<Command>> {
+        let whisper_prompt = config.whisper_prompt.as_deref();
+        let language = config.forced_language.as_deref();
         match media {
-            Media::Document(document)
-                if document.mime_type().map(|s| {
-                    let mime: Result<Mime, _> = s.parse();
-                    if let Ok(mime) = mime {
-                        mime.type_() == mime::AUDIO || mime.type_() == mime::VIDEO
-                    } else {
-                        false
-                    }
-                }) == Some(true) =>
-            {
-                // Checked above
-                log::info!("Downloading media");
-                let mime: Mime = document.mime_type().unwrap().parse().unwrap();
-                let extension = mime.subtype().as_str();
-                let is_video = mime.type_() == mime::VIDEO;
-                let save_path = format!("{}/{}.{}", consts::MEDIA_DIR, message.id(), extension);
-                let downloaded = message.download_media(&save_path).await?;
-                if !downloaded {
-                    self.client
-                        .send_message(recipient, "Failed to download media")
-                        .await?;
-                    return Ok(vec![]);
-                }
-
-                let file = if is_video {
-                    log::info!("Converting video to audio");
-                    let destination = format!("{}/{}.mp3", consts::MEDIA_DIR, message.id());
-                    if !tokio::process::Command::new("ffmpeg")
-                        .args([
-                            "-i",
-                            &save_path,
-                            "-vn",
-                            "-acodec",
-                            "libmp3lame",
-                            "-b:a",
-                            "128k",
-                            &destination,
-                        ])
-                        .status()
-                        .await?
-                        .success()
-                    {
-                        self.client
-                            .send_message(recipient, "Failed to convert video to audio")
-                            .await?;
-                        return Ok(vec![]);
-                    }
-                    destination
-                } else {
-                    save_path.clone()
-                };
-                log::info!("Converting audio to text");
-                let text = self.openai.audio_to_text(&file)?;
-
-                // Remove the file
-                tokio::fs::remove_file(&file).await?;
-                if is_video {
-                    tokio::fs::remove_file(&save_path).await?;
-                }
+            Media::Document(document) => {
+                let mime_type = document.mime_type().unwrap_or("unknown");
+                let route = self.media_types.route(mime_type)?;
+                let transcript =
+                    cached_transcript(&self.db, document.id(), SystemTime::now(), || {
+                        self.download_and_transcribe(
+                            message,
+                            mime_type,
+                            route,
+                            whisper_prompt,
+                            retries,
+                        )
+                    })
+                    .await?;
 
                 log::info!("Summarizing transcribed text");
-                if let Some(text) = text.text {
-                    let result = self
-                        .openai
-                        .prepare_text_summary(&text, gpt_length)
-                        .into_iter()
-                        .map(|prompt| Command::SendPrompt {
-                            recipient: recipient.clone(),
-                            prompt,
-                        })
-                        .collect();
-                    Ok(result)
+                if let Some((text, recording)) = transcript {
+                    let prompts = if recording {
+                        self.openai
+                            .prepare_recording_summary(&text, gpt_length, language)
+                    } else {
+                        self.openai
+                            .prepare_text_summary(&text, gpt_length, language)
+                    };
+                    let prompts = with_model(prompts, self.summary_model.as_deref());
+                    Ok(summary_commands(&recipient, prompts))
                 } else {
-                    self.client
-                        .send_message(recipient, "Failed to transcribe audio")
-                        .await?;
-                    Ok(vec![])
+                    Err(BotError::Media("Failed to transcribe audio".to_string()))
                 }
             }
-            _ => {
-                self.client
-                    .send_message(recipient, "Unsupported media type")
-                    .await?;
-                Ok(vec![])
-            }
+            Media::Photo(_) => Err(BotError::UserFacing(
+                "Unsupported media type: photo".to_string(),
+            )),
+            Media::Sticker(_) => Err(BotError::UserFacing(
+                "Unsupported media type: sticker".to_string(),
+            )),
+            _ => Err(BotError::UserFacing("Unsupported media type".to_string())),
+        }
+    }
+
+    // The transcript of the media and whether it's a long recording transcribed in chunks.
+    async fn download_and_transcribe(
+        &self,
+        message: &Message,
+        mime_type: &str,
+        route: MediaRoute,
+        whisper_prompt: Option<&str>,
+        retries: &RetryBudget,
+    ) -> Result<Option<(String, bool)>> {
+        // Checked by the routing
+        let mime: Mime = mime_type.parse().unwrap();
+        log::info!("Downloading media");
+        let extension = mime.subtype().as_str();
+        let is_video = route == MediaRoute::Video;
+        let save_path = format!("{}/{}.{}", consts::MEDIA_DIR, message.id(), extension);
+        let files = MediaFiles::new(&save_path);
+        download_with_retries(&save_path, self.media_download, retries, || async {
+            message
+                .download_media(&save_path)
+                .await
+                .map_err(|e| BotError::Media(format!("Failed to download media: {e}")))
+        })
+        .await?;
+
+        transcribe_media(
+            &self.transcriber,
+            files,
+            &save_path,
+            is_video,
+            whisper_prompt,
+            retries,
+        )
+        .await
+    }
+}
+
+// Converts the downloaded media if the transcriber needs it and transcribes it. The files are
+// removed when it returns, whether the transcription succeeded or not.
+async fn transcribe_media(
+    transcriber: &Arc<dyn Transcriber>,
+    mut files: MediaFiles,
+    save_path: &str,
+    is_video: bool,
+    whisper_prompt: Option<&str>,
+    retries: &RetryBudget,
+) -> Result<Option<(String, bool)>> {
+    let conversion = transcriber.audio_format().conversion(is_video);
+    let file = if let Some((extension, options)) = conversion {
+        log::info!("Converting media to {extension}");
+        let destination = converted_file(save_path, extension);
+        files.track(&destination);
+        if !tokio::process::Command::new("ffmpeg")
+            .arg("-i")
+            .arg(save_path)
+            .args(options)
+            .arg(&destination)
+            .status()
+            .await
+            .map_err(|e| BotError::Media(format!("Failed to run ffmpeg: {e}")))?
+            .success()
+        {
+            return Err(BotError::Media(
+                "Failed to convert media to audio".to_string(),
+            ));
+        }
+        destination
+    } else {
+        save_path.to_string()
+    };
+    log::info!("Converting audio to text with {}", transcriber.name());
+    let duration = media_duration(&file).await;
+    let recording = duration.filter(|duration| *duration > consts::RECORDING_CHUNK);
+    let size = tokio::fs::metadata(&file)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+    // Only uploads are limited in size.
+    let size_limit = transcriber
+        .uploads_audio()
+        .then_some(consts::WHISPER_FILE_LIMIT);
+    if duration.is_none() && size_limit.is_some_and(|limit| size > limit) {
+        log::warn!(
+            "Media of {size} bytes is over the upload limit, but its length is unknown to split it"
+        );
+    }
+    let chunk = duration.and_then(|duration| {
+        let chunk = chunk_length(
+            duration,
+            size,
+            size_limit,
+            consts::RECORDING_CHUNK,
+            consts::CHUNK_OVERLAP,
+        )?;
+        Some((duration, chunk))
+    });
+    let text = if let Some((duration, chunk)) = chunk {
+        log::info!(
+            "Transcribing {}s media of {size} bytes in {}s chunks",
+            duration.as_secs(),
+            chunk.as_secs()
+        );
+        let mut transcripts = transcribe_in_chunks(
+            transcriber,
+            &mut files,
+            &file,
+            duration,
+            chunk,
+            whisper_prompt,
+            retries,
+        )
+        .await?;
+        trim_overlaps(&mut transcripts);
+        if recording.is_some() {
+            combine_transcripts(&transcripts)
+        } else {
+            join_transcripts(&transcripts)
+        }
+    } else {
+        transcribe(transcriber, &file, whisper_prompt, retries).await?
+    };
+
+    Ok(text.map(|text| (text, recording.is_some())))
+}
+
+// `./media/42.mp4` is converted to `./media/42.converted.mp3`.
+fn converted_file(save_path: &str, extension: &str) -> String {
+    let stem = save_path
+        .rsplit_once('.')
+        .map_or(save_path, |(stem, _)| stem);
+    format!("{stem}.converted.{extension}")
+}
+
+async fn transcribe(
+    transcriber: &Arc<dyn Transcriber>,
+    file: &str,
+    prompt: Option<&str>,
+    retries: &RetryBudget,
+) -> Result<Option<String>> {
+    let transcriber = transcriber.clone();
+    let file = file.to_string();
+    let prompt = prompt.map(ToString::to_string);
+    let retries = retries.clone();
+    run_blocking(move || transcriber.transcribe(&file, prompt.as_deref(), &retries)).await
+}
+
+// Voice chat recordings and large media are too long for a single Whisper upload.
+async fn transcribe_in_chunks(
+    transcriber: &Arc<dyn Transcriber>,
+    files: &mut MediaFiles,
+    file: &str,
+    duration: Duration,
+    chunk_length: Duration,
+    prompt: Option<&str>,
+    retries: &RetryBudget,
+) -> Result<Vec<(Duration, Option<String>)>> {
+    let mut transcripts = vec![];
+    for (index, start) in chunk_starts(duration, chunk_length).into_iter().enumerate() {
+        let chunk = chunk_file(file, index);
+        files.track(&chunk);
+        if !tokio::process::Command::new("ffmpeg")
+            .arg("-ss")
+            .arg(start.as_secs().to_string())
+            .arg("-t")
+            .arg((chunk_length + consts::CHUNK_OVERLAP).as_secs().to_string())
+            .arg("-i")
+            .arg(file)
+            .args(["-c", "copy"])
+            .arg(&chunk)
+            .status()
+            .await
+            .map_err(|e| BotError::Media(format!("Failed to run ffmpeg: {e}")))?
+            .success()
+        {
+            return Err(BotError::Media("Failed to split the audio".to_string()));
         }
+
+        // Chunks are removed as they go, so a long recording doesn't take twice the space.
+        let text = transcribe(transcriber, &chunk, prompt, retries).await;
+        remove_media(&chunk).await;
+        transcripts.push((start, text?));
     }
+    Ok(transcripts)
+}
 
+impl Processor {
     async fn prepare_summary_prompt(
         &self,
         chat: Chat,
@@ -368,40 +2642,105 @@ impl Processor {
         message_count: u32,
         gpt_length: GPTLenght,
         mentioned_by_user: Option<String>,
-    ) -> anyhow::Result<CommandResult> {
+        options: SummaryOptions,
+    ) -> Result<CommandResult> {
         log::info!("Proccessing summarize command");
         let chat = &chat;
 
+        let (stored, to_fetch, opted_out) = {
+            let db = self.db.lock().await;
+            let (message_ids, opted_out) = load_message_ids(
+                &db,
+                chat.id(),
+                options.forum_topic,
+                message_count,
+                options.within,
+                options
+                    .around
+                    .map(|(message_id, radius)| MessageWindow::Around(message_id, radius))
+                    .or(options
+                        .period
+                        .map(|(start, end)| MessageWindow::Period(start, end))),
+            )?;
+            // Sampling and reactions need the messages themselves, otherwise only the messages
+            // without a stored text, like media, are fetched.
+            if self.sampling.is_none() && !self.openai.includes_reactions() {
+                let (stored, to_fetch) = split_stored(
+                    &db,
+                    chat.id(),
+                    &message_ids,
+                    &opted_out,
+                    mentioned_by_user.as_deref(),
+                )?;
+                (stored, to_fetch, opted_out)
+            } else {
+                (vec![], message_ids, opted_out)
+            }
+        };
         let messages = self
-            .load_messages(chat, message_count, mentioned_by_user)
+            .fetch_messages(chat, &to_fetch, &opted_out, mentioned_by_user.as_deref())
             .await?;
+        if messages.is_empty() && stored.is_empty() && options.period.is_some() {
+            return Err(BotError::UserFacing(
+                "No messages were stored for that day".to_string(),
+            ));
+        }
+        if messages.is_empty() && stored.is_empty() {
+            if let (Some(user), Some(within)) = (&mentioned_by_user, options.within) {
+                return Err(BotError::UserFacing(format!(
+                    "@{user} said nothing in the last {}",
+                    format_window(within)
+                )));
+            }
+        }
+        let messages =
+            filter_by_topic(messages, options.topic.as_deref(), |message| message.text());
+        let stored = filter_by_topic(stored, options.topic.as_deref(), |message| {
+            message.text.as_str()
+        });
 
-        if messages.is_empty() {
+        if messages.is_empty() && stored.is_empty() {
+            return Err(no_messages_found(options.topic.as_deref()));
+        }
+        let total = messages.len();
+        let messages = match self.sampling {
+            Some(sampling) => sampling.sample(messages, engagement),
+            None => messages,
+        };
+        if messages.len() < total {
             self.client
-                .send_message(recipient, "No messages found")
+                .send_message(
+                    &recipient,
+                    format!(
+                        "The summary is based on a sample of {} of the {total} messages.",
+                        messages.len()
+                    ),
+                )
                 .await?;
-            return Ok(CommandResult {
-                new_commands: vec![],
-            });
         }
 
         log::info!(
-            "Creating prompts for summarization within {} messages",
-            messages.len()
+            "Creating prompts for summarization within {} messages, {} of them stored",
+            messages.len() + stored.len(),
+            stored.len()
         );
+        let lines = messages
+            .iter()
+            .map(|message| (message.id(), self.openai.summary_line(message)))
+            .chain(stored.into_iter().map(|message| {
+                let username = message.sender_username.unwrap_or_default();
+                (message.message_id, (username, message.text))
+            }))
+            .collect::<Vec<_>>();
+        let source = SummarySource::of(chat.id(), lines.iter().map(|(message_id, _)| *message_id));
         let prompts = self
             .openai
-            .prepare_summarize_prompts_from_messages(&messages, gpt_length)
+            .prepare_summarize_prompts_from_lines(lines, gpt_length, &options)
             .into_iter()
-            .map(|prompt| -> Command {
-                Command::SendPrompt {
-                    recipient: recipient.clone(),
-                    prompt,
-                }
-            })
-            .collect();
+            .map(|prompt| prompt.with_source(source))
+            .collect::<Vec<_>>();
         Ok(CommandResult {
-            new_commands: prompts,
+            new_commands: summary_commands(&recipient, prompts),
         })
     }
 
@@ -409,14 +2748,30 @@ impl Processor {
         &self,
         chat: &Chat,
         message_count: u32,
-        mentioned_by_user: Option<String>,
-    ) -> anyhow::Result<Vec<Message>> {
-        let messages_id_to_load: Vec<i32> = self
-            .db
-            .lock()
+        mentioned_by_user: Option<&str>,
+        within: Option<Duration>,
+        window: Option<MessageWindow>,
+    ) -> Result<Vec<Message>> {
+        let (message_ids, opted_out) = load_message_ids(
+            &*self.db.lock().await,
+            chat.id(),
+            None,
+            message_count,
+            within,
+            window,
+        )?;
+        self.fetch_messages(chat, &message_ids, &opted_out, mentioned_by_user)
             .await
-            .get_messages_id(chat.id(), message_count)?;
-        let mut messages = Vec::with_capacity(messages_id_to_load.len() as usize);
+    }
+
+    async fn fetch_messages(
+        &self,
+        chat: &Chat,
+        messages_id_to_load: &[i32],
+        opted_out: &[i64],
+        mentioned_by_user: Option<&str>,
+    ) -> Result<Vec<Message>> {
+        let mut messages = Vec::with_capacity(messages_id_to_load.len());
         for i in 0..(messages_id_to_load.len() / consts::TELEGRAM_MAX_MESSAGE_FETCH + 1) {
             let minimum = i * consts::TELEGRAM_MAX_MESSAGE_FETCH;
             let maximum =
@@ -433,15 +2788,11 @@ impl Processor {
                 .into_iter()
                 .flatten()
                 .filter(|message| {
-                    if let Some(mentioned_by_user) = mentioned_by_user.as_ref() {
-                        if let Some(Chat::User(user)) = message.sender() {
-                            if user.username() == Some(mentioned_by_user) {
-                                return true;
-                            }
-                        }
-                        return false;
-                    }
-                    true
+                    summarizable(message.sender().map(|sender| sender.id()), opted_out)
+                })
+                .filter(|message| match message.sender() {
+                    Some(Chat::User(user)) => sent_by(user.username(), mentioned_by_user),
+                    _ => mentioned_by_user.is_none(),
                 })
                 .collect::<Vec<_>>();
             messages.extend(fetched_messages);
@@ -449,3 +2800,727 @@ impl Processor {
         Ok(messages)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai::queue::tests::TestCommand;
+    use crate::openai::transcription::{AudioFormat, LocalTranscriber};
+
+    #[tokio::test]
+    async fn restored_request_is_done_with_its_last_command() {
+        let db = Mutex::new(Db::new_with_file(":memory:").unwrap());
+        let pending_id = db
+            .lock()
+            .await
+            .add_pending_command(Some(1), Some(1), "{}", None)
+            .unwrap();
+        let requests = Mutex::new(Requests::new());
+
+        let command = TestCommand { chat_id: 1, id: 1 };
+        let tracked = track(&mut *requests.lock().await, command, Some(pending_id), None);
+        // The command queues a media job and two chunks of the summary.
+        requests.lock().await.fork(tracked.request);
+        let chunks = follow_ups(
+            &mut *requests.lock().await,
+            tracked.request,
+            tracked.priority,
+            vec![
+                TestCommand { chat_id: 1, id: 2 },
+                TestCommand { chat_id: 1, id: 3 },
+            ],
+        );
+        assert_eq!(chunks.len(), 2);
+        let stored = || async { db.lock().await.get_pending_commands().unwrap().len() };
+
+        for _ in 0..3 {
+            let finished = finish_request(&requests, &db, tracked.request, false).await;
+            assert!(finished.is_none());
+            assert_eq!(stored().await, 1);
+        }
+        let finished = finish_request(&requests, &db, tracked.request, false).await;
+        assert!(finished.is_some_and(|(request, succeeded)| {
+            request.pending_id == Some(pending_id) && succeeded
+        }));
+        assert_eq!(stored().await, 0);
+    }
+
+    #[tokio::test]
+    async fn cancel_drops_only_the_recipients_requests() {
+        let db = Mutex::new(Db::new_with_file(":memory:").unwrap());
+        let requests = Mutex::new(Requests::new());
+        let queue = RwLock::new(CommandQueue::new());
+        // The chat of the test commands stands for their recipient.
+        let mut pending_ids = vec![];
+        for (recipient_id, id) in [(1, 1), (2, 2)] {
+            let pending_id = db
+                .lock()
+                .await
+                .add_pending_command(Some(10), Some(recipient_id), "{}", None)
+                .unwrap();
+            pending_ids.push(pending_id);
+            let command = TestCommand {
+                chat_id: recipient_id,
+                id,
+            };
+            let tracked = track(&mut *requests.lock().await, command, Some(pending_id), None);
+            // Each request is split into two prompts.
+            let prompts = follow_ups(
+                &mut *requests.lock().await,
+                tracked.request,
+                tracked.priority,
+                vec![
+                    TestCommand {
+                        chat_id: recipient_id,
+                        id: id * 10,
+                    },
+                    TestCommand {
+                        chat_id: recipient_id,
+                        id: id * 10 + 1,
+                    },
+                ],
+            );
+            finish_request(&requests, &db, tracked.request, false).await;
+            queue.write().await.extend(prompts);
+        }
+
+        let (cancelled, dropped) =
+            cancel_requests(&queue, &requests, &db, |command| command.chat_id == 1).await;
+        assert_eq!(cancelled, 1);
+        assert_eq!(
+            dropped
+                .iter()
+                .map(|tracked| tracked.command.id)
+                .collect::<Vec<_>>(),
+            vec![10, 11]
+        );
+        let stored = db.lock().await.get_pending_commands().unwrap();
+        assert_eq!(
+            stored.iter().map(|(id, ..)| *id).collect::<Vec<_>>(),
+            vec![pending_ids[1]]
+        );
+        let left = queue.write().await.cancel(|_| true);
+        assert_eq!(
+            left.iter()
+                .map(|tracked| tracked.command.id)
+                .collect::<Vec<_>>(),
+            vec![20, 21]
+        );
+    }
+
+    #[test]
+    fn retry_queues_the_latest_failed_command() {
+        let mut failed_commands = FailedCommands::new();
+        let first = TestCommand { chat_id: 1, id: 1 };
+        let latest = TestCommand { chat_id: 2, id: 2 };
+        failed_commands.remember(Some(first.chat_id), 7, first.clone());
+        failed_commands.remember(Some(latest.chat_id), 7, latest.clone());
+
+        let mut queue = CommandQueue::new();
+        let mut requests = Requests::new();
+        for expected in [latest, first] {
+            let command = retry_failed(&mut failed_commands, 7).unwrap();
+            queue.push(track(&mut requests, command, None, None));
+            assert_eq!(queue.pop_ready().unwrap().command, expected);
+        }
+        assert!(matches!(
+            retry_failed(&mut failed_commands, 7),
+            Err(BotError::UserFacing(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn removed_chat_requests_are_cancelled() {
+        let db = Mutex::new(Db::new_with_file(":memory:").unwrap());
+        let requests = Mutex::new(Requests::new());
+        let queue = RwLock::new(CommandQueue::new());
+        let mut tracked = vec![];
+        for (chat_id, id) in [(1, 1), (2, 2), (1, 3)] {
+            let pending_id = db
+                .lock()
+                .await
+                .add_pending_command(Some(chat_id), None, "{}", None)
+                .unwrap();
+            let command = TestCommand { chat_id, id };
+            tracked.push(track(
+                &mut *requests.lock().await,
+                command,
+                Some(pending_id),
+                None,
+            ));
+        }
+        queue.write().await.extend(tracked);
+        // The first command of the chat is running.
+        let running = queue.write().await.pop_ready().unwrap();
+        assert_eq!(running.command.id, 1);
+
+        let (cancelled, dropped) =
+            cancel_requests(&queue, &requests, &db, |command| command.targets_chat(1)).await;
+        assert_eq!(cancelled, 2);
+        assert_eq!(
+            dropped
+                .iter()
+                .map(|tracked| tracked.command.id)
+                .collect::<Vec<_>>(),
+            vec![3]
+        );
+        let stored = db.lock().await.get_pending_commands().unwrap();
+        assert_eq!(stored.len(), 1);
+
+        // The running command doesn't queue anything else for its request.
+        let left = follow_ups(
+            &mut *requests.lock().await,
+            running.request,
+            running.priority,
+            vec![TestCommand { chat_id: 1, id: 4 }],
+        );
+        assert!(left.is_empty());
+        for request in [running.request, dropped[0].request] {
+            let finished = finish_request(&requests, &db, request, false).await;
+            assert!(finished.is_some_and(|(_, succeeded)| !succeeded));
+        }
+        queue.write().await.finish(running.ordering_key());
+        let next = queue.write().await.pop_ready().unwrap();
+        assert_eq!(next.command, TestCommand { chat_id: 2, id: 2 });
+        assert!(queue.write().await.pop_ready().is_none());
+    }
+
+    #[test]
+    fn messages_are_filtered_by_topic() {
+        let messages = vec![
+            "We deploy on Monday",
+            "Lunch?",
+            "DEPLOYMENT failed again",
+            "The release notes are ready",
+        ];
+
+        let filtered = filter_by_topic(messages.clone(), Some("deploy"), |message| message);
+        assert_eq!(
+            filtered,
+            vec!["We deploy on Monday", "DEPLOYMENT failed again"]
+        );
+
+        let filtered = filter_by_topic(messages.clone(), Some("lunch, release"), |message| message);
+        assert_eq!(filtered, vec!["Lunch?", "The release notes are ready"]);
+
+        assert_eq!(
+            filter_by_topic(messages.clone(), None, |message| message),
+            messages
+        );
+    }
+
+    #[test]
+    fn opted_out_messages_are_not_summarized() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let messages = [
+            (1, Some(10), "We deploy on Monday"),
+            (2, Some(20), "My address is Khreshchatyk 1"),
+            (3, None, "Channel post about the release"),
+            (4, Some(30), "I'll prepare the release notes"),
+        ];
+        for (message_id, sender_id, text) in messages {
+            db.add_message_id(1, message_id).unwrap();
+            db.add_message_text(1, message_id, sender_id, None, text)
+                .unwrap();
+        }
+        db.set_opted_out(1, 20, true).unwrap();
+
+        let (message_ids, opted_out) = load_message_ids(&db, 1, None, 10, None, None).unwrap();
+        assert_eq!(opted_out, vec![20]);
+        let (stored, to_fetch) = split_stored(&db, 1, &message_ids, &opted_out, None).unwrap();
+        assert_eq!(
+            stored
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "I'll prepare the release notes",
+                "Channel post about the release",
+                "We deploy on Monday"
+            ]
+        );
+        // The text was dropped on opting out, the fetched message is left out by its sender.
+        assert_eq!(to_fetch, vec![2]);
+        assert!(!summarizable(Some(20), &opted_out));
+
+        db.set_opted_out(1, 20, false).unwrap();
+        let (_, opted_out) = load_message_ids(&db, 1, None, 10, None, None).unwrap();
+        assert!(summarizable(Some(20), &opted_out));
+    }
+
+    #[test]
+    fn summary_reads_stored_texts_without_deleted_messages() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in 1..=5 {
+            db.add_message_id(1, message_id).unwrap();
+        }
+        db.add_message_text(1, 1, Some(10), Some("alice"), "Deploy on Monday")
+            .unwrap();
+        db.add_message_text(1, 2, Some(11), Some("bob"), "Monday is a holiday")
+            .unwrap();
+        db.add_message_text(1, 3, Some(11), Some("bob"), "Let's do Tuesday")
+            .unwrap();
+        db.add_message_text(1, 4, Some(10), Some("alice"), "Tuesday works")
+            .unwrap();
+        // Bob edits his last message and deletes the one before it, message 5 is media.
+        db.add_message_text(1, 3, Some(11), Some("bob"), "Let's do Wednesday")
+            .unwrap();
+        assert_eq!(db.remove_messages(1, &[2]).unwrap(), 1);
+        // Alice edits hers while texts aren't stored, it's fetched again.
+        db.forget_message_text(1, 4).unwrap();
+
+        let (message_ids, opted_out) = load_message_ids(&db, 1, None, 10, None, None).unwrap();
+        assert_eq!(message_ids, vec![5, 4, 3, 1]);
+        let (stored, to_fetch) = split_stored(&db, 1, &message_ids, &opted_out, None).unwrap();
+        assert_eq!(
+            stored
+                .iter()
+                .map(|message| (message.message_id, message.text.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(3, "Let's do Wednesday"), (1, "Deploy on Monday")]
+        );
+        assert_eq!(to_fetch, vec![5, 4]);
+    }
+
+    #[test]
+    fn user_and_time_filters_compose() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in 3..=5 {
+            db.add_message_id(1, message_id).unwrap();
+        }
+        // Messages 1 and 2 were sent two days ago.
+        let two_days_ago = unix_time(SystemTime::now()) - 2 * 24 * 3600;
+        db.add_older_messages(1, &[(2, two_days_ago), (1, two_days_ago)], 10)
+            .unwrap();
+        for (message_id, sender) in [
+            (1, "alice"),
+            (2, "bob"),
+            (3, "alice"),
+            (4, "bob"),
+            (5, "alice"),
+        ] {
+            db.add_message_text(1, message_id, None, Some(sender), "Release notes")
+                .unwrap();
+        }
+
+        let within = Duration::from_secs(24 * 3600);
+        let selected = |user: Option<&str>| {
+            let (message_ids, opted_out) =
+                load_message_ids(&db, 1, None, consts::MESSAGE_TO_STORE, Some(within), None)
+                    .unwrap();
+            let (stored, to_fetch) = split_stored(&db, 1, &message_ids, &opted_out, user).unwrap();
+            assert!(to_fetch.is_empty());
+            stored
+                .iter()
+                .map(|message| message.message_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(selected(Some("alice")), vec![5, 3]);
+        assert_eq!(selected(Some("bob")), vec![4]);
+        assert_eq!(selected(None), vec![5, 4, 3]);
+        assert!(selected(Some("carol")).is_empty());
+        assert!(!sent_by(None, Some("alice")));
+        assert_eq!(format_window(within), "1d");
+        assert_eq!(format_window(Duration::from_secs(12 * 3600)), "12h");
+        assert_eq!(format_window(Duration::from_secs(30 * 60)), "30m");
+    }
+
+    #[test]
+    fn forwarded_channel_post_is_attributed() {
+        let attribution = channel_post_attribution(1234567890, 42, Some("Olena"));
+        assert_eq!(
+            attribution,
+            "📢 Forwarded from a channel post by Olena: https://t.me/c/1234567890/42"
+        );
+
+        let template = SummaryTemplate {
+            header: Some("Summary of {count} messages".to_string()),
+            footer: None,
+        };
+        let prompt = OpenAIClient::new(String::new())
+            .prepare_text_summary("The release is moved to Friday", GPTLenght::Short, None)
+            .remove(0);
+        assert_eq!(
+            decorate(&template, &prompt, "Release moved to Friday", 1),
+            "Summary of 1 messages\n\nRelease moved to Friday"
+        );
+
+        let prompt = prompt.with_attribution(channel_post_attribution(1234567890, 42, None));
+        assert_eq!(
+            decorate(&template, &prompt, "Release moved to Friday", 1),
+            "📢 Forwarded from a channel post: https://t.me/c/1234567890/42\n\nSummary of 1 messages\n\nRelease moved to Friday"
+        );
+    }
+
+    #[test]
+    fn overlapping_request_reuses_partial_summaries() {
+        let openai = OpenAIClient::new(String::new());
+        let text = |first: usize| {
+            (first..200)
+                .map(|i| {
+                    format!(
+                        "Message {i} {}.",
+                        "we should ship the release on monday ".repeat(300)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let mut partials = PartialSummaries::new(consts::PARTIAL_SUMMARIES_TTL);
+        let mut summarize = |prompts: Vec<Prompt>| {
+            let sections = prompts
+                .into_iter()
+                .map(|prompt| {
+                    let key = prompt.content_hash();
+                    (key, partials.get(key), prompt)
+                })
+                .collect();
+            let mut sent = 0;
+            let (summary, summarized) = summarize_with_partials(
+                sections,
+                |prompt| {
+                    sent += 1;
+                    Ok(format!("{} messages", prompt.message_count()))
+                },
+                |parts| Ok(parts.join("\n\n")),
+            );
+            for (key, partial) in summarized {
+                partials.insert(key, partial);
+            }
+            (summary.unwrap(), sent)
+        };
+
+        let newest = openai.prepare_text_summary(&text(50), GPTLenght::Short, None);
+        assert!(newest.len() > 2);
+        let (_, sent) = summarize(newest.clone());
+        assert_eq!(sent, newest.len());
+
+        // At least all chunks but the oldest one of the first request are shared.
+        let all = openai.prepare_text_summary(&text(0), GPTLenght::Short, None);
+        let shared = all
+            .iter()
+            .filter(|prompt| {
+                newest
+                    .iter()
+                    .any(|newer| newer.content_hash() == prompt.content_hash())
+            })
+            .count();
+        assert!(shared >= newest.len() - 1);
+        let (summary, sent) = summarize(all.clone());
+        assert_eq!(sent, all.len() - shared);
+        assert_eq!(
+            summary.matches(" messages").count(),
+            all.len(),
+            "every section is in the summary"
+        );
+        assert_eq!(all.iter().map(Prompt::message_count).sum::<usize>(), 200);
+    }
+
+    #[test]
+    fn transcript_prompts_use_the_media_model() {
+        let openai = OpenAIClient::new(String::new());
+        let prompts = with_model(
+            openai.prepare_text_summary(
+                "We agreed to move the release to Friday",
+                GPTLenght::Short,
+                None,
+            ),
+            Some("gpt-4o-mini"),
+        );
+        assert!(!prompts.is_empty());
+        assert!(prompts.iter().all(|prompt| prompt.model() == "gpt-4o-mini"));
+
+        let prompts = with_model(
+            openai.prepare_text_summary("The release is moved to Friday", GPTLenght::Short, None),
+            None,
+        );
+        assert_eq!(prompts[0].model(), "gpt-4o");
+    }
+
+    #[test]
+    fn media_isnt_uploaded_when_disabled() {
+        let openai = OpenAIClient::new(String::new());
+        let local = LocalTranscriber::new("whisper-cli".to_string(), "ggml-base.bin".to_string());
+
+        assert!(check_transcriber(&openai, true).is_ok());
+        let rejected = check_transcriber(&openai, false).unwrap_err();
+        assert_eq!(
+            rejected.user_message(),
+            "Media transcription is disabled on this instance"
+        );
+        // On-prem transcription keeps working.
+        assert!(check_transcriber(&local, false).is_ok());
+    }
+
+    #[test]
+    fn failed_section_doesnt_abort_the_summary() {
+        let sections = vec!["first", "second", "third"];
+        let summary = summarize_sections(sections.clone(), |section| {
+            if section == "second" {
+                return Err(BotError::openai("Rate limit reached"));
+            }
+            Ok(format!("Summary of the {section} part"))
+        })
+        .unwrap();
+        assert_eq!(
+            summary,
+            "Summary of the first part\n\nSummary of the third part\n\n(1 section could not be summarized)"
+        );
+
+        let summary = summarize_sections(sections.clone(), |section| Ok(section.to_string()));
+        assert_eq!(summary.unwrap(), "first\n\nsecond\n\nthird");
+
+        let summary = summarize_sections(sections, |_| -> Result<String> {
+            Err(BotError::openai("Rate limit reached"))
+        });
+        assert!(matches!(summary, Err(BotError::OpenAi(_))));
+    }
+
+    #[test]
+    fn section_summaries_are_merged() {
+        let sections = vec!["first", "second", "third"];
+        let summarize = |section: &str| -> Result<String> {
+            if section == "second" {
+                return Err(BotError::openai("Rate limit reached"));
+            }
+            Ok(format!("Summary of the {section} part"))
+        };
+        let mut merged = vec![];
+        let summary = merge_sections(sections.clone(), summarize, |parts| {
+            merged = parts;
+            Ok("Merged summary".to_string())
+        })
+        .unwrap();
+        assert_eq!(
+            summary,
+            "Merged summary\n\n(1 section could not be summarized)"
+        );
+        assert_eq!(
+            merged,
+            vec!["Summary of the first part", "Summary of the third part"]
+        );
+
+        // A single summary is sent as it is.
+        let summary = merge_sections(vec!["first"], summarize, |_| -> Result<String> {
+            panic!("a single section is merged")
+        });
+        assert_eq!(summary.unwrap(), "Summary of the first part");
+
+        // The joined summaries are better than nothing.
+        let summary = merge_sections(vec!["first", "third"], summarize, |_| {
+            Err(BotError::openai("Rate limit reached"))
+        });
+        assert_eq!(
+            summary.unwrap(),
+            "Summary of the first part\n\nSummary of the third part"
+        );
+
+        let openai = OpenAIClient::new(String::new());
+        let text = (0..200)
+            .map(|i| {
+                format!(
+                    "Message {i} {}.",
+                    "we should ship the release on monday ".repeat(300)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let prompts = openai.prepare_text_summary(&text, GPTLenght::Short, None);
+        assert!(prompts.len() > 1);
+        let merge = openai.prepare_merge_prompt(&prompts[0], &merged);
+        assert_eq!(merge.gpt_length(), GPTLenght::Short);
+        assert_eq!(merge.model(), prompts[0].model());
+        assert_eq!(merge.check_summary("A merged summary"), Ok(()));
+        let (input_tokens, _) = merge.estimated_tokens();
+        assert!(input_tokens < prompts[0].estimated_tokens().0 / 10);
+    }
+
+    #[test]
+    fn only_messages_with_links_are_kept() {
+        assert!(has_link("Release notes: https://example.com/releases/1.2"));
+        assert!(has_link("(see www.example.com)"));
+        assert!(!has_link("We should use https for the API"));
+        assert!(!has_link("Visit https:// later"));
+        assert!(!has_link("Deploy on Monday"));
+    }
+
+    #[test]
+    fn no_topic_matches() {
+        let messages = vec!["We deploy on Monday", "Lunch?"];
+        assert!(filter_by_topic(messages, Some("vacation"), |message| message).is_empty());
+        assert!(matches!(
+            no_messages_found(Some("vacation")),
+            BotError::UserFacing(_)
+        ));
+        assert_eq!(
+            no_messages_found(Some("vacation")).user_message(),
+            "No messages about \"vacation\" found. Try another keyword or more messages."
+        );
+        assert_eq!(no_messages_found(None).user_message(), "No messages found");
+    }
+
+    #[tokio::test]
+    async fn cached_media_is_not_transcribed_again() {
+        let db = Mutex::new(Db::new_with_file(":memory:").unwrap());
+        let now = SystemTime::now();
+        let mut transcriptions = 0;
+        for _ in 0..2 {
+            let transcript = cached_transcript(&db, 7, now, || async {
+                transcriptions += 1;
+                Ok(Some(("Release on Monday".to_string(), false)))
+            })
+            .await
+            .unwrap();
+            assert_eq!(transcript, Some(("Release on Monday".to_string(), false)));
+        }
+        assert_eq!(transcriptions, 1);
+
+        // Another media and an expired transcript are transcribed.
+        cached_transcript(&db, 8, now, || async {
+            transcriptions += 1;
+            Ok(None)
+        })
+        .await
+        .unwrap();
+        cached_transcript(&db, 7, now + consts::MEDIA_TRANSCRIPT_TTL * 2, || async {
+            transcriptions += 1;
+            Ok(Some(("Release on Tuesday".to_string(), true)))
+        })
+        .await
+        .unwrap();
+        assert_eq!(transcriptions, 3);
+    }
+
+    struct FailingTranscriber(AudioFormat);
+
+    impl Transcriber for FailingTranscriber {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn audio_format(&self) -> AudioFormat {
+            self.0
+        }
+
+        fn uploads_audio(&self) -> bool {
+            false
+        }
+
+        fn transcribe(
+            &self,
+            _audio_file: &str,
+            _prompt: Option<&str>,
+            _retries: &RetryBudget,
+        ) -> Result<Option<String>> {
+            Err(BotError::Media("Failed to transcribe audio".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn media_files_are_removed_when_transcription_fails() {
+        let dir = std::env::temp_dir().join(format!("ohsumbot-media-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let save_path = dir.join("42.ogg").to_str().unwrap().to_string();
+        let retries = RetryBudget::default();
+
+        std::fs::write(&save_path, b"not really audio").unwrap();
+        let transcriber: Arc<dyn Transcriber> = Arc::new(FailingTranscriber(AudioFormat::Any));
+        let files = MediaFiles::new(&save_path);
+        let result = transcribe_media(&transcriber, files, &save_path, false, None, &retries).await;
+        assert!(result.is_err());
+        assert!(!std::path::Path::new(&save_path).exists());
+
+        // A partially converted file is removed with the download.
+        std::fs::write(&save_path, b"not really audio").unwrap();
+        let converted = converted_file(&save_path, "wav");
+        std::fs::write(&converted, b"partial").unwrap();
+        let transcriber: Arc<dyn Transcriber> = Arc::new(FailingTranscriber(AudioFormat::Wav));
+        let files = MediaFiles::new(&save_path);
+        let result = transcribe_media(&transcriber, files, &save_path, false, None, &retries).await;
+        assert!(result.is_err());
+        assert!(!std::path::Path::new(&save_path).exists());
+        assert!(!std::path::Path::new(&converted).exists());
+
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocking_calls_dont_stall_other_tasks() {
+        let ticks = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ticker = {
+            let ticks = ticks.clone();
+            tokio::spawn(async move {
+                loop {
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+
+        // The test runtime has a single thread, so the ticker only runs if the call doesn't block it.
+        // A blocking call would return before the ticker is ever polled.
+        let result = run_blocking(|| {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(42)
+        })
+        .await;
+        ticker.abort();
+
+        assert_eq!(result.unwrap(), 42);
+        assert!(ticks.load(std::sync::atomic::Ordering::SeqCst) > 0);
+    }
+
+    // Stands in for the blocking OpenAI client, counts the requests in flight.
+    #[derive(Clone, Default)]
+    struct FakeApi {
+        in_flight: Arc<std::sync::atomic::AtomicUsize>,
+        max_in_flight: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl FakeApi {
+        fn summarize(&self, id: u32) -> Result<String> {
+            use std::sync::atomic::Ordering;
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(200));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(format!("summary {id}"))
+        }
+    }
+
+    #[tokio::test]
+    async fn summaries_are_requested_concurrently() {
+        let api = FakeApi::default();
+        let queue = RwLock::new(CommandQueue::new());
+        queue.write().await.extend([
+            TestCommand { chat_id: 1, id: 1 },
+            TestCommand { chat_id: 2, id: 2 },
+        ]);
+        let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        let summaries = Mutex::new(vec![]);
+
+        run_workers(&queue, 2, Duration::from_millis(10), &shutdown, |command| {
+            let api = api.clone();
+            let summaries = &summaries;
+            let shutdown_tx = &shutdown_tx;
+            async move {
+                let summary = run_blocking(move || api.summarize(command.id)).await;
+                let mut summaries = summaries.lock().await;
+                summaries.push(summary.unwrap());
+                if summaries.len() == 2 {
+                    shutdown_tx.send(true).unwrap();
+                }
+                vec![]
+            }
+        })
+        .await;
+
+        let mut summaries = summaries.into_inner();
+        summaries.sort();
+        assert_eq!(summaries, vec!["summary 1", "summary 2"]);
+        assert_eq!(
+            api.max_in_flight.load(std::sync::atomic::Ordering::SeqCst),
+            2
+        );
+    }
+}
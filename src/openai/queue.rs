@@ -0,0 +1,454 @@
+use std::cmp::Reverse;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use futures::future::join_all;
+use tokio::sync::{watch, RwLock};
+
+use crate::consts;
+
+// Ready commands of a higher priority are taken first, even ahead of the older commands with
+// the same ordering key. Commands of the same priority are taken in the order they were queued.
+// A ready command is overtaken at most `MAX_COMMAND_OVERTAKES` times, after that it's taken
+// before the higher priorities, so a steady stream of quick requests can't starve the bulk ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+pub trait Queued {
+    // Whether the command reads from or sends to the chat.
+    fn targets_chat(&self, chat_id: i64) -> bool;
+    // Commands with the same key are processed one by one, in the order they were queued
+    // within a priority.
+    fn ordering_key(&self) -> Option<i64>;
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
+    // Deferred commands wait in the queue until then.
+    fn not_before(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+// Commands of different chats are processed in parallel, the commands of one chat in order.
+pub struct CommandQueue<T> {
+    commands: VecDeque<Waiting<T>>,
+    // Keys of the commands being processed.
+    running: HashSet<i64>,
+    max_overtakes: usize,
+}
+
+struct Waiting<T> {
+    command: T,
+    // Times a later ready command was taken ahead of it.
+    overtaken: usize,
+}
+
+impl<T: Queued> CommandQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            commands: VecDeque::new(),
+            running: HashSet::new(),
+            max_overtakes: consts::MAX_COMMAND_OVERTAKES,
+        }
+    }
+
+    pub fn push(&mut self, command: T) {
+        self.commands.push_back(Waiting {
+            command,
+            overtaken: 0,
+        });
+    }
+
+    pub fn extend(&mut self, commands: impl IntoIterator<Item = T>) {
+        for command in commands {
+            self.push(command);
+        }
+    }
+
+    // Takes the oldest command of the highest priority whose chat has nothing running and marks
+    // the chat as busy until `finish` is called. The ones overtaken too often go first.
+    pub fn pop_ready(&mut self) -> Option<T> {
+        let running = &self.running;
+        let now = SystemTime::now();
+        let (index, _) = self
+            .commands
+            .iter()
+            .enumerate()
+            .filter(|(_, waiting)| is_ready(running, &waiting.command, now))
+            .max_by_key(|(index, waiting)| {
+                (
+                    waiting.overtaken >= self.max_overtakes,
+                    waiting.command.priority(),
+                    Reverse(*index),
+                )
+            })?;
+        self.commands
+            .iter_mut()
+            .take(index)
+            .filter(|waiting| is_ready(running, &waiting.command, now))
+            .for_each(|waiting| waiting.overtaken += 1);
+        // Usually the front one, so nothing is shifted.
+        let command = self.commands.remove(index)?.command;
+        if let Some(key) = command.ordering_key() {
+            self.running.insert(key);
+        }
+        Some(command)
+    }
+
+    // Number of commands being processed.
+    pub fn running(&self) -> usize {
+        self.running.len()
+    }
+
+    // Number of commands waiting in the queue, deferred ones included.
+    pub fn waiting(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn finish(&mut self, key: Option<i64>) {
+        if let Some(key) = key {
+            self.running.remove(&key);
+        }
+    }
+
+    // Drops the queued commands matching the filter and returns them, the running ones go on.
+    pub fn cancel(&mut self, matches: impl Fn(&T) -> bool) -> Vec<T> {
+        let (cancelled, kept): (VecDeque<_>, VecDeque<_>) = self
+            .commands
+            .drain(..)
+            .partition(|waiting| matches(&waiting.command));
+        self.commands = kept;
+        cancelled
+            .into_iter()
+            .map(|waiting| waiting.command)
+            .collect()
+    }
+}
+
+// Whether the command isn't deferred past `now` and nothing with its ordering key is running.
+fn is_ready<T: Queued>(running: &HashSet<i64>, command: &T, now: SystemTime) -> bool {
+    if command
+        .not_before()
+        .is_some_and(|not_before| not_before > now)
+    {
+        return false;
+    }
+    match command.ordering_key() {
+        Some(key) => !running.contains(&key),
+        None => true,
+    }
+}
+
+impl<T: Queued> Default for CommandQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Runs `workers` loops taking commands from the queue. The commands returned by `process`
+// are queued before the chat is freed, so they can't be overtaken by the chat's next command.
+// Idle workers check the queue again after `idle`. Once `shutdown` is set, the workers finish
+// their commands and return, the rest is left in the queue.
+pub async fn run_workers<T, F, Fut>(
+    queue: &RwLock<CommandQueue<T>>,
+    workers: usize,
+    idle: Duration,
+    shutdown: &watch::Receiver<bool>,
+    process: F,
+) where
+    T: Queued,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Vec<T>>,
+{
+    let worker = || async {
+        while !*shutdown.borrow() {
+            let command = queue.write().await.pop_ready();
+            let Some(command) = command else {
+                tokio::time::sleep(idle).await;
+                continue;
+            };
+            let key = command.ordering_key();
+            let new_commands = process(command).await;
+            let mut queue = queue.write().await;
+            queue.extend(new_commands);
+            queue.finish(key);
+        }
+    };
+    join_all((0..workers.max(1)).map(|_| worker())).await;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct TestCommand {
+        pub chat_id: i64,
+        pub id: u32,
+    }
+
+    impl Queued for TestCommand {
+        fn targets_chat(&self, chat_id: i64) -> bool {
+            self.chat_id == chat_id
+        }
+
+        fn ordering_key(&self) -> Option<i64> {
+            Some(self.chat_id)
+        }
+    }
+
+    #[test]
+    fn cancel_drops_only_matching_commands() {
+        let mut queue = CommandQueue::new();
+        queue.extend([
+            TestCommand { chat_id: 1, id: 1 },
+            TestCommand { chat_id: 2, id: 2 },
+            TestCommand { chat_id: 1, id: 3 },
+        ]);
+
+        assert_eq!(queue.cancel(|command| command.targets_chat(1)).len(), 2);
+        assert!(queue.cancel(|command| command.targets_chat(3)).is_empty());
+        assert_eq!(queue.pop_ready(), Some(TestCommand { chat_id: 2, id: 2 }));
+        assert_eq!(queue.pop_ready(), None);
+    }
+
+    #[test]
+    fn matching_commands_are_cancelled_in_order() {
+        let mut queue = CommandQueue::new();
+        queue.extend([
+            TestCommand { chat_id: 1, id: 1 },
+            TestCommand { chat_id: 2, id: 2 },
+            TestCommand { chat_id: 1, id: 3 },
+            TestCommand { chat_id: 3, id: 4 },
+        ]);
+
+        assert_eq!(
+            queue.cancel(|command| command.id % 2 == 1),
+            vec![
+                TestCommand { chat_id: 1, id: 1 },
+                TestCommand { chat_id: 1, id: 3 }
+            ]
+        );
+        assert_eq!(queue.pop_ready(), Some(TestCommand { chat_id: 2, id: 2 }));
+        assert_eq!(queue.pop_ready(), Some(TestCommand { chat_id: 3, id: 4 }));
+    }
+
+    #[test]
+    fn busy_chats_are_skipped() {
+        let mut queue = CommandQueue::new();
+        queue.extend([
+            TestCommand { chat_id: 1, id: 1 },
+            TestCommand { chat_id: 1, id: 2 },
+            TestCommand { chat_id: 2, id: 3 },
+        ]);
+
+        assert_eq!(queue.pop_ready(), Some(TestCommand { chat_id: 1, id: 1 }));
+        // The second command of chat 1 waits for the first one, chat 2 doesn't.
+        assert_eq!(queue.pop_ready(), Some(TestCommand { chat_id: 2, id: 3 }));
+        assert_eq!(queue.pop_ready(), None);
+        queue.finish(Some(1));
+        assert_eq!(queue.pop_ready(), Some(TestCommand { chat_id: 1, id: 2 }));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct PrioritizedCommand {
+        id: u32,
+        priority: Priority,
+    }
+
+    impl Queued for PrioritizedCommand {
+        fn targets_chat(&self, _chat_id: i64) -> bool {
+            true
+        }
+
+        fn ordering_key(&self) -> Option<i64> {
+            Some(1)
+        }
+
+        fn priority(&self) -> Priority {
+            self.priority
+        }
+    }
+
+    #[test]
+    fn higher_priorities_go_first_and_equal_ones_in_order() {
+        let mut queue = CommandQueue::new();
+        let command = |id, priority| PrioritizedCommand { id, priority };
+        queue.extend([
+            command(1, Priority::Low),
+            command(2, Priority::Normal),
+            command(3, Priority::High),
+            command(4, Priority::Normal),
+            command(5, Priority::High),
+        ]);
+
+        let mut order = vec![];
+        while let Some(command) = queue.pop_ready() {
+            order.push(command.id);
+            queue.finish(Some(1));
+        }
+        assert_eq!(order, vec![3, 5, 2, 4, 1]);
+    }
+
+    #[test]
+    fn overtaken_commands_dont_starve() {
+        let mut queue = CommandQueue::new();
+        queue.max_overtakes = 2;
+        let command = |id, priority| PrioritizedCommand { id, priority };
+        queue.extend([command(1, Priority::Low), command(2, Priority::Normal)]);
+
+        let mut order = vec![];
+        for id in 3..6 {
+            order.push(queue.pop_ready().unwrap().id);
+            queue.finish(Some(1));
+            // Quick requests keep coming.
+            queue.push(command(id, Priority::High));
+        }
+        while let Some(command) = queue.pop_ready() {
+            order.push(command.id);
+            queue.finish(Some(1));
+        }
+        // The low priority command was overtaken twice, then the normal one was.
+        assert_eq!(order, vec![2, 3, 1, 4, 5]);
+    }
+
+    struct DeferredCommand {
+        id: u32,
+        not_before: Option<SystemTime>,
+    }
+
+    impl Queued for DeferredCommand {
+        fn targets_chat(&self, _chat_id: i64) -> bool {
+            true
+        }
+
+        fn ordering_key(&self) -> Option<i64> {
+            Some(1)
+        }
+
+        fn not_before(&self) -> Option<SystemTime> {
+            self.not_before
+        }
+    }
+
+    #[test]
+    fn deferred_commands_wait_until_their_time() {
+        let mut queue = CommandQueue::new();
+        let now = SystemTime::now();
+        let command = |id, not_before| DeferredCommand { id, not_before };
+        queue.extend([
+            command(1, Some(now + Duration::from_secs(3600))),
+            command(2, None),
+            command(3, Some(now - Duration::from_secs(1))),
+        ]);
+
+        let mut order = vec![];
+        while let Some(command) = queue.pop_ready() {
+            order.push(command.id);
+            queue.finish(Some(1));
+        }
+        assert_eq!(order, vec![2, 3]);
+        assert_eq!(queue.waiting(), 1);
+    }
+
+    #[tokio::test]
+    async fn chats_are_processed_in_parallel_and_in_order() {
+        let queue = RwLock::new(CommandQueue::new());
+        queue.write().await.extend([
+            TestCommand { chat_id: 1, id: 1 },
+            TestCommand { chat_id: 1, id: 2 },
+            TestCommand { chat_id: 2, id: 3 },
+            TestCommand { chat_id: 1, id: 4 },
+        ]);
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let processed = Mutex::new(vec![]);
+
+        let (_shutdown, running_until) = watch::channel(false);
+        let workers = run_workers(
+            &queue,
+            4,
+            Duration::from_millis(5),
+            &running_until,
+            |command| {
+                let (running, max_running, processed) = (&running, &max_running, &processed);
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    processed.lock().unwrap().push(command.id);
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    vec![]
+                }
+            },
+        );
+        tokio::select! {
+            _ = workers => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(500)) => {}
+        }
+
+        // Chat 2 didn't wait for chat 1, but chat 1 never ran two commands at once.
+        assert_eq!(max_running.into_inner(), 2);
+        let processed = processed.into_inner().unwrap();
+        let chat_1 = processed
+            .iter()
+            .filter(|id| **id != 3)
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(chat_1, vec![1, 2, 4]);
+        assert_eq!(processed.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn workers_finish_their_commands_on_shutdown() {
+        let queue = RwLock::new(CommandQueue::new());
+        queue.write().await.extend([
+            TestCommand { chat_id: 1, id: 1 },
+            TestCommand { chat_id: 1, id: 2 },
+        ]);
+        let (shutdown, running_until) = watch::channel(false);
+        let processed = Mutex::new(vec![]);
+
+        let workers = run_workers(
+            &queue,
+            2,
+            Duration::from_millis(5),
+            &running_until,
+            |command| {
+                let (shutdown, processed) = (&shutdown, &processed);
+                async move {
+                    shutdown.send(true).unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    processed.lock().unwrap().push(command.id);
+                    vec![TestCommand {
+                        chat_id: 1,
+                        id: command.id + 10,
+                    }]
+                }
+            },
+        );
+        tokio::time::timeout(Duration::from_secs(1), workers)
+            .await
+            .unwrap();
+
+        // The running command was finished, the queued ones and its follow-up are left.
+        assert_eq!(processed.into_inner().unwrap(), vec![1]);
+        let mut queue = queue.into_inner();
+        assert_eq!(queue.running(), 0);
+        assert_eq!(
+            queue.cancel(|_| true),
+            vec![
+                TestCommand { chat_id: 1, id: 2 },
+                TestCommand { chat_id: 1, id: 11 }
+            ]
+        );
+    }
+}
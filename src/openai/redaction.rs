@@ -0,0 +1,102 @@
+use regex::{Captures, Regex};
+
+const EMAIL_PATTERN: &str = r"[\w.%+-]+@[\w-]+(?:\.[\w-]+)*\.[a-zA-Z]{2,}";
+// Candidates only, see `is_phone_number`.
+const PHONE_PATTERN: &str = r"\+?\(?\d[\d\s().-]{6,}\d";
+// Shorter digit runs are dates, times and amounts rather than phone numbers.
+const PHONE_MIN_DIGITS: usize = 9;
+const PHONE_MAX_DIGITS: usize = 15;
+
+// Masks emails, phone numbers and the configured words, so summaries don't repeat them.
+pub struct Redactor {
+    email: Regex,
+    phone: Regex,
+    // The configured words and the custom pattern, masked the same way.
+    custom: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(words: &[String], pattern: Option<&str>) -> Result<Self, regex::Error> {
+        let mut custom = vec![];
+        let words = words
+            .iter()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .map(regex::escape)
+            .collect::<Vec<_>>();
+        if !words.is_empty() {
+            custom.push(Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|")))?);
+        }
+        if let Some(pattern) = pattern {
+            custom.push(Regex::new(pattern)?);
+        }
+
+        Ok(Self {
+            email: Regex::new(EMAIL_PATTERN)?,
+            phone: Regex::new(PHONE_PATTERN)?,
+            custom,
+        })
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let text = self.email.replace_all(text, "[email]");
+        let text = self.phone.replace_all(&text, |captures: &Captures| {
+            let candidate = &captures[0];
+            if is_phone_number(candidate) {
+                "[phone]".to_string()
+            } else {
+                candidate.to_string()
+            }
+        });
+        self.custom.iter().fold(text.into_owned(), |text, pattern| {
+            pattern.replace_all(&text, "***").into_owned()
+        })
+    }
+}
+
+fn is_phone_number(candidate: &str) -> bool {
+    let digits = candidate.chars().filter(char::is_ascii_digit).count();
+    (PHONE_MIN_DIGITS..=PHONE_MAX_DIGITS).contains(&digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emails_and_phones_are_masked() {
+        let redactor = Redactor::new(&[], None).unwrap();
+        assert_eq!(
+            redactor.redact(
+                "@user1 asked to write to john.doe+work@example.com.ua or call +380 67 123 45 67"
+            ),
+            "@user1 asked to write to [email] or call [phone]"
+        );
+        assert_eq!(
+            redactor.redact("Call (555) 123-4567 or 067-123-45-67"),
+            "Call [phone] or [phone]"
+        );
+    }
+
+    #[test]
+    fn normal_text_is_untouched() {
+        let redactor = Redactor::new(&["дурень".to_string()], None).unwrap();
+        let text = "@user1 and @user2 agreed to release v1.2.3 on 2024-05-01 at 10:30, the budget is 15 000 грн. Contact @user3.";
+        assert_eq!(redactor.redact(text), text);
+    }
+
+    #[test]
+    fn configured_words_and_pattern_are_masked() {
+        let redactor = Redactor::new(
+            &["дурень".to_string(), " idiot ".to_string()],
+            Some(r"ticket-\d+"),
+        )
+        .unwrap();
+        assert_eq!(
+            redactor
+                .redact("Він назвав його Дурень, а той відповів IDIOT щодо ticket-42. Idiotic."),
+            "Він назвав його ***, а той відповів *** щодо ***. Idiotic."
+        );
+        assert!(Redactor::new(&[], Some("(")).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+pub const TRUNCATED_NOTE: &str = "…(truncated, reply /more for the rest)";
+
+// Cuts the text to `max_length` characters, preferring to cut between lines or words.
+// Returns the shown part with a note about the truncation and the rest, if anything was cut.
+pub fn truncate(text: &str, max_length: usize) -> (String, Option<String>) {
+    let Some((cut, _)) = text.char_indices().nth(max_length) else {
+        return (text.to_string(), None);
+    };
+
+    let head = &text[..cut];
+    let split = head
+        .rfind('\n')
+        .or_else(|| head.rfind(char::is_whitespace))
+        .filter(|split| *split >= cut / 2)
+        .unwrap_or(cut);
+    let (shown, rest) = text.split_at(split);
+    let rest = rest.trim_start();
+    if rest.is_empty() {
+        return (text.to_string(), None);
+    }
+    (
+        format!("{}\n{TRUNCATED_NOTE}", shown.trim_end()),
+        Some(rest.to_string()),
+    )
+}
+
+// The cut off end of the latest truncated response sent to each user in DM, shown on /more.
+#[derive(Default)]
+pub struct Remainders {
+    entries: HashMap<i64, String>,
+}
+
+impl Remainders {
+    // Truncates the response and keeps the rest. A newer response replaces the previous rest.
+    pub fn truncate(&mut self, user_id: i64, text: &str, max_length: usize) -> String {
+        let (shown, rest) = truncate(text, max_length);
+        match rest {
+            Some(rest) => self.entries.insert(user_id, rest),
+            None => self.entries.remove(&user_id),
+        };
+        shown
+    }
+
+    // The next part of the stored rest, truncated again if it's still too long.
+    pub fn more(&mut self, user_id: i64, max_length: usize) -> Option<String> {
+        let rest = self.entries.remove(&user_id)?;
+        Some(self.truncate(user_id, &rest, max_length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_text_is_truncated() {
+        assert_eq!(
+            truncate("Short answer", 100),
+            ("Short answer".to_string(), None)
+        );
+
+        let text = "First line of the summary\nSecond line that doesn't fit";
+        assert_eq!(
+            truncate(text, 30),
+            (
+                format!("First line of the summary\n{TRUNCATED_NOTE}"),
+                Some("Second line that doesn't fit".to_string())
+            )
+        );
+
+        // Without a line break close enough, the text is cut between words.
+        let (shown, rest) = truncate("Привіт усім учасникам чату", 12);
+        assert_eq!(shown, format!("Привіт усім\n{TRUNCATED_NOTE}"));
+        assert_eq!(rest.as_deref(), Some("учасникам чату"));
+
+        let (shown, rest) = truncate("abcdefghij", 4);
+        assert_eq!(shown, format!("abcd\n{TRUNCATED_NOTE}"));
+        assert_eq!(rest.as_deref(), Some("efghij"));
+    }
+
+    #[test]
+    fn more_returns_the_stored_remainder() {
+        let mut remainders = Remainders::default();
+        let text = "one two three four five six";
+        assert_eq!(
+            remainders.truncate(1, text, 8),
+            format!("one two\n{TRUNCATED_NOTE}")
+        );
+        assert_eq!(remainders.more(2, 8), None);
+
+        assert_eq!(
+            remainders.more(1, 8),
+            Some(format!("three\n{TRUNCATED_NOTE}"))
+        );
+        assert_eq!(
+            remainders.more(1, 8),
+            Some(format!("four\n{TRUNCATED_NOTE}"))
+        );
+        assert_eq!(remainders.more(1, 20), Some("five six".to_string()));
+        assert_eq!(remainders.more(1, 20), None);
+
+        // A response that fits drops the rest of the previous one.
+        remainders.truncate(1, text, 8);
+        remainders.truncate(1, "short", 8);
+        assert_eq!(remainders.more(1, 8), None);
+    }
+}
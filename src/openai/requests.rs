@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+// Requests being processed. A request fans out into several commands, like the chunks of a long
+// summary, and media jobs. It's done once the last of them finishes without leaving another one.
+pub struct Requests<T> {
+    next_id: u64,
+    requests: HashMap<u64, Request<T>>,
+}
+
+struct Request<T> {
+    request: T,
+    // Commands queued or running and media jobs working for the request.
+    live: usize,
+    failed: bool,
+    cancelled: bool,
+}
+
+impl<T> Requests<T> {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            requests: HashMap::new(),
+        }
+    }
+
+    // Tracks a request with its first command. Returns the id its commands are tracked by.
+    pub fn start(&mut self, request: T) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.insert(
+            id,
+            Request {
+                request,
+                live: 1,
+                failed: false,
+                cancelled: false,
+            },
+        );
+        id
+    }
+
+    // Another command or media job works for the request.
+    pub fn fork(&mut self, id: u64) {
+        if let Some(request) = self.requests.get_mut(&id) {
+            request.live += 1;
+        }
+    }
+
+    // A command or media job of the request is done. Returns the request once nothing is left
+    // of it, with whether all of it succeeded.
+    pub fn finish(&mut self, id: u64, failed: bool) -> Option<(T, bool)> {
+        let request = self.requests.get_mut(&id)?;
+        request.failed |= failed;
+        request.live = request.live.saturating_sub(1);
+        if request.live > 0 {
+            return None;
+        }
+        let request = self.requests.remove(&id)?;
+        Some((request.request, !request.failed && !request.cancelled))
+    }
+
+    // Cancels the matching requests. Their running commands and media jobs are finished, but
+    // nothing else is done for them. Returns the newly cancelled ones with their ids.
+    pub fn cancel(&mut self, matches: impl Fn(&T) -> bool) -> Vec<(u64, &T)> {
+        self.requests
+            .iter_mut()
+            .filter(|(_, request)| !request.cancelled && matches(&request.request))
+            .map(|(id, request)| {
+                request.cancelled = true;
+                (*id, &request.request)
+            })
+            .collect()
+    }
+
+    pub fn is_cancelled(&self, id: u64) -> bool {
+        self.requests
+            .get(&id)
+            .is_some_and(|request| request.cancelled)
+    }
+
+    // Requests that aren't done yet.
+    pub fn unfinished(&self) -> impl Iterator<Item = &T> {
+        self.requests.values().map(|request| &request.request)
+    }
+}
+
+impl<T> Default for Requests<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_is_done_with_its_last_command() {
+        let mut requests = Requests::new();
+        let summary = requests.start("summary");
+        let question = requests.start("question");
+
+        // The summary is split into two chunks, one of them fails.
+        requests.fork(summary);
+        requests.fork(summary);
+        assert_eq!(requests.finish(summary, false), None);
+        assert_eq!(requests.finish(summary, true), None);
+        assert_eq!(requests.finish(question, false), Some(("question", true)));
+        assert_eq!(requests.finish(summary, false), Some(("summary", false)));
+        assert_eq!(requests.unfinished().count(), 0);
+        assert_eq!(requests.finish(summary, false), None);
+    }
+
+    #[test]
+    fn cancelled_requests_are_not_successful() {
+        let mut requests = Requests::new();
+        let first = requests.start(1);
+        let second = requests.start(2);
+
+        assert_eq!(requests.cancel(|chat_id| *chat_id == 1), vec![(first, &1)]);
+        // Cancelled once.
+        assert!(requests.cancel(|chat_id| *chat_id == 1).is_empty());
+        assert!(requests.is_cancelled(first));
+        assert!(!requests.is_cancelled(second));
+        assert_eq!(requests.finish(first, false), Some((1, false)));
+        assert_eq!(requests.unfinished().collect::<Vec<_>>(), vec![&2]);
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::consts;
+use crate::error::{BotError, OpenAiFailure, Result};
+
+// The latest failed command per chat and recipient, re-enqueued by /retry. Failures are reported
+// to the user who made the request, so they retry from there. A failure in one chat doesn't
+// replace the failure in another one.
+pub struct FailedCommands<T> {
+    // By the chat the command read from, if any, and the recipient, with the failure order.
+    commands: HashMap<(Option<i64>, i64), (u64, T)>,
+    failures: u64,
+}
+
+impl<T> FailedCommands<T> {
+    pub fn new() -> Self {
+        Self {
+            commands: HashMap::new(),
+            failures: 0,
+        }
+    }
+
+    pub fn remember(&mut self, chat_id: Option<i64>, recipient_id: i64, command: T) {
+        self.failures += 1;
+        self.commands
+            .insert((chat_id, recipient_id), (self.failures, command));
+    }
+
+    // Takes the latest failure of the recipient. A command is retried once, it's remembered again
+    // if it fails again.
+    pub fn take(&mut self, recipient_id: i64) -> Option<T> {
+        let key = self
+            .commands
+            .iter()
+            .filter(|((_, recipient), _)| *recipient == recipient_id)
+            .max_by_key(|(_, (failure, _))| *failure)
+            .map(|(key, _)| *key)?;
+        self.commands.remove(&key).map(|(_, command)| command)
+    }
+}
+
+impl<T> Default for FailedCommands<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Automatic retries left for a user request. Everything done for the request shares it:
+// media downloads, rate limited or failed completions and summary self checks, so one request
+// can't keep retrying in every layer. Clones share the same budget. /retry starts a new one.
+#[derive(Clone, Debug)]
+pub struct RetryBudget {
+    remaining: Arc<AtomicU32>,
+}
+
+impl RetryBudget {
+    pub fn new(retries: u32) -> Self {
+        Self {
+            remaining: Arc::new(AtomicU32::new(retries)),
+        }
+    }
+
+    // Takes a retry from the budget, false if it's exhausted.
+    pub fn try_spend(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+                remaining.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(consts::COMMAND_RETRY_BUDGET)
+    }
+}
+
+// Pauses between the attempts of an OpenAI request, doubled after every failed retry.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub attempts: u32,
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Backoff {
+    // Pause after `failed` failed attempts. Up to half of it is cut by `jitter` from 0 to 1,
+    // so requests failed together aren't sent again together.
+    pub fn delay(&self, failed: u32, jitter: f64) -> Duration {
+        let delay = self
+            .delay
+            .saturating_mul(2_u32.saturating_pow(failed.saturating_sub(1)))
+            .min(self.max_delay);
+        delay.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+
+    // Sends the request again after rate limits and outages while attempts are left and
+    // `can_retry` allows it. Other errors are returned at once.
+    pub fn retry<T>(
+        &self,
+        mut can_retry: impl FnMut() -> bool,
+        mut request: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        let mut failed = 0;
+        loop {
+            match request() {
+                Err(BotError::OpenAi(error))
+                    if OpenAiFailure::of(&error).is_transient()
+                        && failed + 1 < self.attempts
+                        && can_retry() =>
+                {
+                    failed += 1;
+                    let delay = self.delay(failed, jitter());
+                    log::warn!(
+                        "OpenAI request failed: {error}. Retry {failed} of {} in {delay:?}",
+                        self.attempts - 1
+                    );
+                    std::thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            attempts: consts::OPENAI_MAX_ATTEMPTS,
+            delay: consts::OPENAI_RETRY_DELAY,
+            max_delay: consts::OPENAI_MAX_RETRY_DELAY,
+        }
+    }
+}
+
+// Good enough randomness for spreading the retries, from 0 to 1.
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+    f64::from(nanos) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_are_kept_per_chat_and_recipient() {
+        let mut failed = FailedCommands::new();
+        failed.remember(Some(10), 1, "summarize 100");
+        failed.remember(Some(10), 1, "summarize 50");
+        failed.remember(Some(20), 1, "links");
+        failed.remember(None, 2, "more");
+        assert!(failed.take(3).is_none());
+
+        assert_eq!(failed.take(1), Some("links"));
+        // The failure in the other chat is still there.
+        assert_eq!(failed.take(1), Some("summarize 50"));
+        assert!(failed.take(1).is_none());
+        assert_eq!(failed.take(2), Some("more"));
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried_with_growing_pauses() {
+        let backoff = Backoff {
+            attempts: 3,
+            delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(5),
+        };
+        assert_eq!(backoff.delay(1, 0.0), Duration::from_secs(2));
+        assert_eq!(backoff.delay(2, 0.0), Duration::from_secs(4));
+        assert_eq!(backoff.delay(3, 0.0), Duration::from_secs(5));
+        assert_eq!(backoff.delay(2, 1.0), Duration::from_secs(2));
+
+        let backoff = Backoff {
+            delay: Duration::ZERO,
+            ..backoff
+        };
+        let attempts = |error: &'static str| {
+            let mut sent = 0;
+            let result = backoff.retry(
+                || true,
+                || -> Result<()> {
+                    sent += 1;
+                    Err(BotError::openai(error))
+                },
+            );
+            assert!(result.is_err());
+            sent
+        };
+        assert_eq!(attempts("429 Too Many Requests"), 3);
+        assert_eq!(attempts("503 Service Unavailable"), 3);
+        assert_eq!(attempts("invalid_api_key"), 1);
+
+        // Retries stop with the budget of the request.
+        let budget = RetryBudget::new(1);
+        let mut sent = 0;
+        let result = backoff.retry(
+            || budget.try_spend(),
+            || {
+                sent += 1;
+                if sent < 3 {
+                    Err(BotError::openai("502 Bad Gateway"))
+                } else {
+                    Ok(sent)
+                }
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(sent, 2);
+    }
+
+    #[test]
+    fn retry_budget_is_shared() {
+        let budget = RetryBudget::new(2);
+        let download = budget.clone();
+        let completion = budget.clone();
+        assert!(download.try_spend());
+        assert!(completion.try_spend());
+        assert!(!download.try_spend());
+        assert!(!budget.try_spend());
+        assert!(!RetryBudget::new(0).try_spend());
+    }
+}
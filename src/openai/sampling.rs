@@ -0,0 +1,96 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+// How the messages of a large window are reduced to a representative subset.
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplingStrategy {
+    // Every Nth message, spread evenly over the window.
+    EveryNth,
+    // The messages with the most replies and reactions.
+    MostEngaged,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sampling {
+    pub strategy: SamplingStrategy,
+    // Windows with more messages than this are sampled down to it.
+    pub size: usize,
+}
+
+impl Sampling {
+    // Keeps at most `size` messages in their original order, the same messages every time.
+    pub fn sample<T>(&self, messages: Vec<T>, engagement: impl Fn(&T) -> u32) -> Vec<T> {
+        let size = self.size.max(1);
+        if messages.len() <= size {
+            return messages;
+        }
+        match self.strategy {
+            SamplingStrategy::EveryNth => {
+                let step = messages.len().div_ceil(size);
+                messages.into_iter().step_by(step).collect()
+            }
+            SamplingStrategy::MostEngaged => {
+                let mut ranked = messages
+                    .iter()
+                    .map(engagement)
+                    .enumerate()
+                    .collect::<Vec<_>>();
+                // The sort is stable, so earlier messages win among equally engaging ones.
+                ranked.sort_by_key(|(_, engagement)| Reverse(*engagement));
+                let kept = ranked
+                    .into_iter()
+                    .take(size)
+                    .map(|(index, _)| index)
+                    .collect::<HashSet<_>>();
+                messages
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| kept.contains(index))
+                    .map(|(_, message)| message)
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_windows_are_sampled() {
+        let messages = (0..1000).collect::<Vec<u32>>();
+        // Every 7th message has replies.
+        let engagement = |message: &u32| if message % 7 == 3 { 5 } else { 0 };
+
+        let every_nth = Sampling {
+            strategy: SamplingStrategy::EveryNth,
+            size: 300,
+        };
+        let sample = every_nth.sample(messages.clone(), engagement);
+        assert_eq!(sample.len(), 250);
+        assert_eq!(&sample[..3], &[0, 4, 8]);
+        assert_eq!(sample, every_nth.sample(messages.clone(), engagement));
+        // Windows below the threshold are kept as they are.
+        assert_eq!(
+            every_nth.sample(messages[..300].to_vec(), engagement).len(),
+            300
+        );
+
+        let most_engaged = Sampling {
+            strategy: SamplingStrategy::MostEngaged,
+            size: 200,
+        };
+        let sample = most_engaged.sample(messages.clone(), engagement);
+        assert_eq!(sample.len(), 200);
+        // All 143 engaging messages are kept, the rest are the earliest others.
+        assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            sample.iter().filter(|message| *message % 7 == 3).count(),
+            143
+        );
+        assert_eq!(sample[..4], [0, 1, 2, 3]);
+        assert_eq!(sample, most_engaged.sample(messages, engagement));
+    }
+}
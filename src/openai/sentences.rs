@@ -0,0 +1,106 @@
+// Abbreviations that end with a dot but don't end a sentence. Compared in lowercase.
+const ABBREVIATIONS: &[&str] = &[
+    "т.д.",
+    "т.п.",
+    "т.ч.",
+    "т.зв.",
+    "і.т.д.",
+    "ін.",
+    "див.",
+    "напр.",
+    "вул.",
+    "ст.",
+    "р.",
+    "рр.",
+    "грн.",
+    "тис.",
+    "млн.",
+    "млрд.",
+    "e.g.",
+    "i.e.",
+    "etc.",
+    "vs.",
+    "mr.",
+    "mrs.",
+    "dr.",
+];
+
+// Splits the text into sentences. A sentence ends with `.`, `!` or `?` followed by whitespace,
+// so numbers like `3.14` and URLs are kept intact. Abbreviations and initials don't end a sentence.
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?') {
+            continue;
+        }
+        let end = i + c.len_utf8();
+        let at_boundary = !matches!(chars.peek(), Some((_, next)) if !next.is_whitespace());
+        if !at_boundary || (c == '.' && is_abbreviation(&text[start..end])) {
+            continue;
+        }
+
+        sentences.push(text[start..end].trim());
+        start = end;
+    }
+    sentences.push(text[start..].trim());
+    sentences.retain(|sentence| !sentence.is_empty());
+    sentences
+}
+
+fn is_abbreviation(sentence: &str) -> bool {
+    let Some(word) = sentence.split_whitespace().last() else {
+        return false;
+    };
+    let word = word.trim_start_matches(|c: char| !c.is_alphanumeric());
+    let lowercase = word.to_lowercase();
+    if ABBREVIATIONS.contains(&lowercase.as_str()) {
+        return true;
+    }
+
+    // Initials, e.g. "Т. Шевченко".
+    let mut letters = word.chars();
+    matches!(
+        (letters.next(), letters.next(), letters.next()),
+        (Some(letter), Some('.'), None) if letter.is_uppercase()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentences_are_split() {
+        assert_eq!(
+            split_sentences("Привіт! Як справи? Все добре.  Дякую"),
+            vec!["Привіт!", "Як справи?", "Все добре.", "Дякую"]
+        );
+        assert_eq!(split_sentences("Wait... What?!"), vec!["Wait...", "What?!"]);
+        assert!(split_sentences("  ").is_empty());
+    }
+
+    #[test]
+    fn abbreviations_numbers_and_urls_are_not_split() {
+        assert_eq!(
+            split_sentences("Купили хліб, молоко і т.д. Потім пішли додому."),
+            vec!["Купили хліб, молоко і т.д. Потім пішли додому."]
+        );
+        assert_eq!(
+            split_sentences("Pi is 3.14 and e is 2.71. Both are irrational."),
+            vec!["Pi is 3.14 and e is 2.71.", "Both are irrational."]
+        );
+        assert_eq!(
+            split_sentences("See https://example.com/docs/v1.2/index.html for details. Thanks!"),
+            vec![
+                "See https://example.com/docs/v1.2/index.html for details.",
+                "Thanks!"
+            ]
+        );
+        assert_eq!(
+            split_sentences("Вірш написав Т. Шевченко. Це класика."),
+            vec!["Вірш написав Т. Шевченко.", "Це класика."]
+        );
+    }
+}
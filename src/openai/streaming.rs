@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+use crate::consts;
+
+// Accumulates a streamed response and decides when the Telegram message should be edited.
+// Telegram rate limits edits, so the message is updated at most once per `interval`.
+pub struct StreamingMessage {
+    text: String,
+    interval: Duration,
+    last_edit: Instant,
+    shown_len: usize,
+}
+
+impl StreamingMessage {
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            text: String::new(),
+            interval,
+            last_edit: now,
+            shown_len: 0,
+        }
+    }
+
+    // Appends the delta and returns the text to show if an edit is due.
+    pub fn push(&mut self, delta: &str, now: Instant) -> Option<String> {
+        self.text.push_str(delta);
+        if now.duration_since(self.last_edit) < self.interval || self.text.len() == self.shown_len {
+            return None;
+        }
+
+        self.last_edit = now;
+        self.shown_len = self.text.len();
+        // Only the first message is edited while streaming, the rest is sent once the response is complete.
+        Some(
+            self.text
+                .chars()
+                .take(consts::TELEGRAM_MAX_MESSAGE_LENGTH)
+                .collect(),
+        )
+    }
+}
+
+// Splits the text into parts that fit into a Telegram message, preferring to split between lines.
+pub fn split_message(text: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+    for line in text.split_inclusive('\n') {
+        for piece in split_long_line(line) {
+            let piece_len = piece.chars().count();
+            if current_len + piece_len > consts::TELEGRAM_MAX_MESSAGE_LENGTH {
+                parts.push(std::mem::take(&mut current));
+                current_len = 0;
+            }
+            current.push_str(&piece);
+            current_len += piece_len;
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+fn split_long_line(line: &str) -> Vec<String> {
+    let chars = line.chars().collect::<Vec<_>>();
+    chars
+        .chunks(consts::TELEGRAM_MAX_MESSAGE_LENGTH)
+        .map(|chunk| chunk.iter().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_are_throttled() {
+        let start = Instant::now();
+        let mut message = StreamingMessage::new(Duration::from_secs(1), start);
+
+        // 20 tokens over ~3 seconds, one token every 150ms.
+        let edits = (1..=20)
+            .filter_map(|i| {
+                message.push(
+                    &format!("token{i} "),
+                    start + Duration::from_millis(i * 150),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(edits.len(), 2);
+        assert_eq!(
+            edits[0],
+            "token1 token2 token3 token4 token5 token6 token7 "
+        );
+        assert!(edits[1].ends_with("token14 "));
+        assert!(message.text.ends_with("token20 "));
+    }
+
+    #[test]
+    fn long_text_is_split() {
+        let line = format!("{}\n", "a".repeat(3000));
+        let text = line.repeat(3);
+        let parts = split_message(&text);
+        assert_eq!(parts, vec![line.clone(), line.clone(), line]);
+
+        let parts = split_message(&"б".repeat(consts::TELEGRAM_MAX_MESSAGE_LENGTH + 1));
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[1], "б");
+        assert!(split_message("").is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::error::{BotError, Result};
+
+// Added to the summary system prompt when structured output is enabled.
+pub const STRUCTURED_OUTPUT_RULE: &str = r#"Return the summary as a JSON object instead of text, following the rules above for the content. The object must have exactly these fields:
+* "topics": list of objects with "title" and "summary" strings, one per discussed topic.
+* "participants": list of the nicknames of the users who took part in the discussion.
+* "decisions": list of strings with the decisions made, empty if there were none.
+* "sentiment": one or two words describing the mood of the discussion.
+Don't wrap the JSON into a code block."#;
+
+#[derive(serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct Topic {
+    pub title: String,
+    pub summary: String,
+}
+
+// Machine-readable summary returned by the model in JSON mode.
+#[derive(serde::Deserialize, Debug, PartialEq, Eq)]
+pub struct StructuredSummary {
+    pub topics: Vec<Topic>,
+    #[serde(default)]
+    pub participants: Vec<String>,
+    #[serde(default)]
+    pub decisions: Vec<String>,
+    pub sentiment: String,
+}
+
+impl StructuredSummary {
+    // A summary without topics is rejected, it means the model didn't follow the schema.
+    pub fn parse(text: &str) -> Result<Self> {
+        let summary: StructuredSummary =
+            serde_json::from_str(text.trim()).map_err(BotError::openai)?;
+        if summary.topics.is_empty() {
+            return Err(BotError::openai("Structured summary has no topics"));
+        }
+        Ok(summary)
+    }
+
+    // Telegram message text of the summary.
+    pub fn render(&self) -> String {
+        let mut lines = self
+            .topics
+            .iter()
+            .map(|topic| format!("• {}: {}", topic.title.trim(), topic.summary.trim()))
+            .collect::<Vec<_>>();
+        if !self.decisions.is_empty() {
+            lines.push(String::new());
+            lines.push("Decisions:".to_string());
+            lines.extend(
+                self.decisions
+                    .iter()
+                    .map(|decision| format!("• {}", decision.trim())),
+            );
+        }
+        lines.push(String::new());
+        if !self.participants.is_empty() {
+            let participants = self
+                .participants
+                .iter()
+                .map(|participant| format!("@{}", participant.trim().trim_start_matches('@')))
+                .collect::<Vec<_>>();
+            lines.push(format!("Participants: {}", participants.join(", ")));
+        }
+        lines.push(format!("Sentiment: {}", self.sentiment.trim()));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn well_formed_summary_is_rendered() {
+        let response = r#"{
+            "topics": [
+                {"title": "Release", "summary": "@user1 proposed to release on Monday"},
+                {"title": "Lunch", "summary": "@user2 invited everyone to lunch"}
+            ],
+            "participants": ["user1", "@user2"],
+            "decisions": ["Release on Monday"],
+            "sentiment": "positive"
+        }"#;
+        let summary = StructuredSummary::parse(response).unwrap();
+        assert_eq!(summary.topics.len(), 2);
+        assert_eq!(
+            summary.render(),
+            "• Release: @user1 proposed to release on Monday
+• Lunch: @user2 invited everyone to lunch
+
+Decisions:
+• Release on Monday
+
+Participants: @user1, @user2
+Sentiment: positive"
+        );
+
+        let summary = StructuredSummary::parse(
+            r#"{"topics": [{"title": "Release", "summary": "Postponed"}], "sentiment": "tense"}"#,
+        )
+        .unwrap();
+        assert_eq!(summary.render(), "• Release: Postponed\n\nSentiment: tense");
+    }
+
+    #[test]
+    fn malformed_summary_is_rejected() {
+        assert!(matches!(
+            StructuredSummary::parse("```json\n{\"topics\": ["),
+            Err(BotError::OpenAi(_))
+        ));
+        assert!(StructuredSummary::parse(r#"{"summary": "Release on Monday"}"#).is_err());
+        assert!(StructuredSummary::parse(r#"{"topics": [], "sentiment": "neutral"}"#).is_err());
+    }
+}
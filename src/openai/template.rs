@@ -0,0 +1,72 @@
+use super::api::{self, GPTLenght};
+
+// Optional text wrapped around every summary, e.g. branding or a disclaimer.
+// Supported placeholders: `{count}` - number of summarized messages, `{length}` - requested summary length,
+// `{version}` - model and prompt version that produced the summary.
+#[derive(Clone, Debug, Default)]
+pub struct SummaryTemplate {
+    pub header: Option<String>,
+    pub footer: Option<String>,
+}
+
+impl SummaryTemplate {
+    pub fn apply(&self, summary: &str, count: usize, gpt_length: GPTLenght, model: &str) -> String {
+        let substitute = |template: &str| {
+            template
+                .replace("{count}", &count.to_string())
+                .replace("{length}", gpt_length.name())
+                .replace("{version}", &api::version_tag(model))
+        };
+
+        let mut parts = vec![];
+        parts.extend(self.header.as_deref().map(substitute));
+        parts.push(summary.to_string());
+        parts.extend(self.footer.as_deref().map(substitute));
+        parts.join("\n\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts;
+    use crate::openai::streaming::split_message;
+
+    #[test]
+    fn templates_wrap_summary() {
+        let template = SummaryTemplate {
+            header: Some("🤖 Summary of {count} messages ({length})".to_string()),
+            footer: Some("Generated by OhsumBot".to_string()),
+        };
+        assert_eq!(
+            template.apply("They talked.", 42, GPTLenght::Long, "gpt-4o"),
+            "🤖 Summary of 42 messages (large)\n\nThey talked.\n\nGenerated by OhsumBot"
+        );
+        assert_eq!(
+            SummaryTemplate::default().apply("They talked.", 42, GPTLenght::Long, "gpt-4o"),
+            "They talked."
+        );
+
+        let wrapped = template.apply(
+            &"a".repeat(consts::TELEGRAM_MAX_MESSAGE_LENGTH),
+            1,
+            GPTLenght::Short,
+            "gpt-4o",
+        );
+        let parts = split_message(&wrapped);
+        assert!(parts
+            .iter()
+            .all(|part| part.chars().count() <= consts::TELEGRAM_MAX_MESSAGE_LENGTH));
+        assert!(parts[0].starts_with("🤖 Summary of 1 messages (small)"));
+        assert!(parts.last().unwrap().ends_with("Generated by OhsumBot"));
+
+        let template = SummaryTemplate {
+            header: None,
+            footer: Some("{version}".to_string()),
+        };
+        assert_eq!(
+            template.apply("They talked.", 1, GPTLenght::Short, "gpt-4o-mini"),
+            format!("They talked.\n\n{}", api::version_tag("gpt-4o-mini"))
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+
+// Number of tokens the model reads from the text. Models unknown to the tokenizer, like local ones,
+// are counted with the encoding of the default model.
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    let bpe = match get_tokenizer(model) {
+        Some(Tokenizer::Cl100kBase) => tiktoken_rs::cl100k_base_singleton(),
+        _ => tiktoken_rs::o200k_base_singleton(),
+    };
+    let tokens = bpe.lock().encode_ordinary(text).len();
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_counted_with_the_model_encoding() {
+        assert_eq!(count_tokens("gpt-4o", ""), 0);
+        assert_eq!(count_tokens("gpt-4o", "hello world"), 2);
+        assert_eq!(count_tokens("gpt-4", "hello world"), 2);
+        assert_eq!(
+            count_tokens("local-llm", "Вони обговорили реліз"),
+            count_tokens("gpt-4o", "Вони обговорили реліз")
+        );
+        // Cyrillic takes more bytes per token than English.
+        let ukrainian = "Вони обговорили план релізу та домовились про дату.";
+        assert!(ukrainian.len() / count_tokens("gpt-4o", ukrainian) > 4);
+    }
+}
@@ -0,0 +1,474 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::{BotError, Result};
+use crate::openai::api::OpenAIClient;
+use crate::openai::retry::RetryBudget;
+
+// Selected with TRANSCRIBE_BACKEND.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscribeBackend {
+    // OpenAI Whisper API.
+    #[default]
+    OpenAi,
+    // whisper.cpp CLI, so audio never leaves the host.
+    Local,
+}
+
+// Audio a transcriber can read. Media in other formats is converted with ffmpeg first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioFormat {
+    // Any audio file. Videos are converted to mp3.
+    Any,
+    // 16 kHz mono WAV, the only input whisper.cpp reads.
+    Wav,
+}
+
+impl AudioFormat {
+    // Extension and ffmpeg output options of the converted file, if the media has to be converted.
+    pub fn conversion(self, is_video: bool) -> Option<(&'static str, &'static [&'static str])> {
+        match self {
+            AudioFormat::Any if is_video => {
+                Some(("mp3", &["-vn", "-acodec", "libmp3lame", "-b:a", "128k"]))
+            }
+            AudioFormat::Any => None,
+            AudioFormat::Wav => Some((
+                "wav",
+                &["-vn", "-ar", "16000", "-ac", "1", "-c:a", "pcm_s16le"],
+            )),
+        }
+    }
+}
+
+pub trait Transcriber: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn audio_format(&self) -> AudioFormat;
+
+    // Whether the audio is sent to a third party.
+    fn uploads_audio(&self) -> bool;
+
+    // Returns None if no speech was recognized. `prompt` is the chat's vocabulary hint, if set.
+    // Failed uploads are sent again while the request's `retries` last.
+    fn transcribe(
+        &self,
+        audio_file: &str,
+        prompt: Option<&str>,
+        retries: &RetryBudget,
+    ) -> Result<Option<String>>;
+}
+
+impl Transcriber for OpenAIClient {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn audio_format(&self) -> AudioFormat {
+        AudioFormat::Any
+    }
+
+    fn uploads_audio(&self) -> bool {
+        true
+    }
+
+    fn transcribe(
+        &self,
+        audio_file: &str,
+        prompt: Option<&str>,
+        retries: &RetryBudget,
+    ) -> Result<Option<String>> {
+        Ok(self.audio_to_text(audio_file, prompt, retries)?.text)
+    }
+}
+
+pub struct LocalTranscriber {
+    command: String,
+    model: String,
+}
+
+impl LocalTranscriber {
+    pub fn new(command: String, model: String) -> Self {
+        Self { command, model }
+    }
+
+    fn args(&self, audio_file: &str, prompt: Option<&str>) -> Vec<String> {
+        let mut args: Vec<String> = [
+            "-m",
+            &self.model,
+            "-f",
+            audio_file,
+            // whisper.cpp assumes English otherwise.
+            "-l",
+            "auto",
+            "--no-timestamps",
+            "--no-prints",
+        ]
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+        if let Some(prompt) = prompt {
+            args.extend(["--prompt".to_string(), prompt.to_string()]);
+        }
+        args
+    }
+}
+
+impl Transcriber for LocalTranscriber {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn audio_format(&self) -> AudioFormat {
+        AudioFormat::Wav
+    }
+
+    fn uploads_audio(&self) -> bool {
+        false
+    }
+
+    fn transcribe(
+        &self,
+        audio_file: &str,
+        prompt: Option<&str>,
+        _retries: &RetryBudget,
+    ) -> Result<Option<String>> {
+        let output = std::process::Command::new(&self.command)
+            .args(self.args(audio_file, prompt))
+            .output()
+            .map_err(|e| BotError::Media(format!("Failed to run {}: {e}", self.command)))?;
+        if !output.status.success() {
+            log::error!(
+                "{} failed: {}",
+                self.command,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(BotError::Media("Failed to transcribe audio".to_string()));
+        }
+
+        // whisper.cpp prints a line per segment.
+        let text = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(Some(text).filter(|text| !text.is_empty()))
+    }
+}
+
+pub fn transcriber(
+    backend: TranscribeBackend,
+    openai: &OpenAIClient,
+    whisper_command: Option<String>,
+    whisper_model: Option<String>,
+) -> anyhow::Result<Arc<dyn Transcriber>> {
+    match backend {
+        TranscribeBackend::OpenAi => Ok(Arc::new(openai.clone())),
+        TranscribeBackend::Local => {
+            let Some(model) = whisper_model else {
+                anyhow::bail!("WHISPER_MODEL is required for the local transcription backend");
+            };
+            let command = whisper_command.unwrap_or_else(|| "whisper-cli".to_string());
+            Ok(Arc::new(LocalTranscriber::new(command, model)))
+        }
+    }
+}
+
+// Length of the chunks the audio is transcribed in, if it has to be split. Long recordings are
+// split in `max_chunk` pieces, and audio over the upload limit of the transcriber in pieces
+// short enough to fit under it. Chunks are cut `overlap` longer, so it's taken off the length
+// that fits. The limit itself is kept below the real one for the uneven bitrates.
+pub fn chunk_length(
+    duration: Duration,
+    size: u64,
+    size_limit: Option<u64>,
+    max_chunk: Duration,
+    overlap: Duration,
+) -> Option<Duration> {
+    let by_size = size_limit
+        .filter(|limit| size > *limit)
+        // Whole seconds, ffmpeg is given the chunk boundaries in seconds.
+        .map(|limit| {
+            Duration::from_secs(
+                duration
+                    .mul_f64(limit as f64 / size as f64)
+                    .saturating_sub(overlap)
+                    .as_secs()
+                    .max(1),
+            )
+        });
+    if duration <= max_chunk && by_size.is_none() {
+        return None;
+    }
+    Some(by_size.map_or(max_chunk, |by_size| by_size.min(max_chunk)))
+}
+
+// Start of every chunk a recording of the given duration is split into.
+pub fn chunk_starts(duration: Duration, chunk: Duration) -> Vec<Duration> {
+    let mut starts = vec![];
+    let mut start = Duration::ZERO;
+    while start < duration {
+        starts.push(start);
+        start += chunk;
+    }
+    starts
+}
+
+// Path of the chunk file next to the recording, keeping its extension for ffmpeg.
+pub fn chunk_file(file: &str, index: usize) -> String {
+    match file.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !extension.contains('/') => {
+            format!("{stem}.chunk{index}.{extension}")
+        }
+        _ => format!("{file}.chunk{index}"),
+    }
+}
+
+// Joins chunk transcripts into a single transcript, a line per chunk starting with its time
+// in the recording. Returns None if no speech was recognized in any chunk.
+pub fn combine_transcripts(chunks: &[(Duration, Option<String>)]) -> Option<String> {
+    let lines = chunks
+        .iter()
+        .filter_map(|(start, text)| {
+            let text = text.as_deref()?.trim();
+            (!text.is_empty()).then(|| format!("[{}] {text}", timestamp(*start)))
+        })
+        .collect::<Vec<_>>();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+// Joins chunk transcripts of audio that was only split for its size, as a single text.
+pub fn join_transcripts(chunks: &[(Duration, Option<String>)]) -> Option<String> {
+    let text = chunks
+        .iter()
+        .filter_map(|(_, text)| text.as_deref())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!text.is_empty()).then_some(text)
+}
+
+// Chunks overlap, so the start of a transcript repeats the end of the previous one. At least two
+// words have to match, a single one is as likely to be said twice.
+pub fn trim_overlaps(chunks: &mut [(Duration, Option<String>)]) {
+    for index in 1..chunks.len() {
+        let (previous, next) = chunks.split_at_mut(index);
+        let (Some(previous), Some(next)) = (&previous[index - 1].1, &mut next[0].1) else {
+            continue;
+        };
+        let repeated = repeated_words(previous, next);
+        if repeated > 0 {
+            *next = next
+                .split_whitespace()
+                .skip(repeated)
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+    }
+}
+
+// Number of words `next` starts with that `previous` ends with, ignoring case and punctuation.
+fn repeated_words(previous: &str, next: &str) -> usize {
+    // Longer than the overlap takes to say.
+    const MAX_REPEATED_WORDS: usize = 30;
+    let normalized = |word: &str| {
+        word.trim_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase()
+    };
+    let previous = previous
+        .split_whitespace()
+        .map(normalized)
+        .collect::<Vec<_>>();
+    let next = next.split_whitespace().map(normalized).collect::<Vec<_>>();
+    let longest = MAX_REPEATED_WORDS.min(previous.len()).min(next.len());
+    (2..=longest)
+        .rev()
+        .find(|&count| previous[previous.len() - count..] == next[..count])
+        .unwrap_or(0)
+}
+
+fn timestamp(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Deserialize)]
+    struct Config {
+        #[serde(default)]
+        transcribe_backend: TranscribeBackend,
+    }
+
+    #[test]
+    fn backend_is_selected_from_env() {
+        let config: Config = envy::from_iter(Vec::<(String, String)>::new()).unwrap();
+        assert_eq!(config.transcribe_backend, TranscribeBackend::OpenAi);
+        let config: Config =
+            envy::from_iter([("TRANSCRIBE_BACKEND".to_string(), "local".to_string())]).unwrap();
+        assert_eq!(config.transcribe_backend, TranscribeBackend::Local);
+
+        let openai = OpenAIClient::new("key".to_string());
+        let selected = transcriber(TranscribeBackend::OpenAi, &openai, None, None).unwrap();
+        assert_eq!(selected.name(), "openai");
+        assert_eq!(selected.audio_format(), AudioFormat::Any);
+
+        let selected = transcriber(
+            TranscribeBackend::Local,
+            &openai,
+            None,
+            Some("ggml-base.bin".to_string()),
+        )
+        .unwrap();
+        assert_eq!(selected.name(), "local");
+        assert_eq!(selected.audio_format(), AudioFormat::Wav);
+
+        assert!(transcriber(TranscribeBackend::Local, &openai, None, None).is_err());
+    }
+
+    #[test]
+    fn local_command_is_built() {
+        let local = LocalTranscriber::new(
+            "/opt/whisper/whisper-cli".to_string(),
+            "models/ggml-base.bin".to_string(),
+        );
+        assert_eq!(
+            local.args("./media/42.wav", None),
+            vec![
+                "-m",
+                "models/ggml-base.bin",
+                "-f",
+                "./media/42.wav",
+                "-l",
+                "auto",
+                "--no-timestamps",
+                "--no-prints"
+            ]
+        );
+
+        assert!(local
+            .args("./media/42.wav", Some("ohsumbot, grammers"))
+            .ends_with(&["--prompt".to_string(), "ohsumbot, grammers".to_string()]));
+
+        assert_eq!(AudioFormat::Any.conversion(false), None);
+        assert_eq!(AudioFormat::Any.conversion(true).unwrap().0, "mp3");
+        let (extension, args) = AudioFormat::Wav.conversion(false).unwrap();
+        assert_eq!(extension, "wav");
+        assert!(args.windows(2).any(|arg| arg == ["-ar", "16000"]));
+    }
+
+    #[test]
+    fn long_recording_is_transcribed_in_chunks() {
+        let chunk = Duration::from_secs(600);
+        let starts = chunk_starts(Duration::from_secs(25 * 60), chunk);
+        assert_eq!(
+            starts,
+            vec![
+                Duration::ZERO,
+                Duration::from_secs(600),
+                Duration::from_secs(1200)
+            ]
+        );
+        assert_eq!(chunk_starts(Duration::from_secs(600), chunk).len(), 1);
+        assert_eq!(
+            chunk_file("./media/42.converted.mp3", 1),
+            "./media/42.converted.chunk1.mp3"
+        );
+
+        let chunks = vec![
+            (
+                starts[0],
+                Some("Вітаю всіх на дзвінку. Сьогодні про реліз.".to_string()),
+            ),
+            (starts[1], None),
+            (
+                starts[2],
+                Some(" Реліз переносимо на п'ятницю ".to_string()),
+            ),
+        ];
+        let transcript = combine_transcripts(&chunks).unwrap();
+        assert_eq!(
+            transcript,
+            "[00:00:00] Вітаю всіх на дзвінку. Сьогодні про реліз.\n[00:20:00] Реліз переносимо на п'ятницю"
+        );
+        assert_eq!(combine_transcripts(&[(starts[0], None)]), None);
+
+        let prompts = OpenAIClient::new(String::new()).prepare_recording_summary(
+            &transcript,
+            crate::openai::api::GPTLenght::Medium,
+            None,
+        );
+        assert_eq!(prompts.len(), 1);
+        assert_eq!(prompts[0].message_count(), 3);
+    }
+
+    #[test]
+    fn large_audio_is_split_under_the_upload_limit() {
+        let max_chunk = Duration::from_secs(600);
+        let limit = Some(24 * 1024 * 1024);
+        let overlap = Duration::from_secs(3);
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+        let chunk = |duration, size, limit| chunk_length(duration, size, limit, max_chunk, overlap);
+
+        assert_eq!(chunk(minutes(5), 10 * 1024 * 1024, limit), None);
+        // 48MB of five minutes is split in halves, short of the overlap they're cut with.
+        assert_eq!(
+            chunk(minutes(5), 48 * 1024 * 1024, limit),
+            Some(Duration::from_secs(147))
+        );
+        assert_eq!(chunk(minutes(5), 48 * 1024 * 1024, None), None);
+        // Long recordings are split anyway, in shorter chunks if they're too large.
+        assert_eq!(chunk(minutes(25), 20 * 1024 * 1024, limit), Some(max_chunk));
+        assert_eq!(
+            chunk(minutes(25), 100 * 1024 * 1024, limit),
+            Some(Duration::from_secs(357))
+        );
+        // A chunk with its overlap fits under the limit.
+        let (duration, size) = (minutes(25), 100 * 1024 * 1024);
+        let chunk = chunk(duration, size, limit).unwrap() + overlap;
+        assert!(
+            size as f64 * chunk.as_secs_f64() / duration.as_secs_f64() <= 24.0 * 1024.0 * 1024.0
+        );
+    }
+
+    #[test]
+    fn words_repeated_at_chunk_boundaries_are_trimmed() {
+        let mut chunks = vec![
+            (
+                Duration::ZERO,
+                Some("We ship the release on Friday, after the".to_string()),
+            ),
+            (
+                Duration::from_secs(150),
+                Some("on friday after the review. Then we rest".to_string()),
+            ),
+            (
+                Duration::from_secs(300),
+                Some("Rest is important. Rest is good".to_string()),
+            ),
+            (Duration::from_secs(450), None),
+            (Duration::from_secs(600), Some("Bye".to_string())),
+        ];
+        trim_overlaps(&mut chunks);
+        assert_eq!(chunks[1].1.as_deref(), Some("review. Then we rest"));
+        // A single repeated word isn't trimmed.
+        assert_eq!(
+            chunks[2].1.as_deref(),
+            Some("Rest is important. Rest is good")
+        );
+        assert_eq!(
+            join_transcripts(&chunks).unwrap(),
+            "We ship the release on Friday, after the review. Then we rest Rest is important. Rest is good Bye"
+        );
+        assert_eq!(join_transcripts(&[(Duration::ZERO, None)]), None);
+    }
+}
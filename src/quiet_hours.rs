@@ -0,0 +1,99 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+// Hours of the day, in the chat's timezone, when the bot shouldn't notify the chat members.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    // First quiet hour, 0-23.
+    pub start: u32,
+    // First hour after the quiet hours, 0-23. Can be less than `start` if quiet hours span midnight.
+    pub end: u32,
+    // Offset of the chat's timezone from UTC.
+    pub utc_offset_minutes: i32,
+}
+
+impl QuietHours {
+    // Parses `<start>-<end>` hours and an optional UTC offset in hours, e.g. `23-7 +3`.
+    pub fn parse(range: &str, utc_offset: Option<&str>) -> Option<Self> {
+        let (start, end) = range.split_once('-')?;
+        let start = start.parse::<u32>().ok().filter(|hour| *hour < 24)?;
+        let end = end.parse::<u32>().ok().filter(|hour| *hour < 24)?;
+        let utc_offset_minutes = match utc_offset {
+            Some(offset) => {
+                offset
+                    .trim_start_matches('+')
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|offset| (-12..=14).contains(offset))?
+                    * 60
+            }
+            None => 0,
+        };
+        if start == end {
+            return None;
+        }
+
+        Some(Self {
+            start,
+            end,
+            utc_offset_minutes,
+        })
+    }
+
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let minutes = time
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64 / 60)
+            .unwrap_or_default();
+        let hour =
+            ((minutes + self.utc_offset_minutes as i64).rem_euclid(MINUTES_PER_DAY) / 60) as u32;
+        if self.start < self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(hour: u64, minute: u64) -> SystemTime {
+        // 2024-01-01 00:00 UTC
+        UNIX_EPOCH + Duration::from_secs(1_704_067_200 + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn quiet_hours_span_midnight() {
+        let quiet_hours = QuietHours::parse("23-7", Some("+2")).unwrap();
+        assert_eq!(quiet_hours.utc_offset_minutes, 120);
+
+        // 01:30 UTC is 03:30 in the chat.
+        assert!(quiet_hours.contains(at(1, 30)));
+        // 21:00 UTC is 23:00 in the chat.
+        assert!(quiet_hours.contains(at(21, 0)));
+        // 05:00 UTC is 07:00 in the chat.
+        assert!(!quiet_hours.contains(at(5, 0)));
+        assert!(!quiet_hours.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let quiet_hours = QuietHours::parse("1-6", None).unwrap();
+        assert!(quiet_hours.contains(at(3, 0)));
+        assert!(!quiet_hours.contains(at(6, 0)));
+        assert!(!quiet_hours.contains(at(0, 59)));
+    }
+
+    #[test]
+    fn invalid_quiet_hours_are_rejected() {
+        assert_eq!(QuietHours::parse("23", None), None);
+        assert_eq!(QuietHours::parse("23-24", None), None);
+        assert_eq!(QuietHours::parse("5-5", None), None);
+        assert_eq!(QuietHours::parse("23-7", Some("+20")), None);
+        assert_eq!(QuietHours::parse("23-7", Some("abc")), None);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use grammers_client::Client;
+use tokio::sync::Mutex;
+
+use crate::db::Db;
+use crate::openai::api::OpenAIClient;
+
+// Outcome of checking a single dependency.
+pub struct Check {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub result: Result<(), String>,
+}
+
+async fn check<E: Display>(
+    name: &'static str,
+    probe: impl Future<Output = Result<(), E>>,
+) -> Check {
+    let started = Instant::now();
+    let result = probe.await.map_err(|e| e.to_string());
+    Check {
+        name,
+        elapsed: started.elapsed(),
+        result,
+    }
+}
+
+// Checks the dependencies the bot can't work without, one after another.
+pub async fn run(client: &Client, db: &Arc<Mutex<Db>>, openai: &OpenAIClient) -> Vec<Check> {
+    let database = check("Database", async { db.lock().await.probe() }).await;
+
+    let openai = openai.clone();
+    let openai = check("OpenAI", async {
+        tokio::task::spawn_blocking(move || openai.ping())
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())
+    })
+    .await;
+
+    let ffmpeg = check("ffmpeg", async {
+        let status = tokio::process::Command::new("ffmpeg")
+            .arg("-version")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .await
+            .map_err(|e| e.to_string())?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("exited with {status}"))
+        }
+    })
+    .await;
+
+    let telegram = check("Telegram", async { client.get_me().await.map(|_| ()) }).await;
+
+    vec![database, openai, ffmpeg, telegram]
+}
+
+pub fn report(checks: &[Check]) -> String {
+    let mut lines = checks
+        .iter()
+        .map(|check| {
+            let elapsed = check.elapsed.as_millis();
+            match &check.result {
+                Ok(()) => format!("✅ {} ({elapsed} ms)", check.name),
+                Err(e) => format!("❌ {} ({elapsed} ms): {e}", check.name),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    lines.push(String::new());
+    lines.push(if failed == 0 {
+        "All checks passed".to_string()
+    } else {
+        format!("{failed} of {} checks failed", checks.len())
+    });
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_lists_every_check() {
+        let checks = vec![
+            Check {
+                name: "Database",
+                elapsed: Duration::from_millis(3),
+                result: Ok(()),
+            },
+            Check {
+                name: "OpenAI",
+                elapsed: Duration::from_millis(1250),
+                result: Err("OpenAI request failed: timeout".to_string()),
+            },
+            Check {
+                name: "ffmpeg",
+                elapsed: Duration::from_millis(40),
+                result: Ok(()),
+            },
+        ];
+        assert_eq!(
+            report(&checks),
+            "✅ Database (3 ms)
+❌ OpenAI (1250 ms): OpenAI request failed: timeout
+✅ ffmpeg (40 ms)
+
+1 of 3 checks failed"
+        );
+
+        assert!(report(&checks[..1]).ends_with("\n\nAll checks passed"));
+    }
+
+    #[test]
+    fn failed_probe_is_reported() {
+        let db = Arc::new(Mutex::new(Db::new_with_file(":memory:").unwrap()));
+        let database =
+            futures::executor::block_on(check("Database", async { db.lock().await.probe() }));
+        assert!(database.result.is_ok());
+
+        let missing =
+            futures::executor::block_on(check("ffmpeg", async { Err::<(), _>("not found") }));
+        assert_eq!(missing.result, Err("not found".to_string()));
+    }
+}
@@ -1,22 +1,677 @@
+use std::collections::HashMap;
+use std::ops::ControlFlow;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use grammers_client::{
-    types::{Chat, Message, User},
-    Client, Update,
+    types::{CallbackQuery, Chat, Message, MessageDeletion, ParticipantPermissions, User},
+    Client, InputMessage, Update,
 };
+use grammers_mtsender::retry::RetryPolicy;
+use grammers_session::{PackedChat, PackedType};
+use grammers_tl_types as tl;
 use tokio::sync::Mutex;
 
-fn usage() -> String {
-    format!("Usage: ./summarize <number of messages to summarize>
+fn usage(stores_text: bool) -> String {
+    format!("Usage: ./summarize <number of messages to summarize> [topic:<keyword>[,<keyword>...]] [--bilingual] [--bullets|--prose] [--actions|--mood]
+/summarize <time, e.g. 30m, 2h or 7d> - summarize the messages sent recently
+/summarize @<user> <time, e.g. 30m, 24h or 7d> - summarize what the user said recently
+/summarize <today|yesterday|YYYY-MM-DD> - summarize a day, in the timezone of /setquiethours or UTC
+/summarize ±<number> - in reply to a message, summarize it with this many messages before and after it
+/summarizehere [same options as /summarize] - post the summary in this chat instead of sending it to you
+/again <small|medium|large> - repeat your last summary with another length
+/cancel - drop your requests that aren't done yet
+/links [number of messages] - list the links shared in the latest messages with a short description
+/whoami - show what the bot is allowed to do in this chat
+/reset - forget your previous /ask questions in this chat, the next one starts a new conversation
+/optout - leave your messages out of the summaries in this chat
+/optin - include your messages in the summaries again
 
-We don't store your messages. We store only latest {} message ids that will be used to fetch messages and discard them after summarization.", 
-consts::MESSAGE_TO_STORE)
+Admin commands:
+/setminlength <0-{}> - don't store messages shorter than the given number of characters (0 disables the filter)
+/setaskprompt <prompt> - use a custom system prompt for /ask, send without a prompt to reset it
+/setquiethours <start>-<end> [UTC offset] - reply without notifications during these hours, e.g. 23-7 +2. Use `off` to disable
+/setsummaryinterval <0-{}> - allow one summary in the chat every given number of minutes (0 disables the limit)
+/setsummarystyle <prose|bullets> - write the summaries in the chat as prose or bullet points by default
+/setcleanup <on|off> - delete the command messages after handling them
+/setdefault <small|medium|large> - summarize with this length when /summarize is sent without one
+/setlang <language code, e.g. uk> - write the summaries in this language whatever the chat speaks. Use `off` to disable
+/setwhisperprompt <prompt> - hint the names and terms used in the chat to the voice message transcription, send without a prompt to reset it
+/setsummaryreaction <emoji> - summarize a message when someone reacts to it with the emoji. Use `off` to disable
+/backfill <1-{}> - store up to the given number of messages sent before the bot joined, so they can be summarized
+/disable [nostore] - ignore all commands except /enable. With `nostore` messages aren't stored either
+/enable - answer commands again
+
+{} Transcripts of summarized media are kept for a day, so summarizing the same media again is instant.", 
+consts::MAX_MIN_MESSAGE_LENGTH, consts::MAX_SUMMARY_INTERVAL_MINUTES, consts::MESSAGE_TO_STORE, storage_notice(stores_text))
+}
+
+fn storage_notice(stores_text: bool) -> String {
+    if stores_text {
+        format!("We store the text and the sender's username of only latest {} messages, so summaries don't fetch them again. Older ones are deleted, as are the texts of members who /optout. Media is fetched and discarded after summarization.", consts::MESSAGE_TO_STORE)
+    } else {
+        format!("We don't store your messages. We store only latest {} message ids that will be used to fetch messages and discard them after summarization.", consts::MESSAGE_TO_STORE)
+    }
+}
+
+// Acknowledges a request in DM, or in the chat when it can't be acknowledged with a reaction.
+const WORKING_MESSAGE: &str = "Working on your request... Please, wait.";
+
+// Posted once when the bot is added to a group, followed by the usage.
+const WELCOME: &str = "Hi! I summarize the conversation in this chat. Make me an admin, so I can see the messages and not only the commands.";
+
+// Short messages like "ok" or "+1" add nothing to the summary, so chats can opt out of storing them.
+// Media messages are always stored as their content is not in the text.
+fn is_substantive(text: &str, has_media: bool, min_length: u32) -> bool {
+    has_media || text.trim().chars().count() >= min_length as usize
+}
+
+// Returns the chat id and whether the bot is still a member if the update is about the bot's membership.
+fn bot_membership_change(update: &tl::enums::Update, me: i64) -> Option<(i64, bool)> {
+    match update {
+        tl::enums::Update::ChannelParticipant(update) if update.user_id == me => {
+            let is_member = !matches!(
+                update.new_participant,
+                None | Some(tl::enums::ChannelParticipant::Left(_))
+                    | Some(tl::enums::ChannelParticipant::Banned(_))
+            );
+            Some((update.channel_id, is_member))
+        }
+        tl::enums::Update::ChatParticipant(update) if update.user_id == me => {
+            Some((update.chat_id, update.new_participant.is_some()))
+        }
+        _ => None,
+    }
+}
+
+// What the bot is allowed to do in a chat, reported by /whoami.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct BotRights {
+    is_admin: bool,
+    delete_messages: bool,
+    pin_messages: bool,
+    // Bots see all messages only as admins, otherwise privacy mode hides everything except commands.
+    read_history: bool,
+}
+
+impl BotRights {
+    fn from_permissions(permissions: &ParticipantPermissions) -> Self {
+        let is_admin = permissions.is_admin() || permissions.is_creator();
+        Self {
+            is_admin,
+            delete_messages: permissions.delete_messages(),
+            pin_messages: permissions.pin_messages(),
+            read_history: is_admin,
+        }
+    }
+
+    fn report(&self) -> String {
+        let mark = |allowed: bool| if allowed { "✅" } else { "❌" };
+        let mut lines = vec![
+            format!("{} Admin", mark(self.is_admin)),
+            format!(
+                "{} Read messages (needed to summarize the chat)",
+                mark(self.read_history)
+            ),
+            format!(
+                "{} Delete messages (needed to clean up commands)",
+                mark(self.delete_messages)
+            ),
+            format!("{} Pin messages", mark(self.pin_messages)),
+        ];
+        if !self.read_history || !self.delete_messages {
+            lines.push(String::new());
+            lines.push(
+                "Make the bot an admin with the missing rights to enable all features.".to_string(),
+            );
+        }
+        lines.join("\n")
+    }
+}
+
+// Returns the chat id, the message id and the newly added emojis if the update is about
+// reactions to a message in a group.
+fn added_reactions(update: &tl::enums::Update) -> Option<(i64, i32, Vec<String>)> {
+    let tl::enums::Update::BotMessageReaction(update) = update else {
+        return None;
+    };
+    let chat_id = match &update.peer {
+        tl::enums::Peer::Channel(channel) => channel.channel_id,
+        tl::enums::Peer::Chat(chat) => chat.chat_id,
+        tl::enums::Peer::User(_) => return None,
+    };
+    let added = added_emojis(&update.old_reactions, &update.new_reactions);
+    (!added.is_empty()).then_some((chat_id, update.msg_id, added))
+}
+
+fn added_emojis(old: &[tl::enums::Reaction], new: &[tl::enums::Reaction]) -> Vec<String> {
+    let emoji = |reaction: &tl::enums::Reaction| match reaction {
+        tl::enums::Reaction::Emoji(emoji) => Some(emoji.emoticon.clone()),
+        _ => None,
+    };
+    let old = old.iter().filter_map(emoji).collect::<Vec<_>>();
+    new.iter()
+        .filter_map(emoji)
+        .filter(|emoji| !old.contains(emoji))
+        .collect()
+}
+
+fn triggers_summary(config: &ChatConfig, added: &[String]) -> bool {
+    config.enabled
+        && config
+            .summary_reaction
+            .as_ref()
+            .is_some_and(|trigger| added.contains(trigger))
+}
+
+// Messages of bots, including our own summaries, are never stored to avoid summarizing summaries.
+// Our own id is compared explicitly, as the bot's own messages might not be reported as bot messages.
+fn is_from_bot(sender: Option<&Chat>, me: i64) -> bool {
+    sender
+        .map(|sender| match sender {
+            Chat::User(user) => user.is_bot() || user.id() == me,
+            _ => sender.id() == me,
+        })
+        .unwrap_or(false)
+}
+
+// Commands handled in groups, used to suggest the right one for a typo.
+const GROUP_COMMANDS: &[&str] = &[
+    "/help",
+    "/summarize",
+    "/summarizehere",
+    "/small",
+    "/medium",
+    "/large",
+    "/whoami",
+    "/links",
+    "/again",
+    "/ask",
+    "/reset",
+    "/optout",
+    "/optin",
+    "/setaskprompt",
+    "/setwhisperprompt",
+    "/setquiethours",
+    "/setsummaryreaction",
+    "/setminlength",
+    "/setsummaryinterval",
+    "/setsummarystyle",
+    "/setcleanup",
+    "/setdefault",
+    "/setlang",
+    "/backfill",
+    "/disable",
+    "/enable",
+];
+
+// Levenshtein distance over characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// The known command closest to the unknown one, if it's close enough to be a typo.
+fn suggest_command(cmd: &str) -> Option<&'static str> {
+    let cmd = cmd.to_lowercase();
+    GROUP_COMMANDS
+        .iter()
+        .map(|known| (edit_distance(&cmd, known), *known))
+        .filter(|(distance, _)| *distance <= consts::MAX_COMMAND_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+// While the bot is disabled in the chat, it only listens to /enable.
+fn accepts_command(config: &ChatConfig, cmd: &str) -> bool {
+    config.enabled || cmd == "/enable"
+}
+
+fn deletes_command(config: &ChatConfig, recognized: bool) -> bool {
+    recognized && config.cleanup_commands
+}
+
+// The bot is welcomed only the first time it's added to the chat, not when it's added again
+// or restarted.
+fn needs_welcome(
+    db: &Db,
+    chat_id: i64,
+    action: Option<&tl::enums::MessageAction>,
+    me: i64,
+) -> Result<bool> {
+    let added = matches!(
+        action,
+        Some(tl::enums::MessageAction::ChatAddUser(action)) if action.users.contains(&me)
+    );
+    Ok(added && db.mark_chat_welcomed(chat_id)?)
+}
+
+fn store_message(
+    db: &Db,
+    chat_id: i64,
+    message_id: i32,
+    text: &str,
+    has_media: bool,
+    from_bot: bool,
+    sender_id: Option<i64>,
+) -> Result<bool> {
+    if !should_store(db, chat_id, text, has_media, from_bot, sender_id)? {
+        return Ok(false);
+    }
+    db.add_message_id(chat_id, message_id)?;
+    Ok(true)
+}
+
+fn should_store(
+    db: &Db,
+    chat_id: i64,
+    text: &str,
+    has_media: bool,
+    from_bot: bool,
+    sender_id: Option<i64>,
+) -> Result<bool> {
+    if from_bot {
+        return Ok(false);
+    }
+    if let Some(sender_id) = sender_id {
+        if db.get_opted_out_users(chat_id)?.contains(&sender_id) {
+            return Ok(false);
+        }
+    }
+
+    let config = db.get_chat_config(chat_id)?;
+    if !config.enabled && !config.store_while_disabled {
+        return Ok(false);
+    }
+    Ok(is_substantive(text, has_media, config.min_message_length))
+}
+
+// A message fetched by /backfill.
+struct HistoryMessage {
+    id: i32,
+    date: u64,
+    text: String,
+    has_media: bool,
+    from_bot: bool,
+    sender_id: Option<i64>,
+}
+
+// Stores up to `limit` of the fetched messages, given newest first, with the same filters as new
+// messages. Commands are skipped, they are never stored when they arrive either.
+fn store_history(db: &Db, chat_id: i64, history: &[HistoryMessage], limit: usize) -> Result<usize> {
+    let mut kept = vec![];
+    for message in history {
+        if !message.text.starts_with('/')
+            && should_store(
+                db,
+                chat_id,
+                &message.text,
+                message.has_media,
+                message.from_bot,
+                message.sender_id,
+            )?
+        {
+            kept.push((message.id, message.date));
+        }
+    }
+    Ok(db.add_older_messages(chat_id, &kept, limit)?)
+}
+
+// Stores up to `count` of the messages sent before `before`, newest first, reading at most
+// consts::BACKFILL_MAX_BATCHES batches. Returns how many were stored and whether reading failed.
+async fn backfill_chat(
+    client: &Client,
+    db: &Mutex<Db>,
+    chat: &Chat,
+    me: i64,
+    before: i32,
+    count: usize,
+) -> Result<(usize, bool)> {
+    let mut stored = 0;
+    let mut next_id = before - 1;
+    let mut batches = 0;
+    while stored < count && next_id > 0 && batches < consts::BACKFILL_MAX_BATCHES {
+        if batches > 0 {
+            tokio::time::sleep(consts::BACKFILL_BATCH_DELAY).await;
+        }
+        batches += 1;
+        let ids = (1..=next_id)
+            .rev()
+            .take(consts::TELEGRAM_MAX_MESSAGE_FETCH)
+            .collect::<Vec<_>>();
+        next_id -= ids.len() as i32;
+
+        let fetched = match client.get_messages_by_id(chat, &ids).await {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                log::warn!("Failed to backfill chat {}: {:?}", chat.id(), err);
+                return Ok((stored, true));
+            }
+        };
+        let history = fetched
+            .into_iter()
+            .flatten()
+            .map(|fetched| HistoryMessage {
+                id: fetched.id(),
+                date: fetched.date().timestamp().max(0) as u64,
+                text: fetched.text().to_string(),
+                has_media: fetched.media().is_some(),
+                from_bot: is_from_bot(fetched.sender().as_ref(), me),
+                sender_id: fetched.sender().map(|sender| sender.id()),
+            })
+            .collect::<Vec<_>>();
+        // Nothing is visible this far back, the bot can't read the older history.
+        if history.is_empty() {
+            break;
+        }
+
+        let db = db.lock().await;
+        stored += store_history(&db, chat.id(), &history, count - stored)?;
+        db.remember_chat(chat.id(), &chat.pack().to_bytes())?;
+        // Older messages wouldn't be kept anymore.
+        if db.count_messages(chat.id())? >= consts::MESSAGE_TO_STORE as usize {
+            break;
+        }
+    }
+    Ok((stored, false))
+}
+
+// Sends the message to the group, silently during its quiet hours.
+async fn send_to_group(
+    client: &Client,
+    db: &Mutex<Db>,
+    chat: &Chat,
+    text: impl AsRef<str>,
+) -> Result<()> {
+    let silent = db
+        .lock()
+        .await
+        .get_chat_config(chat.id())?
+        .quiet_hours
+        .map(|quiet_hours| quiet_hours.contains(SystemTime::now()))
+        .unwrap_or(false);
+    client
+        .send_message(chat, InputMessage::text(text).silent(silent))
+        .await?;
+    Ok(())
+}
+
+// How long the chat has to wait for its next summary, None if it's allowed now.
+fn next_summary_wait(db: &Db, chat_id: i64, now: SystemTime) -> Result<Option<Duration>> {
+    let interval = u64::from(db.get_chat_config(chat_id)?.summary_interval_minutes) * 60;
+    let Some(last_summary) = db.get_last_summary(chat_id)? else {
+        return Ok(None);
+    };
+    let elapsed = unix_time(now).saturating_sub(last_summary);
+    Ok((elapsed < interval).then(|| Duration::from_secs(interval - elapsed)))
+}
+
+// Rounded up, so the chat never comes back too early.
+fn summary_wait_message(remaining: Duration) -> String {
+    let minutes = remaining.as_secs().div_ceil(60);
+    format!("This chat had a summary recently, the next one is allowed in {minutes} min")
+}
+
+// Replies to users the bot can't DM, by language code. The first one is the fallback.
+const DM_UNAVAILABLE: &[(&str, &str)] = &[
+    (
+        "en",
+        "I can't message you yet. Please, start a conversation with me first: {link}",
+    ),
+    (
+        "uk",
+        "Я поки не можу вам написати. Будь ласка, спершу почніть розмову зі мною: {link}",
+    ),
+];
+
+// The `{link}` in the configured or localized message is replaced with the bot's start link.
+fn dm_unavailable_message(
+    custom: Option<&str>,
+    lang_code: Option<&str>,
+    bot_username: Option<&str>,
+) -> String {
+    let template = custom.unwrap_or_else(|| {
+        DM_UNAVAILABLE
+            .iter()
+            .find(|(lang, _)| lang_code.is_some_and(|code| code.starts_with(lang)))
+            .unwrap_or(&DM_UNAVAILABLE[0])
+            .1
+    });
+    let link = match bot_username {
+        Some(username) => format!("https://t.me/{username}?start=summary"),
+        None => "open a private chat with the bot".to_string(),
+    };
+    template.replace("{link}", &link)
+}
+
+// Parameters of a summary request, kept so it can be repeated with a different length.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SummaryRequest {
+    message_count: u32,
+    gpt_length: GPTLenght,
+    mentioned_by_user: Option<String>,
+    options: SummaryOptions,
+}
+
+impl SummaryRequest {
+    fn with_length(&self, gpt_length: GPTLenght) -> Self {
+        Self {
+            gpt_length,
+            ..self.clone()
+        }
+    }
+
+    fn into_command(self, chat: Chat, recipient: Chat) -> Command {
+        Command::Summarize {
+            chat,
+            recipient,
+            message_count: self.message_count,
+            gpt_length: self.gpt_length,
+            mentione_by_user: self.mentioned_by_user,
+            options: self.options,
+        }
+    }
+}
+
+// The message replied to and the forum topic of a message. Messages in a topic reply to its first
+// message unless they are replies themselves. The General topic has no id, like chats without topics.
+fn split_reply(
+    forum_topic: bool,
+    reply_to_msg_id: Option<i32>,
+    reply_to_top_id: Option<i32>,
+) -> (Option<i32>, Option<i32>) {
+    match (forum_topic, reply_to_top_id) {
+        (false, _) => (reply_to_msg_id, None),
+        (true, Some(topic_id)) => (reply_to_msg_id, Some(topic_id)),
+        (true, None) => (None, reply_to_msg_id),
+    }
+}
+
+fn reply_and_topic(message: &Message) -> (Option<i32>, Option<i32>) {
+    match message.reply_header() {
+        Some(tl::enums::MessageReplyHeader::Header(header)) => split_reply(
+            header.forum_topic,
+            header.reply_to_msg_id,
+            header.reply_to_top_id,
+        ),
+        _ => (message.reply_to_message_id(), None),
+    }
+}
+
+// Language codes like `uk` or `en`, `off` is handled by /setlang itself.
+fn parse_language(code: &str) -> Option<String> {
+    let valid = (2..=3).contains(&code.len()) && code.chars().all(|c| c.is_ascii_alphabetic());
+    valid.then(|| code.to_ascii_lowercase())
+}
+
+// Same fixed delay as the reconnection policy, the error of the last attempt is returned.
+async fn with_startup_retries<T, E, F, Fut>(
+    attempts: u32,
+    delay: Duration,
+    mut call: F,
+) -> std::result::Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts => {
+                log::warn!("Startup request attempt {attempt} of {attempts} failed: {e}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The summary the message replies to and the messages it was made from, if the bot sent it recently.
+fn replied_summary(
+    db: &Db,
+    chat_id: i64,
+    reply_to: Option<i32>,
+    now: SystemTime,
+) -> Result<Option<(i32, SummarySource)>> {
+    let Some(message_id) = reply_to else {
+        return Ok(None);
+    };
+    let since = unix_time(now).saturating_sub(consts::SUMMARY_SOURCE_TTL.as_secs());
+    let source = db.get_summary_source(chat_id, message_id, since)?.map(
+        |(chat_id, first_message_id, last_message_id)| {
+            (
+                message_id,
+                SummarySource {
+                    chat_id,
+                    first_message_id,
+                    last_message_id,
+                },
+            )
+        },
+    );
+    Ok(source)
+}
+
+// Summaries other than the digest, like `--actions`.
+fn parse_summary_template(flag: &str) -> Option<SummaryKind> {
+    match flag.strip_prefix("--")? {
+        "actions" => Some(SummaryKind::ActionItems),
+        "mood" => Some(SummaryKind::Mood),
+        _ => None,
+    }
+}
+
+// True for bullet points, false for prose.
+fn parse_summary_style(style: &str) -> Option<bool> {
+    match style {
+        "bullets" => Some(true),
+        "prose" => Some(false),
+        _ => None,
+    }
+}
+
+// The number of messages to summarize is the first argument of /summarize.
+fn summary_count(tokens: &[&str], default_count: u32) -> u32 {
+    tokens
+        .get(1)
+        .and_then(|count| count.parse::<u32>().ok())
+        .unwrap_or(default_count)
+        .min(consts::MESSAGE_TO_STORE)
+}
+
+// The user to summarize follows the count, like `/summarize 50 @alice` or `/summarize 50 alice`,
+// or is mentioned anywhere else with `@`.
+fn summary_user(tokens: &[&str]) -> Option<String> {
+    tokens
+        .get(2)
+        .filter(|arg| {
+            (arg.len() > 1 && arg.starts_with('@'))
+                || (is_username(arg) && parse_window(arg).is_none() && Day::parse(arg).is_none())
+        })
+        .or_else(|| {
+            tokens
+                .iter()
+                .skip(1)
+                .find(|arg| arg.len() > 1 && arg.starts_with('@'))
+        })
+        .map(|user| user.trim_start_matches('@').to_string())
+}
+
+// Telegram usernames are letters, digits and underscores. Counts and radiuses aren't usernames.
+fn is_username(arg: &str) -> bool {
+    !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !arg.chars().all(|c| c.is_ascii_digit())
+        && parse_radius(arg).is_none()
+}
+
+// Time window like 30m, 24h or 7d.
+fn parse_window(window: &str) -> Option<Duration> {
+    let unit = match window.chars().last()? {
+        'm' => 60,
+        'h' => 3600,
+        'd' => 24 * 3600,
+        _ => return None,
+    };
+    let amount = window[..window.len() - 1].parse::<u64>().ok()?;
+    (amount > 0).then(|| Duration::from_secs(amount * unit))
+}
+
+// Number of messages around the replied message, like ±20 or +-20.
+fn parse_radius(radius: &str) -> Option<u32> {
+    let amount = radius
+        .strip_prefix('±')
+        .or_else(|| radius.strip_prefix("+-"))?
+        .parse::<u32>()
+        .ok()?;
+    (amount > 0).then(|| amount.min(consts::MAX_SUMMARY_RADIUS))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Enqueued {
+    Accepted,
+    Overloaded,
+}
+
+fn try_enqueue<T>(sender: &tokio::sync::mpsc::Sender<T>, command: T) -> Result<Enqueued> {
+    if sender.capacity() <= consts::COMMAND_CHANNEL_RESERVE {
+        return Ok(Enqueued::Overloaded);
+    }
+
+    match sender.try_send(command) {
+        Ok(()) => Ok(Enqueued::Accepted),
+        Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => Ok(Enqueued::Overloaded),
+        Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => Err(BotError::QueueClosed),
+    }
 }
 
 use crate::{
+    admins::AdminCache,
+    calendar::Day,
     consts,
-    db::Db,
-    openai::processor::{Command, GPTLenght},
+    cooldown::{self, Cooldown},
+    db::{unix_time, ChatConfig, Db},
+    error::{BotError, Result},
+    features::Features,
+    feedback, health,
+    openai::{
+        api::{SummaryKind, SummarySource},
+        processor::{Command, GPTLenght, SummaryOptions},
+        streaming::split_message,
+    },
+    quiet_hours::QuietHours,
 };
 
 pub struct Processor {
@@ -24,6 +679,22 @@ pub struct Processor {
     db: Arc<Mutex<Db>>,
     sender_channel: tokio::sync::mpsc::Sender<Command>,
     me: User,
+    // Users allowed to run the bot-wide admin commands.
+    bot_admins: Vec<i64>,
+    // The latest summary request per (chat, user), repeated by /again.
+    last_requests: HashMap<(i64, i64), SummaryRequest>,
+    cooldown: Option<Cooldown>,
+    admins: AdminCache,
+    features: Features,
+    suggest_commands: bool,
+    // Replaces the localized reply to users the bot can't DM.
+    dm_unavailable_message: Option<String>,
+    // Texts of the stored messages are kept next to their ids, see `Db::add_message_text`.
+    store_message_text: bool,
+    // Summary requests are acknowledged with a reaction on the command message.
+    command_reactions: bool,
+    // The update loop goes on after a failed update while it allows it, it stops at once if unset.
+    reconnection: Option<&'static health::ReconnectionPolicy>,
 }
 
 impl Processor {
@@ -32,17 +703,100 @@ impl Processor {
         db: Arc<Mutex<Db>>,
         sender: tokio::sync::mpsc::Sender<Command>,
     ) -> anyhow::Result<Self> {
-        let me = client.get_me().await?;
+        let me = with_startup_retries(
+            consts::STARTUP_ATTEMPTS,
+            consts::STARTUP_RETRY_DELAY,
+            || client.get_me(),
+        )
+        .await?;
         Ok(Self {
             client,
             db,
             sender_channel: sender,
             me,
+            bot_admins: vec![],
+            last_requests: HashMap::new(),
+            cooldown: None,
+            admins: AdminCache::new(consts::ADMIN_STATUS_TTL),
+            features: Features::default(),
+            suggest_commands: false,
+            dm_unavailable_message: None,
+            store_message_text: false,
+            command_reactions: false,
+            reconnection: None,
         })
     }
 
+    pub fn with_bot_admins(mut self, bot_admins: Vec<i64>) -> Self {
+        self.bot_admins = bot_admins;
+        self
+    }
+
+    pub fn with_command_cooldown(mut self, interval: Option<Duration>) -> Self {
+        self.cooldown = interval.map(Cooldown::new);
+        self
+    }
+
+    pub fn with_command_suggestions(mut self, suggest_commands: bool) -> Self {
+        self.suggest_commands = suggest_commands;
+        self
+    }
+
+    pub fn with_dm_unavailable_message(mut self, message: Option<String>) -> Self {
+        self.dm_unavailable_message = message;
+        self
+    }
+
+    pub fn with_message_text_storage(mut self, store_message_text: bool) -> Self {
+        self.store_message_text = store_message_text;
+        self
+    }
+
+    pub fn with_command_reactions(mut self, command_reactions: bool) -> Self {
+        self.command_reactions = command_reactions;
+        self
+    }
+
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn with_reconnection_policy(mut self, policy: &'static health::ReconnectionPolicy) -> Self {
+        self.reconnection = Some(policy);
+        self
+    }
+
     pub async fn process_updates(&mut self) -> anyhow::Result<()> {
-        while let Some(update) = self.client.next_update().await? {
+        // Failed updates in a row.
+        let mut failures = 0;
+        loop {
+            let update = match self.client.next_update().await {
+                Ok(Some(update)) => {
+                    failures = 0;
+                    update
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    health::HEALTH.record_update_error(unix_time(SystemTime::now()));
+                    log::error!(
+                        "Update loop failed: {:?}\n{}",
+                        err,
+                        health::HEALTH.report(unix_time(SystemTime::now()))
+                    );
+                    match self
+                        .reconnection
+                        .map(|policy| policy.should_retry(failures))
+                    {
+                        Some(ControlFlow::Continue(delay)) => {
+                            failures += 1;
+                            tokio::time::sleep(delay).await;
+                            continue;
+                        }
+                        _ => return Err(err.into()),
+                    }
+                }
+            };
             match update {
                 Update::NewMessage(message)
                     if !message.outgoing() && matches!(message.chat(), Chat::Group(_)) =>
@@ -58,6 +812,36 @@ impl Processor {
                         log::error!("Error processing message: {:?}", err)
                     }
                 }
+                Update::MessageEdited(message)
+                    if !message.outgoing() && matches!(message.chat(), Chat::Group(_)) =>
+                {
+                    if let Err(err) = self.process_edited_message(&message).await {
+                        log::error!("Error processing edited message: {:?}", err)
+                    }
+                }
+                Update::MessageDeleted(deletion) => {
+                    if let Err(err) = self.process_deletion(&deletion).await {
+                        log::error!("Error processing deleted messages: {:?}", err)
+                    }
+                }
+                Update::CallbackQuery(query) => {
+                    if let Err(err) = self.process_callback(&query).await {
+                        log::error!("Error processing callback: {:?}", err)
+                    }
+                }
+                Update::Raw(update) => {
+                    if let Some((chat_id, is_member)) = bot_membership_change(&update, self.me.id())
+                    {
+                        if let Err(err) = self.process_membership_change(chat_id, is_member).await {
+                            log::error!("Error processing membership change: {:?}", err)
+                        }
+                    }
+                    if let Some((chat_id, message_id, added)) = added_reactions(&update) {
+                        if let Err(err) = self.process_reaction(chat_id, message_id, &added).await {
+                            log::error!("Error processing reaction: {:?}", err)
+                        }
+                    }
+                }
                 _ => {}
             }
         }
@@ -65,183 +849,1926 @@ impl Processor {
         Ok(())
     }
 
-    async fn process_user_message(&mut self, message: Message) -> anyhow::Result<()> {
-        if message.text().starts_with('/') {
-            self.client
-                .send_message(
-                    &message.chat(),
-                    "Write/Forward text or audio you want to get summary on",
-                )
-                .await?;
-            return Ok(());
-        }
-
-        if message.sender().is_some() {
-            self.sender_channel
-                .send(Command::SummarizeMessage {
-                    chat: message.chat(),
-                    recipient: message.sender().unwrap(),
-                    message_id: message.id(),
-                    gpt_length: GPTLenght::Medium,
-                })
-                .await?;
-        }
-        Ok(())
-    }
-
-    async fn process_group_message(&mut self, message: Message) -> anyhow::Result<()> {
-        let mut splitted_string = message.text().split_whitespace();
-        let (cmd, bot_name) = if let Some(text) = splitted_string.next() {
-            let mut split = text.split('@');
-            let cmd = split.next().unwrap_or("");
-            let bot_name = split.next();
-            (cmd, bot_name)
-        } else {
+    // Votes of the summary rating buttons.
+    async fn process_callback(&mut self, query: &CallbackQuery) -> Result<()> {
+        let Some((summary_id, rating)) = feedback::parse_callback(query.data()) else {
             return Ok(());
         };
-        let is_bot = message
-            .sender()
-            .map(|s| match s {
-                Chat::User(user) => user.is_bot(),
-                _ => false,
-            })
-            .unwrap_or(false);
-
-        if bot_name.is_some() && bot_name != Some("") && bot_name != self.me.username() {
-            return Ok(());
-        }
-
-        let should_remove = if cmd == "/help" {
-            self.client.send_message(&message.chat(), usage()).await?;
-            true
-        } else if cmd == "/summarize" || cmd == "/small" || cmd == "/medium" || cmd == "/large" {
-            let length = match cmd {
-                "/summarize" => GPTLenght::Medium,
-                "/small" => GPTLenght::Short,
-                "/medium" => GPTLenght::Medium,
-                "/large" => GPTLenght::Long,
-                _ => unreachable!(),
-            };
-            self.summarize(&message, length).await?;
-            true
-        } else if cmd == "/ask" {
-            let question = splitted_string.collect::<Vec<&str>>().join(" ");
-            self.ask(&message, question).await?;
-            true
-        } else if cmd.starts_with('/') || is_bot {
-            false
-        } else {
+        let recorded =
             self.db
                 .lock()
                 .await
-                .add_message_id(message.chat().id(), message.id())?;
-            false
+                .set_feedback(summary_id, query.sender().id(), rating.score())?;
+        let answer = if recorded {
+            "Thanks for the feedback!"
+        } else {
+            "This summary can't be rated anymore"
         };
-
-        if should_remove {
-            // We don't check if the message was deleted or not. Bot can not have permissions to delete messages.
-            self.client
-                .delete_messages(message.chat(), &[message.id()])
-                .await
-                .ok();
-        }
-
+        query.answer().text(answer).send().await?;
         Ok(())
     }
 
-    async fn ask(&mut self, message: &Message, question: String) -> anyhow::Result<()> {
-        let sender = self.sender(message).await?;
-        if sender.is_none() {
+    async fn process_membership_change(&mut self, chat_id: i64, is_member: bool) -> Result<()> {
+        if is_member {
+            self.db.lock().await.unmark_chat_removed(chat_id)?;
             return Ok(());
         }
-        let sender = sender.unwrap();
+
+        log::info!("Bot was removed from chat {chat_id}");
+        self.db.lock().await.mark_chat_removed(chat_id)?;
         self.sender_channel
-            .send(Command::Ask {
-                chat: message.chat(),
-                recipient: sender,
-                question,
-                message_count: 200,
-                gpt_length: GPTLenght::Medium,
-            })
+            .send(Command::ChatRemoved { chat_id })
             .await?;
-
         Ok(())
     }
 
-    async fn summarize(&mut self, message: &Message, gpt_length: GPTLenght) -> anyhow::Result<()> {
-        let mut splitted_string = message.text().split_whitespace();
+    async fn elaborate(
+        &mut self,
+        message: &Message,
+        summary_message_id: i32,
+        source: SummarySource,
+    ) -> Result<()> {
+        let packed = self.db.lock().await.get_known_chat(source.chat_id)?;
+        let Some(Ok(packed)) = packed.map(|packed| PackedChat::from_bytes(&packed)) else {
+            self.client
+                .send_message(
+                    &message.chat(),
+                    "The messages of this summary are no longer available",
+                )
+                .await?;
+            return Ok(());
+        };
 
-        let reply = message.reply_to_message_id();
+        let chat = self.client.unpack_chat(packed).await?;
+        self.enqueue(
+            &message.chat(),
+            Command::Elaborate {
+                chat,
+                recipient: message.chat(),
+                summary_message_id,
+                instruction: message.text().to_string(),
+                source,
+            },
+        )
+        .await
+    }
 
-        let count = if reply.is_some() {
-            1
+    // Stored texts follow the edits, nothing is stored for the messages that weren't.
+    async fn process_edited_message(&self, message: &Message) -> Result<()> {
+        let db = self.db.lock().await;
+        let chat_id = message.chat().id();
+        let sender = message.sender();
+        let sender_id = sender.as_ref().map(Chat::id);
+        let opted_out = db.get_opted_out_users(chat_id)?;
+        if self.store_message_text
+            && message.media().is_none()
+            && !sender_id.is_some_and(|sender_id| opted_out.contains(&sender_id))
+        {
+            db.add_message_text(
+                chat_id,
+                message.id(),
+                sender_id,
+                sender.as_ref().and_then(Chat::username),
+                message.text(),
+            )?;
         } else {
-            splitted_string
-                .nth(1)
-                .and_then(|s| s.parse::<u32>().ok())
-                .unwrap_or(consts::DEFAULT_SUMMARY_LENGTH)
-                .min(consts::MESSAGE_TO_STORE)
+            db.forget_message_text(chat_id, message.id())?;
+        }
+        Ok(())
+    }
+
+    async fn process_deletion(&self, deletion: &MessageDeletion) -> Result<()> {
+        let db = self.db.lock().await;
+        // Deletions outside channels don't name the chat. Their message ids are unique among the
+        // basic groups of the bot, so they are removed from all of them.
+        let chat_ids = match deletion.channel_id() {
+            Some(channel_id) => vec![channel_id],
+            None => db
+                .get_known_chats()?
+                .into_iter()
+                .filter(|(_, packed)| {
+                    PackedChat::from_bytes(packed).is_ok_and(|chat| chat.ty == PackedType::Chat)
+                })
+                .map(|(chat_id, _)| chat_id)
+                .collect(),
         };
+        for chat_id in chat_ids {
+            db.remove_messages(chat_id, deletion.messages())?;
+        }
+        Ok(())
+    }
 
-        let sender = self.sender(message).await?;
-        if sender.is_none() {
+    // The summary is posted to the chat, the user who reacted can't be messaged without their access hash.
+    async fn process_reaction(
+        &mut self,
+        chat_id: i64,
+        message_id: i32,
+        added: &[String],
+    ) -> Result<()> {
+        if !self.features.reactions_enabled {
             return Ok(());
         }
-        let sender = sender.unwrap();
+        let packed = {
+            let db = self.db.lock().await;
+            if !triggers_summary(&db.get_chat_config(chat_id)?, added) {
+                return Ok(());
+            }
+            db.get_known_chat(chat_id)?
+        };
+        let Some(Ok(packed)) = packed.map(|packed| PackedChat::from_bytes(&packed)) else {
+            return Ok(());
+        };
 
-        let filter_by_user = splitted_string
-            .nth(2)
-            .and_then(|s| s.parse::<String>().ok())
-            .map(|s| s.trim_start_matches('@').to_string());
+        let chat = self.client.unpack_chat(packed).await?;
+        self.enqueue(
+            &chat,
+            Command::SummarizeMessage {
+                chat: chat.clone(),
+                recipient: chat.clone(),
+                message_id,
+                gpt_length: GPTLenght::Medium,
+            },
+        )
+        .await
+    }
 
-        let command = match reply {
-            Some(reply) => Command::SummarizeMessage {
-                chat: message.chat(),
-                recipient: sender,
-                message_id: reply,
-                gpt_length,
+    async fn process_user_message(&mut self, message: Message) -> Result<()> {
+        let mut splitted_string = message.text().split_whitespace();
+        let cmd = splitted_string.next();
+        if let Err(e) = self.features.check_command(cmd.unwrap_or_default()) {
+            self.client
+                .send_message(&message.chat(), e.user_message())
+                .await?;
+            return Ok(());
+        }
+        match cmd {
+            Some("/ask") => {
+                let question = splitted_string.collect::<Vec<&str>>().join(" ");
+                if question.is_empty() {
+                    self.client
+                        .send_message(
+                            &message.chat(),
+                            "Usage: /ask <question about the previous summaries>",
+                        )
+                        .await?;
+                    return Ok(());
+                }
+
+                self.enqueue(
+                    &message.chat(),
+                    Command::FollowUp {
+                        recipient: message.chat(),
+                        question,
+                        gpt_length: GPTLenght::Medium,
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+            Some("/more") => {
+                self.enqueue(
+                    &message.chat(),
+                    Command::More {
+                        recipient: message.chat(),
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+            Some("/retry") => {
+                self.enqueue(
+                    &message.chat(),
+                    Command::Retry {
+                        recipient: message.chat(),
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+            Some("/cancel") => {
+                self.enqueue(
+                    &message.chat(),
+                    Command::Cancel {
+                        recipient: message.chat(),
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+            Some("/deadletters") if self.is_bot_admin(&message) => {
+                self.show_dead_letters(&message).await?;
+                return Ok(());
+            }
+            Some("/feedback") if self.is_bot_admin(&message) => {
+                self.show_feedback(&message).await?;
+                return Ok(());
+            }
+            Some("/selftest") if self.is_bot_admin(&message) => {
+                self.enqueue(
+                    &message.chat(),
+                    Command::SelfTest {
+                        recipient: message.chat(),
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+            Some("/health") if self.is_bot_admin(&message) => {
+                self.client
+                    .send_message(
+                        message.chat(),
+                        health::HEALTH.report(unix_time(SystemTime::now())),
+                    )
+                    .await?;
+                return Ok(());
+            }
+            Some("/multidigest") if self.is_bot_admin(&message) => {
+                self.multi_digest(&message, splitted_string.next()).await?;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if message.text().starts_with('/') {
+            self.client
+                .send_message(
+                    &message.chat(),
+                    "Write/Forward text or audio you want to get summary on. Use /ask <question> to ask about the previous summaries, or reply to a summary to get more details.",
+                )
+                .await?;
+            return Ok(());
+        }
+
+        // Replies to a summary, like "expand point 2", are answered over the summarized messages.
+        if self.features.ask_enabled {
+            let replied = replied_summary(
+                &*self.db.lock().await,
+                message.chat().id(),
+                message.reply_to_message_id(),
+                SystemTime::now(),
+            )?;
+            if let Some((summary_message_id, source)) = replied {
+                return self.elaborate(&message, summary_message_id, source).await;
+            }
+        }
+
+        if let Some(sender) = message.sender() {
+            self.enqueue(
+                &message.chat(),
+                Command::SummarizeMessage {
+                    chat: message.chat(),
+                    recipient: sender,
+                    message_id: message.id(),
+                    gpt_length: GPTLenght::Medium,
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn process_group_message(&mut self, message: Message) -> Result<()> {
+        let welcome = needs_welcome(
+            &*self.db.lock().await,
+            message.chat().id(),
+            message.action(),
+            self.me.id(),
+        )?;
+        if welcome {
+            // Groups where members can't write reject the message, the bot stays quiet there.
+            let text = format!("{WELCOME}\n\n{}", usage(self.store_message_text));
+            if let Err(err) = self.send_to_group(&message.chat(), text).await {
+                log::warn!("Failed to welcome chat {}: {:?}", message.chat().id(), err);
+            }
+            return Ok(());
+        }
+
+        let mut splitted_string = message.text().split_whitespace();
+        let (cmd, bot_name) = if let Some(text) = splitted_string.next() {
+            let mut split = text.split('@');
+            let cmd = split.next().unwrap_or("");
+            let bot_name = split.next();
+            (cmd, bot_name)
+        } else {
+            return Ok(());
+        };
+
+        if bot_name.is_some() && bot_name != Some("") && bot_name != self.me.username() {
+            return Ok(());
+        }
+
+        if cmd.starts_with('/') {
+            let config = self.db.lock().await.get_chat_config(message.chat().id())?;
+            if !accepts_command(&config, cmd) {
+                return Ok(());
+            }
+            if let Err(e) = self.features.check_command(cmd) {
+                self.send_to_group(&message.chat(), e.user_message())
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let should_remove = if cmd == "/help" {
+            self.send_to_group(&message.chat(), usage(self.store_message_text))
+                .await?;
+            true
+        } else if cmd == "/summarize" || cmd == "/small" || cmd == "/medium" || cmd == "/large" {
+            // /summarize takes the chat's default length.
+            let length = match cmd {
+                "/summarize" => None,
+                "/small" => Some(GPTLenght::Short),
+                "/medium" => Some(GPTLenght::Medium),
+                "/large" => Some(GPTLenght::Long),
+                _ => unreachable!(),
+            };
+            // Acknowledged commands are kept to show the outcome.
+            !self.summarize(&message, length, false).await?
+        } else if cmd == "/summarizehere" {
+            !self.summarize(&message, None, true).await?
+        } else if cmd == "/whoami" {
+            self.whoami(&message).await?;
+            true
+        } else if cmd == "/links" {
+            self.links(&message, splitted_string.next()).await?;
+            true
+        } else if cmd == "/again" {
+            self.again(&message, splitted_string.next()).await?;
+            true
+        } else if cmd == "/ask" {
+            let question = splitted_string.collect::<Vec<&str>>().join(" ");
+            self.ask(&message, question).await?;
+            true
+        } else if cmd == "/reset" {
+            self.reset_ask_thread(&message).await?;
+            true
+        } else if cmd == "/setaskprompt" {
+            let prompt = message
+                .text()
+                .trim()
+                .split_once(char::is_whitespace)
+                .map(|(_, prompt)| prompt.trim().to_string());
+            self.set_ask_prompt(&message, prompt).await?;
+            true
+        } else if cmd == "/setwhisperprompt" {
+            let prompt = message
+                .text()
+                .trim()
+                .split_once(char::is_whitespace)
+                .map(|(_, prompt)| prompt.trim().to_string());
+            self.set_whisper_prompt(&message, prompt).await?;
+            true
+        } else if cmd == "/setquiethours" {
+            let args = splitted_string.collect::<Vec<&str>>();
+            self.set_quiet_hours(&message, &args).await?;
+            true
+        } else if cmd == "/setsummaryreaction" {
+            self.set_summary_reaction(&message, splitted_string.next())
+                .await?;
+            true
+        } else if cmd == "/optout" || cmd == "/optin" {
+            self.set_opted_out(&message, cmd == "/optout").await?;
+            true
+        } else if cmd == "/setminlength" {
+            self.set_min_length(&message, splitted_string.next())
+                .await?;
+            true
+        } else if cmd == "/setsummaryinterval" {
+            self.set_summary_interval(&message, splitted_string.next())
+                .await?;
+            true
+        } else if cmd == "/setsummarystyle" {
+            self.set_summary_style(&message, splitted_string.next())
+                .await?;
+            true
+        } else if cmd == "/setcleanup" {
+            self.set_cleanup(&message, splitted_string.next()).await?;
+            true
+        } else if cmd == "/setdefault" {
+            self.set_default_length(&message, splitted_string.next())
+                .await?;
+            true
+        } else if cmd == "/setlang" {
+            self.set_language(&message, splitted_string.next()).await?;
+            true
+        } else if cmd == "/backfill" {
+            self.backfill(&message, splitted_string.next()).await?;
+            true
+        } else if cmd == "/disable" {
+            let store = match splitted_string.next() {
+                None => Some(true),
+                Some("nostore") => Some(false),
+                Some(_) => None,
+            };
+            self.set_enabled(&message, false, store).await?;
+            true
+        } else if cmd == "/enable" {
+            self.set_enabled(&message, true, Some(true)).await?;
+            true
+        } else if cmd.starts_with('/') {
+            if let Some(suggestion) = suggest_command(cmd).filter(|_| self.suggest_commands) {
+                self.send_to_group(
+                    &message.chat(),
+                    format!("Unknown command. Did you mean {suggestion}?"),
+                )
+                .await?;
+            }
+            false
+        } else {
+            let db = self.db.lock().await;
+            let chat = message.chat();
+            let sender = message.sender();
+            let stored = store_message(
+                &db,
+                chat.id(),
+                message.id(),
+                message.text(),
+                message.media().is_some(),
+                is_from_bot(sender.as_ref(), self.me.id()),
+                sender.as_ref().map(Chat::id),
+            )?;
+            if stored {
+                db.remember_chat(chat.id(), &chat.pack().to_bytes())?;
+                if let (_, Some(topic_id)) = reply_and_topic(&message) {
+                    db.set_message_topic(chat.id(), message.id(), topic_id)?;
+                }
+            }
+            // Media is fetched for its content anyway, only plain texts are kept.
+            if stored && self.store_message_text && message.media().is_none() {
+                db.add_message_text(
+                    chat.id(),
+                    message.id(),
+                    sender.as_ref().map(Chat::id),
+                    sender.as_ref().and_then(Chat::username),
+                    message.text(),
+                )?;
+            }
+            false
+        };
+
+        // The config is read again, /setcleanup may have just changed it.
+        let config = self.db.lock().await.get_chat_config(message.chat().id())?;
+        if deletes_command(&config, should_remove) {
+            // We don't check if the message was deleted or not. Bot can not have permissions to delete messages.
+            self.client
+                .delete_messages(message.chat(), &[message.id()])
+                .await
+                .ok();
+        }
+
+        Ok(())
+    }
+
+    // Bots can't read the chat history page by page, so the messages before the command are
+    // fetched by id in batches. That takes a while, so it runs in its own task and the other
+    // updates are handled meanwhile.
+    async fn backfill(&mut self, message: &Message, count: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let chat = message.chat();
+        let Some(count) = count
+            .and_then(|count| count.parse::<u32>().ok())
+            .filter(|count| (1..=consts::MESSAGE_TO_STORE).contains(count))
+        else {
+            self.send_to_group(
+                &chat,
+                format!("Usage: /backfill <1-{}>", consts::MESSAGE_TO_STORE),
+            )
+            .await?;
+            return Ok(());
+        };
+
+        let client = self.client.clone();
+        let db = self.db.clone();
+        let me = self.me.id();
+        let before = message.id();
+        tokio::spawn(async move {
+            let reply = match backfill_chat(&client, &db, &chat, me, before, count as usize).await {
+                Ok((stored, failed)) if failed && stored == 0 => {
+                    "Couldn't read the chat history. Make sure I'm an admin and can see the messages."
+                        .to_string()
+                }
+                Ok((stored, _)) => format!("Stored {stored} earlier messages"),
+                Err(err) => {
+                    log::error!("Failed to backfill chat {}: {:?}", chat.id(), err);
+                    return;
+                }
+            };
+            if let Err(err) = send_to_group(&client, &db, &chat, reply).await {
+                log::error!(
+                    "Failed to report the backfill of chat {}: {:?}",
+                    chat.id(),
+                    err
+                );
+            }
+        });
+        Ok(())
+    }
+
+    async fn set_min_length(&mut self, message: &Message, value: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let min_length = match value.and_then(|v| v.parse::<u32>().ok()) {
+            Some(min_length) if min_length <= consts::MAX_MIN_MESSAGE_LENGTH => min_length,
+            _ => {
+                self.send_to_group(
+                    &message.chat(),
+                    format!(
+                        "Usage: /setminlength <0-{}>",
+                        consts::MAX_MIN_MESSAGE_LENGTH
+                    ),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.min_message_length = min_length;
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        self.send_to_group(
+            &message.chat(),
+            format!("Messages shorter than {min_length} characters won't be stored"),
+        )
+        .await?;
+        Ok(())
+    }
+
+    // Any member can opt out, the messages they sent before are left out of the summaries too.
+    async fn set_opted_out(&mut self, message: &Message, opted_out: bool) -> Result<()> {
+        let Some(sender) = message.sender() else {
+            return Ok(());
+        };
+
+        self.db
+            .lock()
+            .await
+            .set_opted_out(message.chat().id(), sender.id(), opted_out)?;
+
+        let reply = if opted_out {
+            "Your messages won't be stored or included in the summaries in this chat"
+        } else {
+            "Your messages will be included in the summaries in this chat again"
+        };
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn reset_ask_thread(&mut self, message: &Message) -> Result<()> {
+        let Some(sender) = message.sender() else {
+            return Ok(());
+        };
+
+        self.db
+            .lock()
+            .await
+            .clear_ask_thread(message.chat().id(), sender.id())?;
+        self.send_to_group(
+            &message.chat(),
+            "Your /ask conversation was reset, the next question starts a new one",
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_summary_interval(&mut self, message: &Message, value: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let interval = match value.and_then(|v| v.parse::<u32>().ok()) {
+            Some(interval) if interval <= consts::MAX_SUMMARY_INTERVAL_MINUTES => interval,
+            _ => {
+                self.send_to_group(
+                    &message.chat(),
+                    format!(
+                        "Usage: /setsummaryinterval <0-{}>",
+                        consts::MAX_SUMMARY_INTERVAL_MINUTES
+                    ),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.summary_interval_minutes = interval;
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        let reply = if interval == 0 {
+            "Summaries in this chat aren't limited".to_string()
+        } else {
+            format!("The chat can have one summary every {interval} min")
+        };
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn set_cleanup(&mut self, message: &Message, value: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let cleanup_commands = match value {
+            Some("on") => true,
+            Some("off") => false,
+            _ => {
+                self.send_to_group(&message.chat(), "Usage: /setcleanup <on|off>")
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.cleanup_commands = cleanup_commands;
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        let reply = if cleanup_commands {
+            "Command messages will be deleted after they're handled"
+        } else {
+            "Command messages will be left in the chat"
+        };
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn set_default_length(&mut self, message: &Message, value: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let Some(length) = value.and_then(GPTLenght::parse) else {
+            self.send_to_group(&message.chat(), "Usage: /setdefault <small|medium|large>")
+                .await?;
+            return Ok(());
+        };
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.default_length = Some(length.name().to_string());
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        self.send_to_group(
+            &message.chat(),
+            format!("/summarize will write {} summaries", length.name()),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_language(&mut self, message: &Message, value: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let language = match value {
+            Some("off") => None,
+            code => {
+                let Some(language) = code.and_then(parse_language) else {
+                    self.send_to_group(
+                        &message.chat(),
+                        "Usage: /setlang <language code, e.g. uk>, or /setlang off",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+                Some(language)
+            }
+        };
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.forced_language = language.clone();
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        let reply = match language {
+            Some(language) => format!("Summaries in this chat will be written in `{language}`"),
+            None => "Summaries will be written in the language of the chat".to_string(),
+        };
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn set_summary_style(&mut self, message: &Message, value: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let Some(bullets) = value.and_then(parse_summary_style) else {
+            self.send_to_group(&message.chat(), "Usage: /setsummarystyle <prose|bullets>")
+                .await?;
+            return Ok(());
+        };
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.bullet_summaries = bullets;
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        let reply = if bullets {
+            "Summaries in this chat will be written as bullet points"
+        } else {
+            "Summaries in this chat will be written as prose"
+        };
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    // Tells the chat when it can have the next summary if it had one less than its interval ago.
+    async fn summary_allowed(&self, chat: &Chat) -> Result<bool> {
+        let wait = next_summary_wait(&*self.db.lock().await, chat.id(), SystemTime::now())?;
+        if let Some(wait) = wait {
+            self.send_to_group(chat, summary_wait_message(wait)).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    async fn record_summary(&self, chat: &Chat) -> Result<()> {
+        self.db
+            .lock()
+            .await
+            .set_last_summary(chat.id(), unix_time(SystemTime::now()))?;
+        Ok(())
+    }
+
+    // `store_while_disabled` is None if the arguments are invalid.
+    async fn set_enabled(
+        &mut self,
+        message: &Message,
+        enabled: bool,
+        store_while_disabled: Option<bool>,
+    ) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let Some(store_while_disabled) = store_while_disabled else {
+            self.send_to_group(&message.chat(), "Usage: /disable [nostore]")
+                .await?;
+            return Ok(());
+        };
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.enabled = enabled;
+            config.store_while_disabled = store_while_disabled;
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        let reply = match (enabled, store_while_disabled) {
+            (true, _) => "The bot is enabled",
+            (false, true) => "The bot is disabled, commands except /enable are ignored. Messages are still stored",
+            (false, false) => "The bot is disabled, commands except /enable are ignored and messages aren't stored",
+        };
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn set_ask_prompt(&mut self, message: &Message, prompt: Option<String>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let chat_id = message.chat().id();
+        let reply = {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.ask_prompt = prompt;
+            db.set_chat_config(chat_id, &config)?;
+            if config.ask_prompt.is_some() {
+                "The /ask prompt was updated"
+            } else {
+                "The /ask prompt was reset to default"
+            }
+        };
+
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn set_whisper_prompt(
+        &mut self,
+        message: &Message,
+        prompt: Option<String>,
+    ) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let chat_id = message.chat().id();
+        let reply = {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.whisper_prompt = prompt;
+            db.set_chat_config(chat_id, &config)?;
+            if config.whisper_prompt.is_some() {
+                "The transcription prompt was updated"
+            } else {
+                "The transcription prompt was removed"
+            }
+        };
+
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn whoami(&mut self, message: &Message) -> Result<()> {
+        let permissions = self
+            .client
+            .get_permissions(message.chat(), self.me.pack())
+            .await?;
+        let report = BotRights::from_permissions(&permissions).report();
+        self.send_to_group(&message.chat(), report).await
+    }
+
+    async fn set_summary_reaction(&mut self, message: &Message, emoji: Option<&str>) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let Some(emoji) = emoji else {
+            self.send_to_group(&message.chat(), "Usage: /setsummaryreaction <emoji|off>")
+                .await?;
+            return Ok(());
+        };
+        let summary_reaction = (emoji != "off").then(|| emoji.to_string());
+
+        let chat_id = message.chat().id();
+        let reply = {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.summary_reaction = summary_reaction;
+            db.set_chat_config(chat_id, &config)?;
+            match &config.summary_reaction {
+                Some(emoji) => format!("React with {emoji} to a message to summarize it"),
+                None => "Summaries on reaction are disabled".to_string(),
+            }
+        };
+
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    async fn set_quiet_hours(&mut self, message: &Message, args: &[&str]) -> Result<()> {
+        if !self.require_admin(message).await? {
+            return Ok(());
+        }
+
+        let quiet_hours = match args {
+            ["off"] => None,
+            [range] => QuietHours::parse(range, None),
+            [range, utc_offset] => QuietHours::parse(range, Some(utc_offset)),
+            _ => None,
+        };
+        if quiet_hours.is_none() && args != ["off"] {
+            self.send_to_group(
+                &message.chat(),
+                "Usage: /setquiethours <start>-<end> [UTC offset], e.g. /setquiethours 23-7 +2. Use /setquiethours off to disable",
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let chat_id = message.chat().id();
+        {
+            let db = self.db.lock().await;
+            let mut config = db.get_chat_config(chat_id)?;
+            config.quiet_hours = quiet_hours;
+            db.set_chat_config(chat_id, &config)?;
+        }
+
+        let reply = match quiet_hours {
+            Some(quiet_hours) => format!(
+                "Replies will be silent from {}:00 to {}:00",
+                quiet_hours.start, quiet_hours.end
+            ),
+            None => "Quiet hours are disabled".to_string(),
+        };
+        self.send_to_group(&message.chat(), reply).await?;
+        Ok(())
+    }
+
+    // Replies in the group without a notification during the chat's quiet hours.
+    async fn send_to_group(&self, chat: &Chat, text: impl AsRef<str>) -> Result<()> {
+        send_to_group(&self.client, &self.db, chat, text).await
+    }
+
+    fn is_bot_admin(&self, message: &Message) -> bool {
+        message
+            .sender()
+            .map(|sender| self.bot_admins.contains(&sender.id()))
+            .unwrap_or(false)
+    }
+
+    async fn show_dead_letters(&mut self, message: &Message) -> Result<()> {
+        let dead_letters = self
+            .db
+            .lock()
+            .await
+            .get_dead_letters(consts::DEAD_LETTERS_TO_SHOW)?;
+        if dead_letters.is_empty() {
+            self.client
+                .send_message(message.chat(), "No failed commands")
+                .await?;
+            return Ok(());
+        }
+
+        let report = dead_letters
+            .iter()
+            .map(|dead_letter| {
+                format!(
+                    "#{} {} {} chat: {} user: {}\n{}",
+                    dead_letter.id,
+                    dead_letter.created_at,
+                    dead_letter.command,
+                    dead_letter
+                        .chat_id
+                        .map_or_else(|| "-".to_string(), |id| id.to_string()),
+                    dead_letter
+                        .user_id
+                        .map_or_else(|| "-".to_string(), |id| id.to_string()),
+                    dead_letter.error,
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        for part in split_message(&report) {
+            self.client.send_message(message.chat(), part).await?;
+        }
+        Ok(())
+    }
+
+    // Ratings of the summaries per prompt version and settings.
+    async fn show_feedback(&mut self, message: &Message) -> Result<()> {
+        let totals = self.db.lock().await.get_feedback_totals()?;
+        if totals.is_empty() {
+            self.client
+                .send_message(message.chat(), "No summaries were rated yet")
+                .await?;
+            return Ok(());
+        }
+
+        let report = totals
+            .iter()
+            .map(|(prompt_version, parameters, up, down)| {
+                format!("{prompt_version} {parameters}: 👍 {up} 👎 {down}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        for part in split_message(&report) {
+            self.client.send_message(message.chat(), part).await?;
+        }
+        Ok(())
+    }
+
+    async fn multi_digest(&mut self, message: &Message, chat_ids: Option<&str>) -> Result<()> {
+        let chat_ids = chat_ids.map(|chat_ids| {
+            chat_ids
+                .split(',')
+                .map(|chat_id| chat_id.trim().parse::<i64>())
+                .collect::<Result<Vec<_>, _>>()
+        });
+        let Some(Ok(chat_ids)) = chat_ids else {
+            self.client
+                .send_message(
+                    message.chat(),
+                    "Usage: /multidigest <chat_id>,<chat_id>[,...]",
+                )
+                .await?;
+            return Ok(());
+        };
+        let Some(operator) = message.sender() else {
+            return Ok(());
+        };
+
+        let mut chats = Vec::with_capacity(chat_ids.len());
+        for chat_id in chat_ids {
+            match self.resolve_chat(chat_id, &operator).await? {
+                Some(chat) => chats.push(chat),
+                None => {
+                    self.client
+                        .send_message(
+                            message.chat(),
+                            format!(
+                                "Chat {chat_id} is unknown to the bot or you are not its admin"
+                            ),
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+
+        self.enqueue(
+            &message.chat(),
+            Command::MultiDigest {
+                chats,
+                recipient: message.chat(),
+                message_count: consts::DEFAULT_SUMMARY_LENGTH,
+                gpt_length: GPTLenght::Long,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    // Resolves a chat the bot has stored messages from, if the user is an admin there.
+    async fn resolve_chat(&self, chat_id: i64, user: &Chat) -> Result<Option<Chat>> {
+        let packed = self.db.lock().await.get_known_chat(chat_id)?;
+        let Some(Ok(packed)) = packed.map(|packed| PackedChat::from_bytes(&packed)) else {
+            return Ok(None);
+        };
+
+        let chat = self.client.unpack_chat(packed).await?;
+        match self.client.get_permissions(&chat, user).await {
+            Ok(permissions) if permissions.is_admin() || permissions.is_creator() => Ok(Some(chat)),
+            Ok(_) => Ok(None),
+            Err(err) => {
+                log::warn!(
+                    "Failed to get user permissions in chat {chat_id}: {:?}",
+                    err
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    // Config commands are denied when the permissions can't be checked, like when the bot isn't
+    // allowed to see the participants.
+    async fn is_admin(&mut self, chat: &Chat, user: &Chat) -> bool {
+        let now = Instant::now();
+        if let Some(is_admin) = self.admins.get(chat.id(), user.id(), now) {
+            return is_admin;
+        }
+
+        match self.client.get_permissions(chat, user).await {
+            Ok(permissions) => {
+                let is_admin = permissions.is_admin() || permissions.is_creator();
+                self.admins.insert(chat.id(), user.id(), is_admin, now);
+                is_admin
+            }
+            Err(err) => {
+                log::warn!("Failed to get sender permissions: {:?}", err);
+                false
+            }
+        }
+    }
+
+    // Tells the sender when an admin-only command is refused.
+    async fn require_admin(&mut self, message: &Message) -> Result<bool> {
+        let chat = message.chat();
+        let is_admin = match message.sender() {
+            Some(sender) => self.is_admin(&chat, &sender).await,
+            None => false,
+        };
+        if !is_admin {
+            self.send_to_group(&chat, "This command is restricted to admins")
+                .await?;
+        }
+        Ok(is_admin)
+    }
+
+    async fn ask(&mut self, message: &Message, question: String) -> Result<()> {
+        let sender = self.sender(message).await?;
+        if sender.is_none() {
+            return Ok(());
+        }
+        let sender = sender.unwrap();
+        self.enqueue(
+            &sender,
+            Command::Ask {
+                chat: message.chat(),
+                recipient: sender.clone(),
+                question,
+                message_count: 200,
+                gpt_length: GPTLenght::Medium,
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn links(&mut self, message: &Message, count: Option<&str>) -> Result<()> {
+        let message_count = count
+            .and_then(|count| count.parse::<u32>().ok())
+            .unwrap_or(consts::DEFAULT_SUMMARY_LENGTH)
+            .min(consts::MESSAGE_TO_STORE);
+        let Some(sender) = self.sender(message).await? else {
+            return Ok(());
+        };
+        self.enqueue(
+            &sender,
+            Command::LinkDigest {
+                chat: message.chat(),
+                recipient: sender.clone(),
+                message_count,
+            },
+        )
+        .await
+    }
+
+    // Summaries are sent to the requester in DM, or posted in the group if `public`.
+    // Returns whether the command message got a reaction.
+    async fn summarize(
+        &mut self,
+        message: &Message,
+        gpt_length: Option<GPTLenght>,
+        public: bool,
+    ) -> Result<bool> {
+        let tokens = message.text().split_whitespace().collect::<Vec<_>>();
+        let args = tokens.get(1..).unwrap_or_default();
+
+        // In a forum topic only its messages are summarized.
+        let (reply, forum_topic) = reply_and_topic(message);
+        let within = args.iter().find_map(|arg| parse_window(arg));
+        let radius = args.iter().find_map(|arg| parse_radius(arg));
+        let day = args.iter().find_map(|arg| Day::parse(arg));
+
+        let count = if reply.is_some() {
+            1
+        } else {
+            // With a time window, all the stored messages within it are summarized by default.
+            let default_count = if within.is_some() || day.is_some() {
+                consts::MESSAGE_TO_STORE
+            } else {
+                consts::DEFAULT_SUMMARY_LENGTH
+            };
+            summary_count(&tokens, default_count)
+        };
+
+        if !self.summary_allowed(&message.chat()).await? {
+            return Ok(false);
+        }
+        let recipient = if public {
+            self.group_recipient(message).await?
+        } else {
+            self.sender(message).await?
+        };
+        let Some(recipient) = recipient else {
+            return Ok(false);
+        };
+
+        let filter_by_user = summary_user(&tokens);
+        let topic = args
+            .iter()
+            .find_map(|arg| arg.strip_prefix("topic:"))
+            .filter(|topic| !topic.is_empty())
+            .map(ToString::to_string);
+        let bilingual = args.contains(&"--bilingual");
+        let template = args.iter().find_map(|arg| parse_summary_template(arg));
+        let config = self.db.lock().await.get_chat_config(message.chat().id())?;
+        let gpt_length = gpt_length
+            .or(config.default_length.as_deref().and_then(GPTLenght::parse))
+            .unwrap_or(GPTLenght::Medium);
+        let language = config.forced_language.clone();
+        // The flag overrides the chat's default style.
+        let bullets = args
+            .iter()
+            .find_map(|arg| parse_summary_style(arg.strip_prefix("--")?))
+            .unwrap_or(config.bullet_summaries);
+        // Days start at midnight in the timezone set with /setquiethours, UTC if it isn't set.
+        let utc_offset_minutes = config
+            .quiet_hours
+            .map(|quiet_hours| quiet_hours.utc_offset_minutes)
+            .unwrap_or_default();
+        let period = match day {
+            Some(day) => match day.range(unix_time(SystemTime::now()), utc_offset_minutes) {
+                Some(period) => Some(period),
+                None => {
+                    self.send_to_group(&message.chat(), "Dates before 1970 can't be summarized")
+                        .await?;
+                    return Ok(false);
+                }
             },
-            None => Command::Summarize {
+            None => None,
+        };
+
+        let command = match (reply, radius) {
+            (Some(reply), Some(radius)) => SummaryRequest {
+                message_count: 2 * radius + 1,
+                gpt_length,
+                mentioned_by_user: None,
+                options: SummaryOptions {
+                    topic,
+                    bilingual,
+                    bullets,
+                    around: Some((reply, radius)),
+                    forum_topic,
+                    language,
+                    template,
+                    ..Default::default()
+                },
+            }
+            .into_command(message.chat(), recipient.clone()),
+            (Some(reply), None) => Command::SummarizeMessage {
                 chat: message.chat(),
-                recipient: sender,
-                message_count: count,
+                recipient: recipient.clone(),
+                message_id: reply,
                 gpt_length,
-                mentione_by_user: filter_by_user,
             },
+            (None, _) => {
+                let request = SummaryRequest {
+                    message_count: count,
+                    gpt_length,
+                    mentioned_by_user: filter_by_user,
+                    options: SummaryOptions {
+                        topic,
+                        bilingual,
+                        bullets,
+                        within,
+                        around: None,
+                        period,
+                        forum_topic,
+                        language,
+                        template,
+                    },
+                };
+                // /again repeats the summary in DM, public ones are repeated with /summarizehere.
+                if !public {
+                    self.last_requests
+                        .insert((message.chat().id(), recipient.id()), request.clone());
+                }
+                request.into_command(message.chat(), recipient.clone())
+            }
+        };
+
+        let (command, reacted) = self.acknowledge(message, command, public).await?;
+        self.enqueue(&recipient, command).await?;
+        self.record_summary(&message.chat()).await?;
+
+        Ok(reacted)
+    }
+
+    // Repeats the user's latest summary in this chat with another length.
+    // Messages are loaded again, so the summary covers the latest message window.
+    async fn again(&mut self, message: &Message, length: Option<&str>) -> Result<()> {
+        let Some(gpt_length) = length.and_then(GPTLenght::parse) else {
+            self.send_to_group(&message.chat(), "Usage: /again <small|medium|large>")
+                .await?;
+            return Ok(());
+        };
+        let Some(user) = message.sender() else {
+            return Ok(());
+        };
+        let Some(request) = self.last_requests.get(&(message.chat().id(), user.id())) else {
+            self.send_to_group(&message.chat(), "Nothing to repeat, use /summarize first")
+                .await?;
+            return Ok(());
         };
 
-        self.sender_channel.send(command).await?;
+        let request = request.with_length(gpt_length);
+        if !self.summary_allowed(&message.chat()).await? {
+            return Ok(());
+        }
+        let Some(sender) = self.sender(message).await? else {
+            return Ok(());
+        };
+        self.last_requests
+            .insert((message.chat().id(), sender.id()), request.clone());
+        self.enqueue(
+            &sender,
+            request.into_command(message.chat(), sender.clone()),
+        )
+        .await?;
+        self.record_summary(&message.chat()).await?;
+        Ok(())
+    }
 
+    // Queues a user request without blocking the update loop, rejecting it if the bot is overloaded.
+    async fn enqueue(&self, reply_to: &Chat, command: Command) -> Result<()> {
+        if try_enqueue(&self.sender_channel, command)? == Enqueued::Overloaded {
+            log::warn!(
+                "Command channel is overloaded: {} of {} slots free",
+                self.sender_channel.capacity(),
+                consts::COMMAND_CHANNEL_SIZE
+            );
+            self.client
+                .send_message(reply_to, "Bot is overloaded, try again shortly")
+                .await?;
+        }
         Ok(())
     }
 
-    async fn sender(&mut self, message: &Message) -> anyhow::Result<Option<Chat>> {
+    // Puts the in-progress reaction on the command message if enabled. Requesters of DM summaries
+    // are told in DM anyway, public ones are told in the chat if the bot can't react there.
+    async fn acknowledge(
+        &self,
+        message: &Message,
+        command: Command,
+        public: bool,
+    ) -> Result<(Command, bool)> {
+        if !self.command_reactions {
+            return Ok((command, false));
+        }
+        let reacted = self
+            .client
+            .send_reactions(message.chat(), message.id(), consts::PROCESSING_REACTION)
+            .await;
+        match reacted {
+            Ok(()) => Ok((
+                Command::Acknowledged {
+                    chat: message.chat(),
+                    message_id: message.id(),
+                    command: Box::new(command),
+                },
+                true,
+            )),
+            Err(err) => {
+                log::warn!("Failed to react to the command: {:?}", err);
+                if public {
+                    self.send_to_group(&message.chat(), WORKING_MESSAGE).await?;
+                }
+                Ok((command, false))
+            }
+        }
+    }
+
+    // Whether the sender has to wait before the next request, they are told how long.
+    async fn in_cooldown(&mut self, message: &Message, sender: &Chat) -> Result<bool> {
+        let cooldown = self
+            .cooldown
+            .as_mut()
+            .map(|cooldown| cooldown.check(sender.id(), Instant::now()));
+        if let Some(Err(remaining)) = cooldown {
+            self.send_to_group(&message.chat(), cooldown::wait_message(remaining))
+                .await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // The group itself, for responses posted publicly. The requester doesn't need a conversation
+    // with the bot, but the cooldown still applies to them.
+    async fn group_recipient(&mut self, message: &Message) -> Result<Option<Chat>> {
+        if let Some(sender) = message.sender() {
+            if self.in_cooldown(message, &sender).await? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(message.chat()))
+    }
+
+    async fn sender(&mut self, message: &Message) -> Result<Option<Chat>> {
         let sender = if let Some(sender) = message.sender() {
+            if self.in_cooldown(message, &sender).await? {
+                return Ok(None);
+            }
             if self
                 .client
-                .send_message(&sender, "Working on your request... Please, wait.")
+                .send_message(&sender, WORKING_MESSAGE)
                 .await
                 .is_err()
             {
-                self.client
-                    .send_message(
-                        message.chat(),
-                        "Couldn't send you a message. Please, start a conversation with me first.",
-                    )
-                    .await?;
+                let lang_code = match &sender {
+                    Chat::User(user) => user.lang_code(),
+                    _ => None,
+                };
+                let reply = dm_unavailable_message(
+                    self.dm_unavailable_message.as_deref(),
+                    lang_code,
+                    self.me.username(),
+                );
+                self.send_to_group(&message.chat(), reply).await?;
                 return Ok(None);
             } else {
                 sender
             }
         } else {
-            self.client
-                .send_message(
-                    message.chat(),
-                    "Sender is unknown. Check your privacy settings.",
-                )
-                .await?;
+            self.send_to_group(
+                &message.chat(),
+                "Sender is unknown. Check your privacy settings.",
+            )
+            .await?;
             return Ok(None);
         };
         Ok(Some(sender))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_messages_are_not_stored() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let config = crate::db::ChatConfig {
+            min_message_length: 5,
+            ..Default::default()
+        };
+        db.set_chat_config(1, &config).unwrap();
+
+        assert!(!store_message(&db, 1, 1, "ok", false, false, None).unwrap());
+        assert!(!store_message(&db, 1, 2, "  +1  ", false, false, None).unwrap());
+        assert!(store_message(&db, 1, 3, "Let's deploy on Monday", false, false, None).unwrap());
+        assert!(store_message(&db, 1, 4, "", true, false, None).unwrap());
+
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![4, 3]);
+    }
+
+    #[test]
+    fn opted_out_users_arent_stored() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        db.set_opted_out(1, 10, true).unwrap();
+
+        assert!(!store_message(&db, 1, 1, "My phone is 555-0100", false, false, Some(10)).unwrap());
+        assert!(
+            store_message(&db, 1, 2, "Let's deploy on Monday", false, false, Some(11)).unwrap()
+        );
+        assert!(store_message(
+            &db,
+            2,
+            3,
+            "Opted out only in the other chat",
+            false,
+            false,
+            Some(10)
+        )
+        .unwrap());
+
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn backfill_stores_up_to_the_requested_count() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        db.set_opted_out(1, 10, true).unwrap();
+        // The bot already stored the latest message.
+        db.add_message_id(1, 21).unwrap();
+
+        // 2024-03-10 00:00 UTC, a minute between the messages.
+        let start = 1_710_028_800;
+        let history = (1..=20)
+            .rev()
+            .map(|id| HistoryMessage {
+                id,
+                date: start + id as u64 * 60,
+                text: match id {
+                    20 => "/summarize".to_string(),
+                    _ => format!("Message {id}"),
+                },
+                has_media: false,
+                from_bot: id == 19,
+                sender_id: Some(if id == 18 { 10 } else { 11 }),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(store_history(&db, 1, &history, 5).unwrap(), 5);
+        assert_eq!(
+            db.get_messages_id(1, None, 100).unwrap(),
+            vec![21, 17, 16, 15, 14, 13]
+        );
+        // A repeated backfill continues below the stored messages.
+        assert_eq!(store_history(&db, 1, &history, 100).unwrap(), 12);
+        db.add_message_id(1, 22).unwrap();
+        let stored = db.get_messages_id(1, None, 100).unwrap();
+        assert_eq!(stored.len(), 19);
+        assert_eq!(stored[..3], [22, 21, 17]);
+        assert_eq!(stored.last(), Some(&1));
+
+        // Backfilled messages keep their dates.
+        assert_eq!(
+            db.get_messages_id_in_period(1, None, 100, start, start + 3 * 60 + 1)
+                .unwrap(),
+            vec![3, 2, 1]
+        );
+    }
+
+    #[test]
+    fn all_messages_are_stored_by_default() {
+        let db = Db::new_with_file(":memory:").unwrap();
+
+        assert!(store_message(&db, 1, 1, "ok", false, false, None).unwrap());
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn again_repeats_request_with_new_length() {
+        let request = SummaryRequest {
+            message_count: 300,
+            gpt_length: GPTLenght::Medium,
+            mentioned_by_user: Some("user1".to_string()),
+            options: SummaryOptions {
+                topic: Some("deploy".to_string()),
+                bilingual: true,
+                bullets: true,
+                within: Some(Duration::from_secs(24 * 3600)),
+                around: None,
+                period: None,
+                forum_topic: Some(7),
+                language: Some("uk".to_string()),
+                template: Some(SummaryKind::ActionItems),
+            },
+        };
+
+        let again = request.with_length(GPTLenght::parse("short").unwrap());
+        assert_eq!(
+            again,
+            SummaryRequest {
+                gpt_length: GPTLenght::Short,
+                ..request.clone()
+            }
+        );
+    }
+
+    #[test]
+    fn summary_templates_are_parsed() {
+        assert_eq!(
+            parse_summary_template("--actions"),
+            Some(SummaryKind::ActionItems)
+        );
+        assert_eq!(parse_summary_template("--mood"), Some(SummaryKind::Mood));
+        assert_eq!(parse_summary_template("mood"), None);
+    }
+
+    #[test]
+    fn summary_lengths_are_parsed() {
+        assert_eq!(GPTLenght::parse("short"), Some(GPTLenght::Short));
+        assert_eq!(GPTLenght::parse("large"), Some(GPTLenght::Long));
+        assert_eq!(GPTLenght::parse("huge"), None);
+    }
+
+    #[test]
+    fn language_codes_are_parsed() {
+        assert_eq!(parse_language("uk"), Some("uk".to_string()));
+        assert_eq!(parse_language("EN"), Some("en".to_string()));
+        assert_eq!(parse_language("ukrainian"), None);
+        assert_eq!(parse_language("u1"), None);
+    }
+
+    #[test]
+    fn topic_messages_are_not_replies_to_the_topic() {
+        // A chat without topics.
+        assert_eq!(split_reply(false, Some(5), None), (Some(5), None));
+        // Sent in topic 3, and a reply to message 5 in it.
+        assert_eq!(split_reply(true, Some(3), None), (None, Some(3)));
+        assert_eq!(split_reply(true, Some(5), Some(3)), (Some(5), Some(3)));
+    }
+
+    #[test]
+    fn rights_report_lists_missing_rights() {
+        let rights = BotRights {
+            is_admin: true,
+            delete_messages: true,
+            pin_messages: false,
+            read_history: true,
+        };
+        assert_eq!(
+            rights.report(),
+            "✅ Admin
+✅ Read messages (needed to summarize the chat)
+✅ Delete messages (needed to clean up commands)
+❌ Pin messages"
+        );
+
+        let report = BotRights::default().report();
+        assert!(report.contains("❌ Read messages"));
+        assert!(report
+            .ends_with("Make the bot an admin with the missing rights to enable all features."));
+    }
+
+    #[test]
+    fn configured_reaction_triggers_summary() {
+        let reaction = |emoji: &str| {
+            tl::enums::Reaction::Emoji(tl::types::ReactionEmoji {
+                emoticon: emoji.to_string(),
+            })
+        };
+        let added = added_emojis(&[reaction("👍")], &[reaction("👍"), reaction("📝")]);
+        assert_eq!(added, vec!["📝".to_string()]);
+        assert!(added_emojis(&[reaction("📝")], &[reaction("📝")]).is_empty());
+
+        let mut config = ChatConfig::default();
+        assert!(!triggers_summary(&config, &added));
+        config.summary_reaction = Some("📝".to_string());
+        assert!(triggers_summary(&config, &added));
+        assert!(!triggers_summary(&config, &["👍".to_string()]));
+        config.enabled = false;
+        assert!(!triggers_summary(&config, &added));
+    }
+
+    #[test]
+    fn chat_summaries_are_limited_by_interval() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let start = SystemTime::now();
+        db.set_last_summary(1, unix_time(start)).unwrap();
+        // Without an interval, summaries aren't limited.
+        assert_eq!(next_summary_wait(&db, 1, start).unwrap(), None);
+
+        db.set_chat_config(
+            1,
+            &ChatConfig {
+                summary_interval_minutes: 10,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let remaining = next_summary_wait(&db, 1, start + Duration::from_secs(150))
+            .unwrap()
+            .unwrap();
+        assert_eq!(remaining, Duration::from_secs(450));
+        assert_eq!(
+            summary_wait_message(remaining),
+            "This chat had a summary recently, the next one is allowed in 8 min"
+        );
+        assert_eq!(
+            next_summary_wait(&db, 1, start + Duration::from_secs(600)).unwrap(),
+            None
+        );
+        // The limit is per chat, not per user.
+        assert_eq!(next_summary_wait(&db, 2, start).unwrap(), None);
+    }
+
+    #[test]
+    fn dm_unavailable_message_links_to_the_bot() {
+        let link = "https://t.me/ohsumbot?start=summary";
+        let english = dm_unavailable_message(None, None, Some("ohsumbot"));
+        assert!(english.starts_with("I can't message you yet"));
+        assert!(english.ends_with(link));
+
+        let ukrainian = dm_unavailable_message(None, Some("uk"), Some("ohsumbot"));
+        assert!(ukrainian.starts_with("Я поки не можу"));
+        assert!(ukrainian.ends_with(link));
+        // Unknown languages fall back to English.
+        assert_eq!(
+            dm_unavailable_message(None, Some("de"), Some("ohsumbot")),
+            english
+        );
+
+        assert_eq!(
+            dm_unavailable_message(Some("Tap {link} first"), Some("uk"), Some("ohsumbot")),
+            format!("Tap {link} first")
+        );
+        assert!(!dm_unavailable_message(None, None, None).contains("t.me"));
+    }
+
+    #[tokio::test]
+    async fn startup_survives_brief_outage() {
+        let mut attempts = 0;
+        let me = with_startup_retries(5, Duration::ZERO, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err("connection reset")
+                } else {
+                    Ok("ohsumbot")
+                }
+            }
+        })
+        .await;
+        assert_eq!(me, Ok("ohsumbot"));
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let me = with_startup_retries(2, Duration::ZERO, || {
+            attempts += 1;
+            async { Err::<(), _>("connection reset") }
+        })
+        .await;
+        assert_eq!(me, Err("connection reset"));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn reply_to_summary_finds_its_messages() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        for message_id in 1..=20 {
+            db.add_message_id(100, message_id).unwrap();
+        }
+        let now = SystemTime::now();
+        db.add_summary_source(10, 500, (100, 5, 12), unix_time(now), 0)
+            .unwrap();
+
+        let (summary_message_id, source) =
+            replied_summary(&db, 10, Some(500), now).unwrap().unwrap();
+        assert_eq!(summary_message_id, 500);
+        assert_eq!(
+            source,
+            SummarySource {
+                chat_id: 100,
+                first_message_id: 5,
+                last_message_id: 12,
+            }
+        );
+        assert_eq!(
+            db.get_messages_id_between(
+                source.chat_id,
+                None,
+                source.first_message_id,
+                source.last_message_id
+            )
+            .unwrap(),
+            (5..=12).rev().collect::<Vec<_>>()
+        );
+
+        // Other replies and old summaries are summarized as usual.
+        assert_eq!(replied_summary(&db, 10, Some(501), now).unwrap(), None);
+        assert_eq!(replied_summary(&db, 11, Some(500), now).unwrap(), None);
+        assert_eq!(replied_summary(&db, 10, None, now).unwrap(), None);
+        let later = now + consts::SUMMARY_SOURCE_TTL * 2;
+        assert_eq!(replied_summary(&db, 10, Some(500), later).unwrap(), None);
+    }
+
+    #[test]
+    fn typo_suggests_the_closest_command() {
+        assert_eq!(suggest_command("/summarise"), Some("/summarize"));
+        assert_eq!(suggest_command("/sumarize"), Some("/summarize"));
+        assert_eq!(suggest_command("/Again"), Some("/again"));
+        assert_eq!(suggest_command("/setminlenght"), Some("/setminlength"));
+        assert_eq!(suggest_command("/start"), None);
+        assert_eq!(suggest_command("/weather"), None);
+        assert_eq!(edit_distance("/links", "/links"), 0);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn time_window_is_parsed() {
+        assert_eq!(parse_window("24h"), Some(Duration::from_secs(24 * 3600)));
+        assert_eq!(parse_window("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_window("7d"), Some(Duration::from_secs(7 * 24 * 3600)));
+        assert_eq!(parse_window("0h"), None);
+        assert_eq!(parse_window("h"), None);
+        assert_eq!(parse_window("100"), None);
+        assert_eq!(parse_window("@alice"), None);
+    }
+
+    #[test]
+    fn summary_count_and_user_are_positional() {
+        let tokens = ["/summarize", "50", "@alice"];
+        assert_eq!(summary_count(&tokens, 100), 50);
+        assert_eq!(summary_user(&tokens), Some("alice".to_string()));
+
+        let tokens = ["/summarize", "50", "--bullets", "@bob"];
+        assert_eq!(summary_user(&tokens), Some("bob".to_string()));
+        assert_eq!(summary_user(&["/summarize", "50", "24h"]), None);
+        assert_eq!(
+            summary_user(&["/summarize", "50", "alice"]),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            summary_user(&["/summarize", "@alice", "50"]),
+            Some("alice".to_string())
+        );
+        assert_eq!(summary_user(&["/summarize", "100", "±20"]), None);
+        // A time window alone summarizes all the stored messages within it.
+        assert_eq!(summary_user(&["/summarize", "2h"]), None);
+        assert_eq!(
+            summary_count(&["/summarize", "2h"], consts::MESSAGE_TO_STORE),
+            consts::MESSAGE_TO_STORE
+        );
+        assert_eq!(summary_count(&["/summarize"], 100), 100);
+        assert_eq!(
+            summary_count(&["/summarize", "99999"], 100),
+            consts::MESSAGE_TO_STORE
+        );
+    }
+
+    #[test]
+    fn summary_radius_is_parsed() {
+        assert_eq!(parse_radius("±20"), Some(20));
+        assert_eq!(parse_radius("+-5"), Some(5));
+        assert_eq!(parse_radius("±0"), None);
+        assert_eq!(parse_radius("20"), None);
+        assert_eq!(parse_radius("-20"), None);
+        assert_eq!(parse_radius("±1000"), Some(consts::MAX_SUMMARY_RADIUS));
+    }
+
+    #[test]
+    fn requests_are_rejected_when_overloaded() {
+        let (sender, mut receiver) =
+            tokio::sync::mpsc::channel(consts::COMMAND_CHANNEL_RESERVE + 2);
+
+        assert_eq!(try_enqueue(&sender, 1).unwrap(), Enqueued::Accepted);
+        assert_eq!(try_enqueue(&sender, 2).unwrap(), Enqueued::Accepted);
+        assert_eq!(try_enqueue(&sender, 3).unwrap(), Enqueued::Overloaded);
+
+        assert_eq!(receiver.try_recv().unwrap(), 1);
+        assert_eq!(try_enqueue(&sender, 4).unwrap(), Enqueued::Accepted);
+        assert_eq!(receiver.try_recv().unwrap(), 2);
+        assert_eq!(receiver.try_recv().unwrap(), 4);
+
+        drop(receiver);
+        assert!(matches!(
+            try_enqueue(&sender, 5),
+            Err(BotError::QueueClosed)
+        ));
+    }
+
+    #[test]
+    fn bot_messages_are_never_stored() {
+        let db = Db::new_with_file(":memory:").unwrap();
+
+        assert!(store_message(&db, 1, 1, "Let's deploy on Monday", false, false, None).unwrap());
+        assert!(!store_message(
+            &db,
+            1,
+            2,
+            "Summary: they agreed to deploy",
+            false,
+            true,
+            None
+        )
+        .unwrap());
+        assert!(!store_message(&db, 1, 3, "", true, true, None).unwrap());
+
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![1]);
+        assert!(!is_from_bot(None, 42));
+    }
+
+    #[test]
+    fn commands_are_ignored_while_disabled() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let mut config = db.get_chat_config(1).unwrap();
+        assert!(accepts_command(&config, "/summarize"));
+
+        config.enabled = false;
+        db.set_chat_config(1, &config).unwrap();
+        let config = db.get_chat_config(1).unwrap();
+        assert!(!accepts_command(&config, "/summarize"));
+        assert!(!accepts_command(&config, "/help"));
+        assert!(accepts_command(&config, "/enable"));
+        assert!(store_message(&db, 1, 1, "Still stored", false, false, None).unwrap());
+
+        let config = ChatConfig {
+            store_while_disabled: false,
+            ..config
+        };
+        db.set_chat_config(1, &config).unwrap();
+        assert!(!store_message(&db, 1, 2, "Not stored", false, false, None).unwrap());
+
+        let config = ChatConfig {
+            enabled: true,
+            store_while_disabled: true,
+            ..config
+        };
+        db.set_chat_config(1, &config).unwrap();
+        assert!(accepts_command(
+            &db.get_chat_config(1).unwrap(),
+            "/summarize"
+        ));
+        assert_eq!(db.get_messages_id(1, None, 10).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn bot_is_welcomed_once() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let me = 42;
+        let added = |users: Vec<i64>| {
+            tl::enums::MessageAction::ChatAddUser(tl::types::MessageActionChatAddUser { users })
+        };
+
+        assert!(!needs_welcome(&db, 1, Some(&added(vec![7])), me).unwrap());
+        assert!(!needs_welcome(&db, 1, None, me).unwrap());
+        assert!(needs_welcome(&db, 1, Some(&added(vec![7, me])), me).unwrap());
+        // Added again after being removed, or the update is seen again after a restart.
+        assert!(!needs_welcome(&db, 1, Some(&added(vec![me])), me).unwrap());
+        assert!(needs_welcome(&db, 2, Some(&added(vec![me])), me).unwrap());
+        assert_eq!(db.get_chat_config(1).unwrap(), ChatConfig::default());
+    }
+
+    #[test]
+    fn command_cleanup_follows_the_config() {
+        let db = Db::new_with_file(":memory:").unwrap();
+        let config = db.get_chat_config(1).unwrap();
+        assert!(deletes_command(&config, true));
+        assert!(!deletes_command(&config, false));
+
+        let config = ChatConfig {
+            cleanup_commands: false,
+            ..config
+        };
+        db.set_chat_config(1, &config).unwrap();
+        assert!(!deletes_command(&db.get_chat_config(1).unwrap(), true));
+    }
+}